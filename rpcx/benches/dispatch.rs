@@ -0,0 +1,115 @@
+//! Measures what the server does for every request: decoding it, looking up
+//! and calling its handler, and encoding the reply, in-process and over a
+//! loopback connection.
+//!
+//! Run with `cargo bench -p rpcx --bench dispatch`.
+
+use bytes::BytesMut;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use rpcx::*;
+
+use std::{
+    hint::black_box,
+    net::{TcpListener, TcpStream},
+    thread,
+};
+
+const PAYLOAD_SIZES: [usize; 3] = [64, 4096, 65536];
+
+fn echo_server(dispatch_mode: DispatchMode) -> Server {
+    let mut server = Server::new("127.0.0.1:0".to_owned(), 4);
+    server.set_dispatch_mode(dispatch_mode);
+    let echo: RpcxHandler = Box::new(|_, x, _| Ok(x.to_vec()));
+    server
+        .register_handler("Echo".to_owned(), "Echo".to_owned(), String::new(), echo)
+        .unwrap();
+    server
+}
+
+fn request(size: usize) -> Message {
+    let mut msg = Message::new();
+    msg.set_version(0);
+    msg.set_message_type(MessageType::Request);
+    msg.set_serialize_type(SerializeType::SerializeNone);
+    msg.set_seq(1);
+    msg.service_path = "Echo".to_owned();
+    msg.service_method = "Echo".to_owned();
+    msg.payload = vec![b'x'; size];
+    msg
+}
+
+/// The read/dispatch/reply loop without a connection, step by step and as
+/// a whole.
+fn dispatch(c: &mut Criterion) {
+    let server = echo_server(DispatchMode::Inline);
+    let mut group = c.benchmark_group("dispatch");
+    for size in PAYLOAD_SIZES {
+        let req = request(size);
+        let data = req.encode();
+        group.throughput(Throughput::Bytes(data.len() as u64));
+
+        group.bench_with_input(BenchmarkId::new("encode", size), &req, |b, req| {
+            b.iter(|| req.encode())
+        });
+        group.bench_with_input(BenchmarkId::new("decode", size), &data, |b, data| {
+            b.iter(|| {
+                let mut msg = Message::new();
+                msg.decode(&mut &data[..]).unwrap();
+                msg
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("handle", size), &req, |b, req| {
+            b.iter(|| server.dispatch(req).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("round_trip", size), &data, |b, data| {
+            b.iter(|| {
+                let mut msg = Message::new();
+                msg.decode(&mut &data[..]).unwrap();
+                server.dispatch(&msg).unwrap().encode()
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Calls over a loopback connection, so the time of a call is its latency
+/// as a client sees it.
+fn connection(c: &mut Criterion) {
+    let mut group = c.benchmark_group("connection");
+    for (name, mode) in [
+        ("inline", DispatchMode::Inline),
+        ("pooled", DispatchMode::Pooled),
+    ] {
+        let mut server = echo_server(mode);
+        let listener = server.bind().unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || server.start_with_listener(listener));
+
+        let mut client = SyncClient::new(&addr.to_string());
+        for size in PAYLOAD_SIZES {
+            let args = BytesMut::from(&vec![b'x'; size][..]);
+            group.throughput(Throughput::Bytes(size as u64));
+            group.bench_with_input(BenchmarkId::new(name, size), &args, |b, args| {
+                b.iter(|| {
+                    let reply: BytesMut =
+                        client.call("Echo", "Echo", &Metadata::new(), args).unwrap();
+                    reply
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+/// The `try_clone` every TCP connection is split with.
+fn try_clone(c: &mut Criterion) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    c.bench_function("try_clone/tcp", |b| {
+        b.iter(|| black_box(&stream).try_clone().unwrap())
+    });
+}
+
+criterion_group!(benches, dispatch, connection, try_clone);
+criterion_main!(benches);
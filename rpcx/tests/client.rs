@@ -0,0 +1,256 @@
+use rmp_serde as rmps;
+use serde::{Deserialize, Serialize};
+
+use rpcx::*;
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+#[derive(RpcxParam, Default, Debug, Copy, Clone, Serialize, Deserialize)]
+struct SleepArgs {
+    millis: u64,
+}
+
+#[derive(RpcxParam, Default, Debug, Copy, Clone, Serialize, Deserialize)]
+struct SleepReply {
+    slept: bool,
+}
+
+#[derive(RpcxParam, Default, Debug, Copy, Clone, Serialize, Deserialize)]
+struct CountArgs {
+    to: u8,
+}
+
+/// Starts a server with a service sleeping as long as it is asked to and a
+/// service streaming the numbers up to the one it is asked for, and returns
+/// its address.
+fn start_server() -> String {
+    start_server_dropping(0)
+}
+
+/// Like `start_server`, but the first `dropped` connections are closed
+/// without reading anything.
+fn start_server_dropping(dropped: usize) -> String {
+    let mut rpc_server = Server::new("127.0.0.1:0".to_owned(), 2);
+    rpc_server
+        .register_typed(
+            "Sleep".to_owned(),
+            "Sleep".to_owned(),
+            String::new(),
+            |args: SleepArgs| {
+                thread::sleep(Duration::from_millis(args.millis));
+                SleepReply { slept: true }
+            },
+        )
+        .unwrap();
+    rpc_server
+        .register_stream_fn(
+            "Count".to_owned(),
+            "Count".to_owned(),
+            String::new(),
+            |_: &Context, data: &[u8], st, sink: &mut StreamSink| {
+                let mut args = CountArgs::default();
+                args.from_slice(st, data)?;
+                for i in 0..args.to.min(3) {
+                    sink.send(vec![i])?;
+                }
+                if args.to > 3 {
+                    return Err(Error::from("counted too far"));
+                }
+                Ok(())
+            },
+        )
+        .unwrap();
+
+    let listener = rpc_server.bind().unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for _ in 0..dropped {
+            drop(listener.accept().unwrap());
+        }
+        rpc_server.start_with_listener(listener)
+    });
+    addr.to_string()
+}
+
+#[test]
+fn calls_time_out_without_a_reply() {
+    let mut client = Client::connect(&start_server(), Opt::default()).unwrap();
+    let timeout = Some(Duration::from_millis(100));
+
+    let reply: SleepReply = client
+        .call_timeout(
+            "Sleep",
+            "Sleep",
+            false,
+            &Metadata::new(),
+            &SleepArgs { millis: 0 },
+            timeout,
+        )
+        .unwrap()
+        .unwrap();
+    assert!(reply.slept);
+
+    let start = Instant::now();
+    let res: Result<SleepReply> = client
+        .call_timeout(
+            "Sleep",
+            "Sleep",
+            false,
+            &Metadata::new(),
+            &SleepArgs { millis: 500 },
+            timeout,
+        )
+        .unwrap();
+    assert_eq!(res.unwrap_err().kind(), ErrorKind::Timeout);
+    assert!(start.elapsed() < Duration::from_millis(400));
+
+    // made on a new connection, so the late reply can't be taken for it
+    let reply: SleepReply = client
+        .call_timeout(
+            "Sleep",
+            "Sleep",
+            false,
+            &Metadata::new(),
+            &SleepArgs { millis: 0 },
+            timeout,
+        )
+        .unwrap()
+        .unwrap();
+    assert!(reply.slept);
+}
+
+#[test]
+fn idempotent_calls_are_retried_on_a_new_connection() {
+    let mut client = Client::connect(&start_server_dropping(1), Opt::default()).unwrap();
+    let options = CallOptions {
+        retries: 2,
+        backoff: Duration::from_millis(10),
+        idempotent: true,
+        compress: None,
+    };
+    let reply: SleepReply = client
+        .invoke_with_options(
+            "Sleep",
+            "Sleep",
+            &SleepArgs { millis: 0 },
+            SerializeType::JSON,
+            &options,
+        )
+        .unwrap();
+    assert!(reply.slept);
+}
+
+#[test]
+fn other_calls_fail_fast() {
+    let mut client = Client::connect(&start_server_dropping(1), Opt::default()).unwrap();
+    let options = CallOptions {
+        retries: 2,
+        backoff: Duration::from_millis(10),
+        idempotent: false,
+        compress: None,
+    };
+    let res: Result<SleepReply> = client.invoke_with_options(
+        "Sleep",
+        "Sleep",
+        &SleepArgs { millis: 0 },
+        SerializeType::JSON,
+        &options,
+    );
+    assert_eq!(res.unwrap_err().kind(), ErrorKind::Client);
+}
+
+#[test]
+fn encode_errors_are_not_retried() {
+    let mut client = Client::connect(&start_server(), Opt::default()).unwrap();
+    let options = CallOptions {
+        retries: 3,
+        backoff: Duration::from_secs(1),
+        idempotent: true,
+        compress: None,
+    };
+    let start = Instant::now();
+    // derived params can't be encoded without a format
+    let res: Result<SleepReply> = client.invoke_with_options(
+        "Sleep",
+        "Sleep",
+        &SleepArgs { millis: 0 },
+        SerializeType::SerializeNone,
+        &options,
+    );
+    assert_eq!(res.unwrap_err().kind(), ErrorKind::Serialization);
+    // a retry would have waited for the backoff and reconnected
+    assert!(start.elapsed() < Duration::from_millis(500));
+
+    let reply: SleepReply = client
+        .invoke_with_options(
+            "Sleep",
+            "Sleep",
+            &SleepArgs { millis: 0 },
+            SerializeType::JSON,
+            &options,
+        )
+        .unwrap();
+    assert!(reply.slept);
+}
+
+#[test]
+fn sync_clients_reconnect_and_match_replies_by_seq() {
+    let mut client = SyncClient::connect(&start_server_dropping(1), Opt::default()).unwrap();
+    // lets the server close the first connection
+    thread::sleep(Duration::from_millis(100));
+    let reply: SleepReply = client
+        .call("Sleep", "Sleep", &Metadata::new(), &SleepArgs { millis: 0 })
+        .unwrap();
+    assert!(reply.slept);
+
+    // the quick reply arrives first and waits for its turn
+    let metadata = Metadata::new();
+    let slow = client
+        .send("Sleep", "Sleep", &metadata, &SleepArgs { millis: 200 })
+        .unwrap();
+    let quick = client
+        .send("Sleep", "Sleep", &metadata, &SleepArgs { millis: 0 })
+        .unwrap();
+    let reply: SleepReply = client.wait(slow).unwrap();
+    assert!(reply.slept);
+    let reply: SleepReply = client.wait(quick).unwrap();
+    assert!(reply.slept);
+
+    let res: Result<SleepReply> = client.wait(quick);
+    assert_eq!(res.unwrap_err().kind(), ErrorKind::Client);
+}
+
+#[test]
+fn streamed_replies_end_with_the_last_frame() {
+    let mut client = Client::connect(&start_server(), Opt::default()).unwrap();
+
+    let frames: Vec<Vec<u8>> = client
+        .call_stream("Count", "Count", &Metadata::new(), &CountArgs { to: 3 })
+        .unwrap()
+        .collect::<Result<_>>()
+        .unwrap();
+    assert_eq!(frames, [[0], [1], [2]]);
+
+    let mut frames = client
+        .call_stream("Count", "Count", &Metadata::new(), &CountArgs { to: 5 })
+        .unwrap();
+    assert_eq!(frames.next().unwrap().unwrap(), [0]);
+    assert_eq!(frames.nth(1).unwrap().unwrap(), [2]);
+    let err = frames.next().unwrap().unwrap_err();
+    assert_eq!(err.to_string(), "counted too far");
+    assert!(frames.next().is_none());
+
+    // the connection still serves plain calls
+    let reply: SleepReply = client
+        .invoke(
+            "Sleep",
+            "Sleep",
+            &SleepArgs { millis: 0 },
+            SerializeType::JSON,
+        )
+        .unwrap();
+    assert!(reply.slept);
+}
@@ -0,0 +1,110 @@
+use rmp_serde as rmps;
+use serde::{Deserialize, Serialize};
+
+use rpcx::*;
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+#[derive(RpcxParam, Default, Debug, Copy, Clone, Serialize, Deserialize)]
+struct ArithAddArgs {
+    #[serde(rename = "A")]
+    a: u64,
+    #[serde(rename = "B")]
+    b: u64,
+}
+
+#[derive(RpcxParam, Default, Debug, Copy, Clone, Serialize, Deserialize)]
+struct ArithAddReply {
+    #[serde(rename = "C")]
+    c: u64,
+}
+
+/// Starts a server counting its connections and returns its address.
+fn start_server(connections: Arc<AtomicUsize>) -> String {
+    let mut rpc_server = Server::new("127.0.0.1:0".to_owned(), 2);
+    rpc_server
+        .register_typed(
+            "Arith".to_owned(),
+            "Add".to_owned(),
+            String::new(),
+            |args: ArithAddArgs| ArithAddReply { c: args.a + args.b },
+        )
+        .unwrap();
+    rpc_server.set_on_connect(move |_| {
+        connections.fetch_add(1, Ordering::SeqCst);
+    });
+
+    let listener = rpc_server.bind().unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || rpc_server.start_with_listener(listener));
+    addr.to_string()
+}
+
+fn add(client: &mut Client, a: u64, b: u64) -> u64 {
+    let args = ArithAddArgs { a, b };
+    let reply: ArithAddReply = client
+        .call("Arith", "Add", false, &Metadata::new(), &args)
+        .unwrap()
+        .unwrap();
+    reply.c
+}
+
+#[test]
+fn pooled_connections_are_reused() {
+    let connections = Arc::new(AtomicUsize::new(0));
+    let pool = ClientPool::new(&start_server(connections.clone()), Opt::default(), 1, 0);
+
+    for i in 0..3 {
+        let args = ArithAddArgs { a: i, b: 1 };
+        let reply: ArithAddReply = pool
+            .call("Arith", "Add", false, &Metadata::new(), &args)
+            .unwrap()
+            .unwrap();
+        assert_eq!(reply.c, i + 1);
+    }
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+    assert_eq!(pool.idle_count(), 1);
+
+    // only max_idle connections are kept
+    let mut first = pool.get().unwrap();
+    let mut second = pool.get().unwrap();
+    assert_eq!(add(&mut first, 1, 2) + add(&mut second, 3, 4), 10);
+    assert_eq!(pool.total_count(), 2);
+    drop(first);
+    drop(second);
+    assert_eq!(pool.idle_count(), 1);
+    assert_eq!(pool.total_count(), 1);
+
+    // discarded connections are replaced
+    pool.get().unwrap().discard();
+    assert_eq!(pool.total_count(), 0);
+    assert_eq!(add(&mut pool.get().unwrap(), 2, 2), 4);
+    assert_eq!(connections.load(Ordering::SeqCst), 3);
+}
+
+#[test]
+fn pool_waits_for_a_connection_at_max_total() {
+    let connections = Arc::new(AtomicUsize::new(0));
+    let pool = Arc::new(ClientPool::new(
+        &start_server(connections.clone()),
+        Opt::default(),
+        1,
+        1,
+    ));
+
+    let client = pool.get().unwrap();
+    let p = pool.clone();
+    let waiter = thread::spawn(move || add(&mut p.get().unwrap(), 1, 1));
+    thread::sleep(std::time::Duration::from_millis(100));
+    assert!(!waiter.is_finished());
+
+    drop(client);
+    assert_eq!(waiter.join().unwrap(), 2);
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+}
@@ -0,0 +1,97 @@
+use rmp_serde as rmps;
+use serde::{Deserialize, Serialize};
+
+use rpcx::*;
+
+#[derive(Clone, PartialEq, prost::Message, RpcxParam)]
+#[rpcx(protobuf_only)]
+struct ArithAddArgs {
+    #[prost(uint64, tag = "1")]
+    a: u64,
+    #[prost(uint64, tag = "2")]
+    b: u64,
+}
+
+#[cfg(feature = "protobuf")]
+#[derive(Clone, PartialEq, prost::Message, RpcxParam)]
+#[rpcx(protobuf_only)]
+struct ArithAddReply {
+    #[prost(uint64, tag = "1")]
+    c: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message, RpcxParam, Serialize, Deserialize)]
+#[rpcx(protobuf)]
+struct Greeting {
+    #[prost(string, tag = "1")]
+    name: String,
+}
+
+#[test]
+fn protobuf_round_trip() {
+    let args = ArithAddArgs { a: 3, b: 300 };
+    let data = args.into_bytes(SerializeType::Protobuf).unwrap();
+    // field 1 varint 3, field 2 varint 300
+    assert_eq!(data, [0x08, 0x03, 0x10, 0xac, 0x02]);
+
+    let mut decoded = ArithAddArgs::default();
+    decoded.from_slice(SerializeType::Protobuf, &data).unwrap();
+    assert_eq!(decoded, args);
+    assert!(args.into_bytes(SerializeType::JSON).is_err());
+}
+
+#[test]
+fn protobuf_and_serde_codecs() {
+    let greeting = Greeting {
+        name: "rpcx".to_owned(),
+    };
+    for st in &[
+        SerializeType::Protobuf,
+        SerializeType::JSON,
+        SerializeType::MsgPack,
+    ] {
+        let data = greeting.into_bytes(*st).unwrap();
+        let mut decoded = Greeting::default();
+        decoded.from_slice(*st, &data).unwrap();
+        assert_eq!(decoded, greeting);
+    }
+}
+
+#[cfg(feature = "protobuf")]
+#[test]
+fn proto_handlers_decode_the_request() {
+    use std::{io::Write, net::TcpStream, thread};
+
+    let mut rpc_server = Server::new("127.0.0.1:0".to_owned(), 1);
+    rpc_server
+        .register_proto(
+            "Arith".to_owned(),
+            "Add".to_owned(),
+            String::new(),
+            |args: ArithAddArgs| ArithAddReply { c: args.a + args.b },
+        )
+        .unwrap();
+    let listener = rpc_server.bind().unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || rpc_server.start_with_listener(listener));
+    let mut conn = TcpStream::connect(addr).unwrap();
+
+    let mut request = Message::new();
+    request.set_serialize_type(SerializeType::Protobuf);
+    request.set_seq(1);
+    request.service_path = "Arith".to_owned();
+    request.service_method = "Add".to_owned();
+    request.payload = ArithAddArgs { a: 1, b: 2 }
+        .into_bytes(SerializeType::Protobuf)
+        .unwrap();
+    conn.write_all(&request.encode()).unwrap();
+
+    let mut reply = Message::new();
+    reply.decode(&mut conn).unwrap();
+    assert_eq!(reply.get_error(), None);
+    let mut result = ArithAddReply::default();
+    result
+        .from_slice(SerializeType::Protobuf, &reply.payload)
+        .unwrap();
+    assert_eq!(result.c, 3);
+}
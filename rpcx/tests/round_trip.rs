@@ -0,0 +1,162 @@
+use rmp_serde as rmps;
+use serde::{Deserialize, Serialize};
+
+use rpcx::*;
+
+use std::{
+    io::Write,
+    net::TcpListener,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+#[derive(RpcxParam, Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct EchoArgs {
+    text: String,
+    times: u32,
+}
+
+#[derive(RpcxParam, Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct EchoReply {
+    text: String,
+}
+
+async fn echo(args: EchoArgs) -> std::result::Result<EchoReply, String> {
+    if args.times == 0 {
+        return Err("nothing to echo".to_owned());
+    }
+    Ok(EchoReply {
+        text: args.text.repeat(args.times as usize),
+    })
+}
+
+fn runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap()
+}
+
+/// Starts a server serving `Echo.Echo` on an ephemeral port and returns its
+/// address.
+fn start_server() -> String {
+    let mut rpc_server = Server::new("127.0.0.1:0".to_owned(), 2);
+    register_func!(
+        rpc_server,
+        runtime(),
+        "Echo",
+        "Echo",
+        echo,
+        String::new(),
+        EchoArgs
+    )
+    .unwrap();
+
+    let listener = rpc_server.bind().unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || rpc_server.start_with_listener(listener));
+    addr.to_string()
+}
+
+#[test]
+fn calls_round_trip() {
+    let addr = start_server();
+
+    for st in &[SerializeType::JSON, SerializeType::MsgPack] {
+        let mut opt = Opt::default();
+        opt.set_serialize_type(*st);
+        let mut client = Client::connect(&addr, opt).unwrap();
+
+        let args = EchoArgs {
+            text: "ab".to_owned(),
+            times: 3,
+        };
+        let reply: EchoReply = client
+            .call("Echo", "Echo", false, &Metadata::new(), &args)
+            .unwrap()
+            .unwrap();
+        assert_eq!(reply.text, "ababab");
+    }
+}
+
+#[test]
+fn service_errors_reach_the_caller() {
+    let mut client = Client::connect(&start_server(), Opt::default()).unwrap();
+
+    let args = EchoArgs {
+        text: "ab".to_owned(),
+        times: 0,
+    };
+    let res: Result<EchoReply> = client.invoke("Echo", "Echo", &args, SerializeType::JSON);
+    let err = res.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::RemoteService);
+    assert_eq!(err.to_string(), "nothing to echo");
+
+    let res: Result<EchoReply> = client.invoke("Echo", "Shout", &args, SerializeType::JSON);
+    let err = res.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::RemoteService);
+    assert_eq!(err.to_string(), "service Echo.Shout not found");
+}
+
+#[test]
+fn requests_may_be_compressed() {
+    let mut rpc_server = Server::new("127.0.0.1:0".to_owned(), 1);
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let s = seen.clone();
+    let echo: RpcxHandler = Box::new(move |ctx, x, _| {
+        s.lock().unwrap().push(ctx.compress_type);
+        Ok(x.to_vec())
+    });
+    rpc_server
+        .register_handler("Echo".to_owned(), "Raw".to_owned(), String::new(), echo)
+        .unwrap();
+    let listener = rpc_server.bind().unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || rpc_server.start_with_listener(listener));
+    let mut client = Client::connect(&addr.to_string(), Opt::default()).unwrap();
+
+    let args = EchoArgs {
+        text: "ab".repeat(1000),
+        times: 1,
+    };
+    for compress in &[Some(CompressType::Gzip), None] {
+        let options = CallOptions {
+            compress: *compress,
+            ..CallOptions::default()
+        };
+        let reply: EchoArgs = client
+            .invoke_with_options("Echo", "Raw", &args, SerializeType::JSON, &options)
+            .unwrap();
+        assert_eq!(reply, args);
+    }
+    assert_eq!(
+        *seen.lock().unwrap(),
+        [Some(CompressType::Gzip), Some(CompressType::CompressNone)]
+    );
+}
+
+#[test]
+fn service_errors_fail_replies_without_the_error_status() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let (mut conn, _) = listener.accept().unwrap();
+        let mut request = Message::new();
+        request.decode(&mut conn).unwrap();
+        let reply = request.get_reply().unwrap();
+        reply
+            .metadata
+            .borrow_mut()
+            .insert(SERVICE_ERROR.to_owned(), "out of echoes".to_owned());
+        conn.write_all(&reply.encode()).unwrap();
+    });
+
+    let mut client = SyncClient::new(&addr.to_string());
+    let args = EchoArgs {
+        text: "ab".to_owned(),
+        times: 1,
+    };
+    let res: Result<EchoReply> = client.call("Echo", "Echo", &Metadata::new(), &args);
+    let err = res.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::RemoteService);
+    assert_eq!(err.to_string(), "out of echoes");
+}
@@ -0,0 +1,182 @@
+use rmp_serde as rmps;
+use serde::{Deserialize, Serialize};
+
+use rpcx::*;
+
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    thread,
+};
+
+#[derive(RpcxParam, Default, Debug, Copy, Clone, Serialize, Deserialize)]
+struct ArithAddArgs {
+    #[serde(rename = "A")]
+    a: u64,
+    #[serde(rename = "B")]
+    b: u64,
+}
+
+#[derive(RpcxParam, Default, Debug, Copy, Clone, Serialize, Deserialize)]
+struct ArithAddReply {
+    #[serde(rename = "C")]
+    c: u64,
+}
+
+async fn add(args: ArithAddArgs) -> std::result::Result<ArithAddReply, String> {
+    Ok(ArithAddReply { c: args.a + args.b })
+}
+
+fn runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap()
+}
+
+fn start_server() -> TcpStream {
+    let mut rpc_server = Server::new("127.0.0.1:0".to_owned(), 1);
+    register_func!(
+        rpc_server,
+        runtime(),
+        "Arith",
+        "Add",
+        add,
+        String::new(),
+        ArithAddArgs
+    )
+    .unwrap();
+
+    let listener = rpc_server.bind().unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || rpc_server.start_with_listener(listener));
+    TcpStream::connect(addr).unwrap()
+}
+
+/// Frames a request without metadata the way the Go client does.
+fn go_frame(header: [u8; 12], path: &str, method: &str, payload: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for part in &[path.as_bytes(), method.as_bytes(), &[], payload] {
+        body.extend_from_slice(&(part.len() as u32).to_be_bytes());
+        body.extend_from_slice(part);
+    }
+
+    let mut frame = header.to_vec();
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&body);
+    frame
+}
+
+#[test]
+fn json_round_trip() {
+    let mut conn = start_server();
+
+    // version 0, request, JSON, seq 42
+    let header = [0x08, 0x00, 0x00, 0x10, 0, 0, 0, 0, 0, 0, 0, 42];
+    let request = go_frame(header, "Arith", "Add", br#"{"A":10,"B":20}"#);
+    conn.write_all(&request).unwrap();
+
+    // response, JSON, seq 42
+    let header = [0x08, 0x00, 0x80, 0x10, 0, 0, 0, 0, 0, 0, 0, 42];
+    let expected = go_frame(header, "Arith", "Add", br#"{"C":30}"#);
+    let mut reply = vec![0u8; expected.len()];
+    conn.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, expected);
+}
+
+#[test]
+fn msgpack_round_trip() {
+    let mut conn = start_server();
+
+    let mut request = Message::new();
+    request.set_message_type(MessageType::Request);
+    request.set_serialize_type(SerializeType::MsgPack);
+    request.set_compress_type(CompressType::CompressNone);
+    request.set_seq(7);
+    request.service_path = "Arith".to_owned();
+    request.service_method = "Add".to_owned();
+    request.payload = ArithAddArgs { a: 3, b: 4 }
+        .into_bytes(SerializeType::MsgPack)
+        .unwrap();
+    conn.write_all(&request.encode()).unwrap();
+
+    let mut reply = Message::new();
+    reply.decode(&mut conn).unwrap();
+    assert_eq!(reply.get_error(), None);
+    assert_eq!(reply.get_seq(), 7);
+    assert_eq!(reply.get_serialize_type(), Some(SerializeType::MsgPack));
+
+    // fields are encoded by name, as Go's msgpack codec expects
+    assert_eq!(reply.payload, [0x81, 0xa1, b'C', 0x07]);
+    let mut result = ArithAddReply::default();
+    result
+        .from_slice(SerializeType::MsgPack, &reply.payload)
+        .unwrap();
+    assert_eq!(result.c, 7);
+}
+
+#[test]
+fn malformed_request_gets_an_error_reply() {
+    let mut conn = start_server();
+
+    let header = [0x08, 0x00, 0x00, 0x10, 0, 0, 0, 0, 0, 0, 0, 1];
+    let mut request = go_frame(header, "Arith", "Add", br#"{"A":1,"B":2}"#);
+    // invalid utf-8 in the service path
+    request[20] = 0xff;
+    conn.write_all(&request).unwrap();
+
+    let mut reply = Message::new();
+    reply.decode(&mut conn).unwrap();
+    assert_eq!(reply.get_seq(), 1);
+    assert_eq!(
+        reply.get_message_status_type(),
+        Some(MessageStatusType::Error)
+    );
+    assert!(reply.get_error().unwrap().starts_with("invalid request"));
+
+    // the connection is still served
+    let header = [0x08, 0x00, 0x00, 0x10, 0, 0, 0, 0, 0, 0, 0, 2];
+    let request = go_frame(header, "Arith", "Add", br#"{"A":1,"B":2}"#);
+    conn.write_all(&request).unwrap();
+
+    let mut reply = Message::new();
+    reply.decode(&mut conn).unwrap();
+    assert_eq!(reply.get_seq(), 2);
+    assert_eq!(reply.get_error(), None);
+    assert_eq!(reply.payload, br#"{"C":3}"#);
+}
+
+#[test]
+fn serialize_types_may_change_per_message() {
+    let mut conn = start_server();
+
+    for (seq, st) in [
+        SerializeType::JSON,
+        SerializeType::MsgPack,
+        SerializeType::JSON,
+    ]
+    .iter()
+    .enumerate()
+    {
+        let mut request = Message::new();
+        request.set_message_type(MessageType::Request);
+        request.set_serialize_type(*st);
+        request.set_seq(seq as u64);
+        request.service_path = "Arith".to_owned();
+        request.service_method = "Add".to_owned();
+        request.payload = ArithAddArgs {
+            a: seq as u64,
+            b: 1,
+        }
+        .into_bytes(*st)
+        .unwrap();
+        conn.write_all(&request.encode()).unwrap();
+
+        let mut reply = Message::new();
+        reply.decode(&mut conn).unwrap();
+        assert_eq!(reply.get_error(), None);
+        assert_eq!(reply.get_serialize_type(), Some(*st));
+        let mut result = ArithAddReply::default();
+        result.from_slice(*st, &reply.payload).unwrap();
+        assert_eq!(result.c, seq as u64 + 1);
+    }
+}
@@ -0,0 +1,122 @@
+use rmp_serde as rmps;
+use serde::{Deserialize, Serialize};
+
+use rpcx::*;
+
+use std::thread;
+
+#[derive(RpcxParam, Default, Debug, Copy, Clone, Serialize, Deserialize)]
+struct ArithArgs {
+    #[serde(rename = "A")]
+    a: u64,
+    #[serde(rename = "B")]
+    b: u64,
+}
+
+#[derive(RpcxParam, Default, Debug, Copy, Clone, Serialize, Deserialize)]
+struct ArithReply {
+    #[serde(rename = "C")]
+    c: u64,
+}
+
+struct Arith {
+    scale: u64,
+}
+
+#[rpcx_service]
+impl Arith {
+    pub fn add(&self, args: ArithArgs) -> std::result::Result<ArithReply, String> {
+        Ok(ArithReply {
+            c: (args.a + args.b) * self.scale,
+        })
+    }
+
+    pub fn checked_div(&self, args: ArithArgs) -> std::result::Result<ArithReply, String> {
+        match args.a.checked_div(args.b) {
+            Some(c) => Ok(ArithReply { c: c * self.scale }),
+            None => Err("divide by zero".to_owned()),
+        }
+    }
+
+    // no method, it isn't public
+    #[allow(dead_code)]
+    fn mul(&self, args: ArithArgs) -> std::result::Result<ArithReply, String> {
+        Ok(ArithReply { c: args.a * args.b })
+    }
+}
+
+#[test]
+fn service_methods_are_registered_at_once() {
+    let mut rpc_server = Server::new("127.0.0.1:0".to_owned(), 1);
+    rpc_server
+        .register_service("Arith", "group=math".to_owned(), Arith { scale: 10 })
+        .unwrap();
+    assert_eq!(rpc_server.service_count(), 2);
+
+    let listener = rpc_server.bind().unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || rpc_server.start_with_listener(listener));
+    let mut client = Client::connect(&addr.to_string(), Opt::default()).unwrap();
+
+    let args = ArithArgs { a: 6, b: 3 };
+    let reply: ArithReply = client
+        .invoke("Arith", "Add", &args, SerializeType::JSON)
+        .unwrap();
+    assert_eq!(reply.c, 90);
+    let reply: ArithReply = client
+        .invoke("Arith", "CheckedDiv", &args, SerializeType::MsgPack)
+        .unwrap();
+    assert_eq!(reply.c, 20);
+
+    let args = ArithArgs { a: 6, b: 0 };
+    let res: Result<ArithReply> = client.invoke("Arith", "CheckedDiv", &args, SerializeType::JSON);
+    assert_eq!(res.unwrap_err().to_string(), "divide by zero");
+    let res: Result<ArithReply> = client.invoke("Arith", "Mul", &args, SerializeType::JSON);
+    assert_eq!(res.unwrap_err().to_string(), "service Arith.Mul not found");
+}
+
+/// A service with a method name that can't be registered.
+struct Broken;
+
+impl RpcxService for Broken {
+    fn method_names(&self) -> Vec<&'static str> {
+        vec!["Add", "Checked Div"]
+    }
+
+    fn call(&self, _: &str, args: &[u8], _: SerializeType) -> Result<Vec<u8>> {
+        Ok(args.to_vec())
+    }
+}
+
+#[test]
+fn services_are_registered_with_their_meta_or_not_at_all() {
+    let plugin = MemoryRegisterPlugin::new();
+    let mut rpc_server = Server::new("127.0.0.1:0".to_owned(), 1);
+    rpc_server.add_register_plugin(Box::new(plugin.clone()));
+
+    rpc_server
+        .register_service("Arith", "group=math".to_owned(), Arith { scale: 1 })
+        .unwrap();
+    assert_eq!(
+        plugin.registrations(),
+        [
+            (
+                "Arith".to_owned(),
+                "Add".to_owned(),
+                "group=math".to_owned()
+            ),
+            (
+                "Arith".to_owned(),
+                "CheckedDiv".to_owned(),
+                "group=math".to_owned()
+            ),
+        ]
+    );
+
+    let err = rpc_server
+        .register_service("Broken", String::new(), Broken)
+        .unwrap_err();
+    assert_eq!(err.to_string(), "invalid service method \"Checked Div\"");
+    assert_eq!(rpc_server.service_count(), 2);
+    assert_eq!(plugin.registrations().len(), 2);
+}
@@ -0,0 +1,71 @@
+#![cfg(feature = "tower")]
+
+use rmp_serde as rmps;
+use serde::{Deserialize, Serialize};
+use tower_service::Service;
+
+use rpcx::*;
+
+use std::{future::poll_fn, thread};
+
+#[derive(RpcxParam, Default, Debug, Copy, Clone, Serialize, Deserialize)]
+struct ArithArgs {
+    #[serde(rename = "A")]
+    a: u64,
+    #[serde(rename = "B")]
+    b: u64,
+}
+
+#[derive(RpcxParam, Default, Debug, Copy, Clone, Serialize, Deserialize)]
+struct ArithReply {
+    #[serde(rename = "C")]
+    c: u64,
+}
+
+fn start_server() -> String {
+    let mut rpc_server = Server::new("127.0.0.1:0".to_owned(), 1);
+    rpc_server
+        .register_typed(
+            "Arith".to_owned(),
+            "Add".to_owned(),
+            String::new(),
+            |args: ArithArgs| ArithReply { c: args.a + args.b },
+        )
+        .unwrap();
+
+    let listener = rpc_server.bind().unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || rpc_server.start_with_listener(listener));
+    addr.to_string()
+}
+
+#[test]
+fn clients_are_tower_services() {
+    let client = Client::connect(&start_server(), Opt::default()).unwrap();
+    let mut service = ClientService::new(client);
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+
+    for st in &[SerializeType::JSON, SerializeType::MsgPack] {
+        let payload = ArithArgs { a: 1, b: 2 }.into_bytes(*st).unwrap();
+        let req = Request::new("Arith", "Add", payload, *st);
+        let reply = rt
+            .block_on(async {
+                poll_fn(|cx| service.poll_ready(cx)).await?;
+                service.call(req).await
+            })
+            .unwrap();
+
+        let mut result = ArithReply::default();
+        result
+            .from_slice(reply.serialize_type, &reply.payload)
+            .unwrap();
+        assert_eq!(result.c, 3);
+    }
+
+    let req = Request::new("Arith", "Mul", Vec::new(), SerializeType::JSON);
+    let err = rt.block_on(service.clone().call(req)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::RemoteService);
+    assert_eq!(err.to_string(), "service Arith.Mul not found");
+}
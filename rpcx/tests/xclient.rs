@@ -0,0 +1,136 @@
+use rmp_serde as rmps;
+use serde::{Deserialize, Serialize};
+
+use rpcx::*;
+
+use std::{
+    collections::HashMap,
+    net::TcpListener,
+    thread,
+    time::{Duration, Instant},
+};
+
+#[derive(RpcxParam, Default, Debug, Copy, Clone, Serialize, Deserialize)]
+struct ArithAddArgs {
+    #[serde(rename = "A")]
+    a: u64,
+    #[serde(rename = "B")]
+    b: u64,
+}
+
+#[derive(RpcxParam, Default, Debug, Copy, Clone, Serialize, Deserialize)]
+struct ArithAddReply {
+    #[serde(rename = "C")]
+    c: u64,
+}
+
+fn start_server() -> String {
+    start_server_dropping(0)
+}
+
+/// Like `start_server`, but the first `dropped` connections are closed
+/// without reading anything.
+fn start_server_dropping(dropped: usize) -> String {
+    let mut rpc_server = Server::new("127.0.0.1:0".to_owned(), 1);
+    rpc_server
+        .register_typed(
+            "Arith".to_owned(),
+            "Add".to_owned(),
+            String::new(),
+            |args: ArithAddArgs| ArithAddReply { c: args.a + args.b },
+        )
+        .unwrap();
+
+    let listener = rpc_server.bind().unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for _ in 0..dropped {
+            drop(listener.accept().unwrap());
+        }
+        rpc_server.start_with_listener(listener)
+    });
+    addr.to_string()
+}
+
+/// Returns an address nothing listens on.
+fn dead_addr() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+fn add(client: &mut XClient<RoundbinSelector>, a: u64) -> Result<u64> {
+    let args = ArithAddArgs { a, b: 1 };
+    let reply: ArithAddReply = client
+        .call("Add", false, &Metadata::new(), &args)
+        .unwrap()?;
+    Ok(reply.c)
+}
+
+fn xclient(fail_mode: FailMode) -> XClient<RoundbinSelector> {
+    let selector = RoundbinSelector::new();
+    let mut servers = HashMap::new();
+    servers.insert(format!("tcp@{}", start_server()), String::new());
+    servers.insert(format!("tcp@{}", dead_addr()), String::new());
+    selector.update_server(&servers);
+    XClient::new(
+        "Arith".to_owned(),
+        fail_mode,
+        Box::new(selector),
+        Opt::default(),
+    )
+}
+
+#[test]
+fn failing_endpoints_are_left_out_of_the_selection() {
+    let mut client = xclient(FailMode::Failfast);
+    let results: Vec<_> = (0..6).map(|i| add(&mut client, i)).collect();
+
+    // the dead endpoint fails the first call it is picked for only
+    let failed = results.iter().filter(|res| res.is_err()).count();
+    assert_eq!(failed, 1);
+    for (i, res) in results.iter().enumerate().skip(2) {
+        assert_eq!(*res.as_ref().unwrap(), i as u64 + 1);
+    }
+}
+
+#[test]
+fn failover_calls_go_to_another_endpoint() {
+    let mut client = xclient(FailMode::Failover);
+    for i in 0..4 {
+        assert_eq!(add(&mut client, i).unwrap(), i + 1);
+    }
+}
+
+#[test]
+fn circuit_breakers_open_and_recover() {
+    let addr = format!("tcp@{}", start_server_dropping(2));
+    let selector = RoundbinSelector::new();
+    let mut servers = HashMap::new();
+    servers.insert(addr.clone(), String::new());
+    selector.update_server(&servers);
+    let mut client = XClient::new(
+        "Arith".to_owned(),
+        FailMode::Failfast,
+        Box::new(selector),
+        Opt::default(),
+    );
+    client.set_breaker(BreakerConfig {
+        failure_threshold: 2,
+        cooldown: Duration::from_millis(200),
+    });
+
+    assert_eq!(add(&mut client, 1).unwrap_err().kind(), ErrorKind::Client);
+    assert_eq!(client.breaker_state(&addr), BreakerState::Closed);
+    assert_eq!(add(&mut client, 1).unwrap_err().kind(), ErrorKind::Client);
+    assert_eq!(client.breaker_state(&addr), BreakerState::Open);
+
+    // fails fast without connecting, the server would answer now
+    let start = Instant::now();
+    assert_eq!(add(&mut client, 1).unwrap_err().kind(), ErrorKind::Network);
+    assert!(start.elapsed() < Duration::from_millis(100));
+
+    thread::sleep(Duration::from_millis(250));
+    assert_eq!(client.breaker_state(&addr), BreakerState::HalfOpen);
+    assert_eq!(add(&mut client, 1).unwrap(), 2);
+    assert_eq!(client.breaker_state(&addr), BreakerState::Closed);
+}
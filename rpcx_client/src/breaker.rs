@@ -0,0 +1,109 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// When a `CircuitBreaker` opens and how long it stays open.
+#[derive(Debug, Copy, Clone)]
+pub struct BreakerConfig {
+    /// How many calls in a row must fail for the breaker to open.
+    pub failure_threshold: u32,
+    /// How long the breaker stays open before it lets a call through to
+    /// probe whether the endpoint recovered.
+    pub cooldown: Duration,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        BreakerConfig {
+            failure_threshold: 5,
+            cooldown: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Whether a `CircuitBreaker` lets calls through.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BreakerState {
+    /// Calls are made.
+    Closed,
+    /// Calls fail fast until the cooldown passed.
+    Open,
+    /// The cooldown passed; the next call probes the endpoint, and closes
+    /// the breaker if it succeeds or opens it again if it fails.
+    HalfOpen,
+}
+
+/// Stops calls to an endpoint that keeps failing them, so a dead backend
+/// isn't hammered and its callers fail fast instead of waiting on it.
+///
+/// Callers ask `allow` before every call and report how it went with
+/// `on_success` or `on_failure`.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    config: BreakerConfig,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// The calls that failed in a row.
+    failures: u32,
+    opened_at: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: BreakerConfig) -> Self {
+        CircuitBreaker {
+            config,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        let inner = self.inner.lock().unwrap();
+        self.state_of(&inner)
+    }
+
+    fn state_of(&self, inner: &Inner) -> BreakerState {
+        match inner.opened_at {
+            None => BreakerState::Closed,
+            Some(at) if at.elapsed() < self.config.cooldown => BreakerState::Open,
+            Some(_) => BreakerState::HalfOpen,
+        }
+    }
+
+    /// Whether a call may be made now.
+    ///
+    /// Letting a call probe a half-open breaker opens it again, so the
+    /// other calls fail fast while the probe is on its way, and a probe
+    /// that is never reported doesn't keep the breaker from probing again
+    /// after the next cooldown.
+    pub fn allow(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match self.state_of(&inner) {
+            BreakerState::Closed => true,
+            BreakerState::Open => false,
+            BreakerState::HalfOpen => {
+                inner.opened_at = Some(Instant::now());
+                true
+            }
+        }
+    }
+
+    /// Reports a call that succeeded, which closes the breaker.
+    pub fn on_success(&self) {
+        *self.inner.lock().unwrap() = Inner::default();
+    }
+
+    /// Reports a call that failed. The breaker opens once
+    /// `failure_threshold` calls failed in a row, or again if the call
+    /// probing it failed.
+    pub fn on_failure(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.failures = inner.failures.saturating_add(1);
+        if inner.opened_at.is_some() || inner.failures >= self.config.failure_threshold {
+            inner.opened_at = Some(Instant::now());
+        }
+    }
+}
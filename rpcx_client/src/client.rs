@@ -6,7 +6,7 @@ use std::{
     net::{Shutdown, SocketAddr, TcpStream},
     sync::{
         atomic::{AtomicU64, Ordering},
-        mpsc::{self, Receiver, SendError, SyncSender},
+        mpsc::{self, Receiver, SendError, Sender, SyncSender},
         Arc, Mutex,
     },
     thread,
@@ -14,7 +14,7 @@ use std::{
 };
 
 use rpcx_protocol::{call::*, *};
-use tokio::runtime::Runtime;
+use tokio::runtime::{Builder, Runtime};
 
 #[derive(Debug, Copy, Clone)]
 pub struct Opt {
@@ -50,6 +50,35 @@ impl Opt {
     }
 }
 
+/// How `Client::invoke_with_options` retries a call.
+#[derive(Debug, Copy, Clone)]
+pub struct CallOptions {
+    /// How many times a call failing with a connection error is retried
+    /// on a new connection.
+    pub retries: u32,
+    /// How long to wait before the first retry, doubled for every further
+    /// one.
+    pub backoff: Duration,
+    /// Calls are only retried if they are idempotent, since a request may
+    /// have been handled even though its reply was lost.
+    pub idempotent: bool,
+    /// How the payload of the request is compressed, if not as
+    /// `Opt::compress_type` says. Replies are decompressed however they are
+    /// compressed; the server compresses its reply like the request.
+    pub compress: Option<CompressType>,
+}
+
+impl Default for CallOptions {
+    fn default() -> Self {
+        CallOptions {
+            retries: 0,
+            backoff: Duration::from_millis(100),
+            idempotent: false,
+            compress: None,
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 struct RpcData {
     seq: u64,
@@ -66,10 +95,22 @@ pub struct Client {
     chan_sender: SyncSender<RpcData>,
     chan_receiver: Arc<Mutex<Receiver<RpcData>>>,
     calls: Arc<Mutex<HashMap<u64, ArcCall>>>,
+    streams: Streams,
+    /// Waits for the replies of blocking calls, created by the first of them.
+    rt: Option<Runtime>,
 }
 
+/// The senders of the frames of every open `ReplyStream`, keyed by seq.
+type Streams = Arc<Mutex<HashMap<u64, Sender<Message>>>>;
+
 impl Client {
+    /// Connects to `addr`, panicking if it can't, see `connect`.
     pub fn new(addr: &str,opt:Opt) -> Client {
+        Client::connect(addr, opt).unwrap()
+    }
+
+    /// Connects to `addr`.
+    pub fn connect(addr: &str, opt: Opt) -> Result<Client> {
         let (sender, receiver) = mpsc::sync_channel(0);
 
         let mut c=Client {
@@ -80,9 +121,11 @@ impl Client {
             chan_sender: sender,
             chan_receiver: Arc::new(Mutex::new(receiver)),
             calls: Arc::new(Mutex::new(HashMap::new())),
+            streams: Arc::new(Mutex::new(HashMap::new())),
+            rt: None,
         };
-        c.start().unwrap();
-        c
+        c.start()?;
+        Ok(c)
     }
     pub fn start(&mut self) -> Result<()> {
         let stream = if self.opt.connect_timeout.as_millis() == 0 {
@@ -113,6 +156,7 @@ impl Client {
         self.stream = Some(stream);
 
         let calls = self.calls.clone();
+        let streams = self.streams.clone();
         thread::spawn(move || {
             let mut reader = BufReader::new(read_stream.try_clone().unwrap());
 
@@ -120,14 +164,17 @@ impl Client {
                 let mut msg = Message::new();
                 match msg.decode(&mut reader) {
                     Ok(()) => {
+                        if let Some(sender) = stream_sender(&streams, &msg) {
+                            let _ = sender.send(msg);
+                            continue;
+                        }
                         if let Some(call) = calls.lock().unwrap().remove(&msg.get_seq()) {
                             let internal_call_cloned = call.clone();
                             let mut internal_call_mutex = internal_call_cloned.lock().unwrap();
                             let internal_call = internal_call_mutex.get_mut();
                             internal_call.is_client_error = false;
-                            if let Some(MessageStatusType::Error) = msg.get_message_status_type() {
-                                internal_call.error =
-                                    msg.get_error().unwrap_or_else(|| "".to_owned());
+                            if let Some(err) = service_error(&msg) {
+                                internal_call.error = err;
                             } else {
                                 internal_call.reply_data.extend_from_slice(&msg.payload);
                             }
@@ -142,6 +189,8 @@ impl Client {
                     Err(err) => {
                         println!("failed to read: {}", err.to_string());
                         Self::drain_calls(calls, err);
+                        // ends the open streams with an error
+                        streams.lock().unwrap().clear();
                         match read_stream.shutdown(Shutdown::Both) {
                             Ok(_) => {}
                             Err(err) => eprintln!("failed to shutdown stream: {}", err),
@@ -195,6 +244,20 @@ impl Client {
         Ok(())
     }
 
+    /// Replaces the connection with a new one.
+    ///
+    /// Dropping the sender of the old connection's channel stops its writer
+    /// thread, so requests can't end up on the old connection.
+    pub fn reconnect(&mut self) -> Result<()> {
+        if let Some(stream) = self.stream.take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        let (sender, receiver) = mpsc::sync_channel(0);
+        self.chan_sender = sender;
+        self.chan_receiver = Arc::new(Mutex::new(receiver));
+        self.start()
+    }
+
     pub fn send(
         &self,
         service_path: &str,
@@ -204,26 +267,38 @@ impl Client {
         metadata: &Metadata,
         args: &dyn RpcxParam,
     ) -> CallFuture {
-        let seq = self.seq.clone().fetch_add(1, Ordering::SeqCst);
-
-        let mut req = Message::new();
-        req.set_version(0);
-        req.set_message_type(MessageType::Request);
-        req.set_serialize_type(self.opt.serialize_type);
-        req.set_compress_type(self.opt.compress_type);
-        req.set_seq(seq);
-        req.service_path = service_path.to_string();
-        req.service_method = service_method.to_string();
-
-        let mut new_metadata = HashMap::with_capacity(metadata.len());
-        for (k, v) in metadata {
-            new_metadata.insert(k.clone(), v.clone());
-        }
-        req.metadata.replace(new_metadata);
-        let payload = args.into_bytes(self.opt.serialize_type).unwrap();
-        req.payload = payload;
+        self.send_with(
+            service_path,
+            service_method,
+            is_oneway,
+            is_heartbeat,
+            metadata,
+            args,
+            self.opt.serialize_type,
+            self.opt.compress_type,
+        )
+    }
 
-        let data = req.encode();
+    /// Like `send`, but encodes the request as `st` and compresses it as
+    /// `ct` instead of what `opt` says.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn send_with(
+        &self,
+        service_path: &str,
+        service_method: &str,
+        is_oneway: bool,
+        is_heartbeat: bool,
+        metadata: &Metadata,
+        args: &dyn RpcxParam,
+        st: SerializeType,
+        ct: CompressType,
+    ) -> CallFuture {
+        let seq = self.seq.clone().fetch_add(1, Ordering::SeqCst);
+        let data =
+            match self.encode_request(seq, service_path, service_method, metadata, args, st, ct) {
+                Ok(data) => data,
+                Err(err) => return failed_call(seq, &Error::new(ErrorKind::Serialization, err)),
+            };
 
         let call_future = if !is_oneway && !is_heartbeat {
             let callback = Call::new(seq);
@@ -248,6 +323,64 @@ impl Client {
         call_future
     }
 
+    /// Calls `service_path.service_method` of a streaming service, whose
+    /// reply comes in several frames, and returns the frames' payloads as
+    /// they arrive.
+    pub fn call_stream(
+        &mut self,
+        service_path: &str,
+        service_method: &str,
+        metadata: &Metadata,
+        args: &dyn RpcxParam,
+    ) -> Result<ReplyStream> {
+        let st = self.opt.serialize_type;
+        let ct = self.opt.compress_type;
+        let seq = self.seq.clone().fetch_add(1, Ordering::SeqCst);
+        let data =
+            self.encode_request(seq, service_path, service_method, metadata, args, st, ct)?;
+
+        // registered before sending, so no frame can arrive unclaimed
+        let (sender, receiver) = mpsc::channel();
+        self.streams.lock().unwrap().insert(seq, sender);
+        if self.chan_sender.send(RpcData { seq, data }).is_err() {
+            self.streams.lock().unwrap().remove(&seq);
+        }
+
+        Ok(ReplyStream {
+            receiver,
+            done: false,
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn encode_request(
+        &self,
+        seq: u64,
+        service_path: &str,
+        service_method: &str,
+        metadata: &Metadata,
+        args: &dyn RpcxParam,
+        st: SerializeType,
+        ct: CompressType,
+    ) -> Result<Vec<u8>> {
+        let mut req = Message::new();
+        req.set_version(0);
+        req.set_message_type(MessageType::Request);
+        req.set_serialize_type(st);
+        req.set_compress_type(ct);
+        req.set_seq(seq);
+        req.service_path = service_path.to_string();
+        req.service_method = service_method.to_string();
+
+        let mut new_metadata = HashMap::with_capacity(metadata.len());
+        for (k, v) in metadata {
+            new_metadata.insert(k.clone(), v.clone());
+        }
+        req.metadata.replace(new_metadata);
+        req.payload = args.into_bytes(st)?;
+        Ok(req.encode())
+    }
+
     fn remove_call_with_senderr(&self, err: SendError<RpcData>) {
         let seq = err.0.seq;
         let calls = self.calls.clone();
@@ -308,41 +441,294 @@ impl Client {
     where
         T: RpcxParam + Default,
     {
-        let rt = Runtime::new().unwrap();
-        let callfuture = rt.block_on(async {
-            let f = self.send(
-                service_path,
-                service_method,
-                is_oneway,
-                false,
-                metadata,
-                args,
-            );
-            f.await
-        });
+        let (st, ct) = (self.opt.serialize_type, self.opt.compress_type);
+        self.call_with(service_path, service_method, is_oneway, metadata, args, st, ct, None)
+    }
 
-        if is_oneway {
-            return None;
-        }
+    /// Like `call`, but gives up with an `ErrorKind::Timeout` error if no
+    /// reply arrived within `timeout`.
+    ///
+    /// A late reply would still be on its way, so the connection is shut
+    /// down after a timeout and the next call is made on a new one.
+    pub fn call_timeout<T>(
+        &mut self,
+        service_path: &str,
+        service_method: &str,
+        is_oneway: bool,
+        metadata: &Metadata,
+        args: &dyn RpcxParam,
+        timeout: Option<Duration>,
+    ) -> Option<Result<T>>
+    where
+        T: RpcxParam + Default,
+    {
+        let (st, ct) = (self.opt.serialize_type, self.opt.compress_type);
+        self.call_with(service_path, service_method, is_oneway, metadata, args, st, ct, timeout)
+    }
 
-        let arc_call_1 = callfuture.unwrap().clone();
-        let mut arc_call_2 = arc_call_1.lock().unwrap();
-        let arc_call_3 = arc_call_2.get_mut();
-        let reply_data = &arc_call_3.reply_data;
+    /// Calls `service_path.service_method` with `args` encoded as `st` and
+    /// decodes the reply the same way.
+    ///
+    /// Errors reported by the service through `SERVICE_ERROR` are returned as
+    /// `Err` of `ErrorKind::RemoteService`, so remote failures can be
+    /// propagated with `?`.
+    pub fn invoke<A, R>(
+        &mut self,
+        service_path: &str,
+        service_method: &str,
+        args: &A,
+        st: SerializeType,
+    ) -> Result<R>
+    where
+        A: RpcxParam,
+        R: RpcxParam + Default,
+    {
+        let ct = self.opt.compress_type;
+        self.invoke_compressed(service_path, service_method, args, st, ct)
+    }
 
-        if !arc_call_3.error.is_empty() {
-            let err = &arc_call_3.error;
-            if arc_call_3.is_client_error {
-                return Some(Err(Error::new(ErrorKind::Client, String::from(err))));
+    /// Like `invoke`, but compresses the request as `ct`.
+    fn invoke_compressed<A, R>(
+        &mut self,
+        service_path: &str,
+        service_method: &str,
+        args: &A,
+        st: SerializeType,
+        ct: CompressType,
+    ) -> Result<R>
+    where
+        A: RpcxParam,
+        R: RpcxParam + Default,
+    {
+        let metadata = Metadata::new();
+        self.call_with(service_path, service_method, false, &metadata, args, st, ct, None)
+            .unwrap_or_else(|| Err(Error::from("reply is empty")))
+    }
+
+    /// Like `invoke`, but compresses the request and retries calls failing
+    /// with a connection error on a new connection as `options` says.
+    pub fn invoke_with_options<A, R>(
+        &mut self,
+        service_path: &str,
+        service_method: &str,
+        args: &A,
+        st: SerializeType,
+        options: &CallOptions,
+    ) -> Result<R>
+    where
+        A: RpcxParam,
+        R: RpcxParam + Default,
+    {
+        let ct = options.compress.unwrap_or(self.opt.compress_type);
+        let retries = if options.idempotent { options.retries } else { 0 };
+        let mut backoff = options.backoff;
+        let mut attempt = 0;
+        loop {
+            let res = if self.stream.is_some() {
+                self.invoke_compressed(service_path, service_method, args, st, ct)
             } else {
-                return Some(Err(Error::from(String::from(err))));
+                Err(Error::new(ErrorKind::Network, "not connected"))
+            };
+            match res {
+                Err(err) if attempt < retries && is_connection_error(&err) => {
+                    attempt += 1;
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                    if self.reconnect().is_err() {
+                        // retried with the next attempt
+                        self.stream = None;
+                    }
+                }
+                res => return res,
             }
         }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn call_with<T>(
+        &mut self,
+        service_path: &str,
+        service_method: &str,
+        is_oneway: bool,
+        metadata: &Metadata,
+        args: &dyn RpcxParam,
+        st: SerializeType,
+        ct: CompressType,
+        timeout: Option<Duration>,
+    ) -> Option<Result<T>>
+    where
+        T: RpcxParam + Default,
+    {
+        if self.stream.is_none() {
+            // shut down after a timeout
+            if let Err(err) = self.reconnect() {
+                return Some(Err(err));
+            }
+        }
+        if self.rt.is_none() {
+            match Builder::new_current_thread().enable_time().build() {
+                Ok(rt) => self.rt = Some(rt),
+                Err(err) => return Some(Err(Error::from(err))),
+            }
+        }
+        let rt = self.rt.as_ref().unwrap();
+        let f = self.send_with(
+            service_path,
+            service_method,
+            is_oneway,
+            false,
+            metadata,
+            args,
+            st,
+            ct,
+        );
+        let seq = f.arc_call.as_ref().map(|call| call.lock().unwrap().borrow().seq);
+        let callfuture = match timeout {
+            Some(timeout) => match rt.block_on(async { tokio::time::timeout(timeout, f).await }) {
+                Ok(callfuture) => callfuture,
+                Err(_) => {
+                    if let Some(seq) = seq {
+                        self.calls.lock().unwrap().remove(&seq);
+                    }
+                    if let Some(stream) = self.stream.take() {
+                        let _ = stream.shutdown(Shutdown::Both);
+                    }
+                    let err = format!(
+                        "{}.{} timed out after {:?}",
+                        service_path, service_method, timeout
+                    );
+                    return Some(Err(Error::new(ErrorKind::Timeout, err)));
+                }
+            },
+            None => rt.block_on(f),
+        };
+
+        if is_oneway {
+            return None;
+        }
+
+        let reply_data = match call_result(&callfuture.unwrap()) {
+            Ok(reply_data) => reply_data,
+            Err(err) => return Some(Err(err)),
+        };
 
         let mut reply: T = Default::default();
-        match reply.from_slice(self.opt.serialize_type, &reply_data) {
+        match reply.from_slice(st, &reply_data) {
             Ok(()) => Some(Ok(reply)),
-            Err(err) => Some(Err(err)),
+            Err(err) => Some(Err(Error::new(ErrorKind::Serialization, err))),
+        }
+    }
+}
+
+/// The payloads of a streamed reply, see `Client::call_stream`.
+///
+/// The iteration ends with the frame marked `STREAM_END`, yielding its
+/// error if the call failed. A reply of a service that doesn't stream is
+/// yielded as the only frame.
+#[derive(Debug)]
+pub struct ReplyStream {
+    receiver: Receiver<Message>,
+    done: bool,
+}
+
+impl Iterator for ReplyStream {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let msg = match self.receiver.recv() {
+            Ok(msg) => msg,
+            Err(_) => {
+                self.done = true;
+                let err = "connection closed before the end of the stream";
+                return Some(Err(Error::new(ErrorKind::Client, err)));
+            }
+        };
+        if is_more(&msg) {
+            return Some(Ok(msg.payload));
+        }
+
+        self.done = true;
+        if let Some(err) = service_error(&msg) {
+            return Some(Err(Error::new(ErrorKind::RemoteService, err)));
+        }
+        if msg.payload.is_empty() {
+            None
+        } else {
+            Some(Ok(msg.payload))
         }
     }
 }
+
+/// Whether `msg` is a frame of a stream with more frames to come.
+fn is_more(msg: &Message) -> bool {
+    msg.metadata.borrow().get(STREAM).map(String::as_str) == Some(STREAM_MORE)
+}
+
+/// Returns the sender of the stream `msg` belongs to, forgetting the stream
+/// if `msg` is its last frame.
+fn stream_sender(streams: &Streams, msg: &Message) -> Option<Sender<Message>> {
+    let mut streams = streams.lock().unwrap();
+    if is_more(msg) {
+        streams.get(&msg.get_seq()).cloned()
+    } else {
+        streams.remove(&msg.get_seq())
+    }
+}
+
+/// The error the service reported for the call answered by `msg`.
+///
+/// A reply is failed by a `SERVICE_ERROR` in its metadata, whatever its
+/// status, and by the error status without one.
+pub(crate) fn service_error(msg: &Message) -> Option<String> {
+    let metadata = msg.metadata.borrow();
+    match metadata.get(SERVICE_ERROR).filter(|err| !err.is_empty()) {
+        Some(err) => Some(err.clone()),
+        None => match msg.get_message_status_type() {
+            Some(MessageStatusType::Error) => Some("unknown service error".to_owned()),
+            _ => None,
+        },
+    }
+}
+
+/// Takes the reply data of a finished call, or returns the error it failed
+/// with.
+pub(crate) fn call_result(call: &ArcCall) -> Result<Vec<u8>> {
+    let mut call = call.lock().unwrap();
+    let call = call.get_mut();
+    if call.error.is_empty() {
+        return Ok(std::mem::take(&mut call.reply_data));
+    }
+    let kind = if call.is_client_error {
+        call.error_kind
+    } else {
+        ErrorKind::RemoteService
+    };
+    Err(Error::new(kind, call.error.clone()))
+}
+
+/// Whether `err` means the connection failed rather than the call, so the
+/// call may succeed on a new connection.
+fn is_connection_error(err: &Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::Client | ErrorKind::Network | ErrorKind::IO
+    )
+}
+
+/// Builds an already completed call for a request that could not be sent.
+pub(crate) fn failed_call(seq: u64, err: &Error) -> CallFuture {
+    let callback = Call::new(seq);
+    let arc_call = Arc::new(Mutex::new(RefCell::from(callback)));
+    let internal_call_cloned = arc_call.clone();
+    let mut internal_call_mutex = internal_call_cloned.lock().unwrap();
+    let internal_call = internal_call_mutex.get_mut();
+    internal_call.error = err.to_string();
+    internal_call.error_kind = err.kind();
+    internal_call.state.lock().unwrap().ready = true;
+    drop(internal_call_mutex);
+
+    CallFuture::new(Some(arc_call))
+}
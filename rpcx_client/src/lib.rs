@@ -1,11 +1,21 @@
+pub mod breaker;
 pub mod client;
 pub mod discovery;
+pub mod pool;
 pub mod selector;
+pub mod sync_client;
+#[cfg(feature = "tower")]
+pub mod tower;
 pub mod xclient;
 
+pub use breaker::*;
 pub use client::*;
 pub use discovery::*;
+pub use pool::*;
 pub use selector::*;
+pub use sync_client::*;
+#[cfg(feature = "tower")]
+pub use self::tower::*;
 pub use xclient::*;
 
 use async_trait::async_trait;
@@ -0,0 +1,164 @@
+use super::client::{Client, Opt};
+
+use rpcx_protocol::{ErrorKind, Metadata, Result, RpcxParam};
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{Condvar, Mutex},
+};
+
+/// Reuses connections to one server instead of dialing one per call.
+///
+/// `get` hands out an idle connection, or dials a new one while fewer than
+/// `max_total` are open, and otherwise waits for one to be returned.
+/// Connections come back to the pool when the `PooledClient` is dropped; at
+/// most `max_idle` are kept, the others are closed.
+#[derive(Debug)]
+pub struct ClientPool {
+    addr: String,
+    opt: Opt,
+    max_idle: usize,
+    max_total: usize,
+    state: Mutex<PoolState>,
+    returned: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct PoolState {
+    idle: Vec<Client>,
+    /// The idle connections and those handed out.
+    total: usize,
+}
+
+impl ClientPool {
+    /// `max_total` of `0` means unlimited.
+    pub fn new(addr: &str, opt: Opt, max_idle: usize, max_total: usize) -> Self {
+        ClientPool {
+            addr: addr.to_owned(),
+            opt,
+            max_idle,
+            max_total,
+            state: Mutex::new(PoolState::default()),
+            returned: Condvar::new(),
+        }
+    }
+
+    /// Hands out a connection, waiting while `max_total` of them are in
+    /// use. Fails if a new connection has to be dialed and can't be.
+    pub fn get(&self) -> Result<PooledClient<'_>> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(client) = state.idle.pop() {
+                return Ok(self.pooled(client));
+            }
+            if self.max_total == 0 || state.total < self.max_total {
+                break;
+            }
+            state = self.returned.wait(state).unwrap();
+        }
+        state.total += 1;
+        drop(state);
+
+        match Client::connect(&self.addr, self.opt) {
+            Ok(client) => Ok(self.pooled(client)),
+            Err(err) => {
+                self.release(None);
+                Err(err)
+            }
+        }
+    }
+
+    /// Calls `service_path.service_method` on a pooled connection, like
+    /// `Client::call`. The connection is closed instead of returned if the
+    /// call failed on the client side, e.g. because the server is gone.
+    pub fn call<T>(
+        &self,
+        service_path: &str,
+        service_method: &str,
+        is_oneway: bool,
+        metadata: &Metadata,
+        args: &dyn RpcxParam,
+    ) -> Option<Result<T>>
+    where
+        T: RpcxParam + Default,
+    {
+        let mut client = match self.get() {
+            Ok(client) => client,
+            Err(err) => return Some(Err(err)),
+        };
+        let res = client.call(service_path, service_method, is_oneway, metadata, args);
+        if let Some(Err(err)) = &res {
+            if err.kind() == ErrorKind::Client {
+                client.discard();
+            }
+        }
+        res
+    }
+
+    /// The number of idle connections.
+    pub fn idle_count(&self) -> usize {
+        self.state.lock().unwrap().idle.len()
+    }
+
+    /// The number of idle connections and those handed out.
+    pub fn total_count(&self) -> usize {
+        self.state.lock().unwrap().total
+    }
+
+    fn pooled(&self, client: Client) -> PooledClient<'_> {
+        PooledClient {
+            pool: self,
+            client: Some(client),
+            discard: false,
+        }
+    }
+
+    /// Takes a connection back, or forgets it if it is `None` or there are
+    /// enough idle connections already.
+    fn release(&self, client: Option<Client>) {
+        let mut state = self.state.lock().unwrap();
+        match client {
+            Some(client) if state.idle.len() < self.max_idle => state.idle.push(client),
+            _ => state.total -= 1,
+        }
+        drop(state);
+        self.returned.notify_one();
+    }
+}
+
+/// A connection handed out by `ClientPool::get`, returned to the pool when
+/// dropped.
+#[derive(Debug)]
+pub struct PooledClient<'a> {
+    pool: &'a ClientPool,
+    client: Option<Client>,
+    discard: bool,
+}
+
+impl PooledClient<'_> {
+    /// Closes the connection when it is dropped instead of returning it,
+    /// e.g. after an error left it in an unknown state.
+    pub fn discard(&mut self) {
+        self.discard = true;
+    }
+}
+
+impl Deref for PooledClient<'_> {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledClient<'_> {
+    fn deref_mut(&mut self) -> &mut Client {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledClient<'_> {
+    fn drop(&mut self) {
+        let client = self.client.take().filter(|_| !self.discard);
+        self.pool.release(client);
+    }
+}
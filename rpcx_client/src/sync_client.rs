@@ -0,0 +1,244 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, BufReader, Write},
+    net::{SocketAddr, TcpStream},
+};
+
+use super::client::{service_error, Opt};
+use rpcx_protocol::*;
+
+/// A client calling services over one connection from the calling thread,
+/// without the threads and channels of `Client`.
+///
+/// Requests are written as they are made and the calling thread blocks
+/// reading the connection until the reply with the request's seq arrives.
+/// Replies to other pending requests read on the way are kept until they
+/// are waited for, so requests may be pipelined with `send` and `wait`.
+///
+/// A connection found broken is replaced with a new one by the next call.
+#[derive(Debug)]
+pub struct SyncClient {
+    pub opt: Opt,
+    addr: String,
+    conn: Option<Conn>,
+    seq: u64,
+}
+
+/// The connection of a `SyncClient` and the calls made on it.
+#[derive(Debug)]
+struct Conn {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    /// The seqs of the requests whose replies haven't been read yet.
+    pending: HashSet<u64>,
+    /// Replies read while waiting for another one, keyed by seq.
+    replies: HashMap<u64, Message>,
+}
+
+impl SyncClient {
+    /// Connects to `addr` with the default options, panicking if it can't,
+    /// see `connect`.
+    pub fn new(addr: &str) -> SyncClient {
+        SyncClient::connect(addr, Opt::default()).unwrap()
+    }
+
+    /// Connects to `addr`.
+    pub fn connect(addr: &str, opt: Opt) -> Result<SyncClient> {
+        let mut c = SyncClient {
+            opt,
+            addr: String::from(addr),
+            conn: None,
+            seq: 0,
+        };
+        c.reconnect()?;
+        Ok(c)
+    }
+
+    /// Replaces the connection with a new one. Requests still waiting for
+    /// their replies fail.
+    pub fn reconnect(&mut self) -> Result<()> {
+        self.conn = None;
+        let stream = dial(&self.addr, &self.opt)?;
+        self.conn = Some(Conn {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            pending: HashSet::new(),
+            replies: HashMap::new(),
+        });
+        Ok(())
+    }
+
+    /// Calls `service_path.service_method` and blocks until its reply
+    /// arrives.
+    ///
+    /// Errors reported by the service through `SERVICE_ERROR` are returned as
+    /// `Err` of `ErrorKind::RemoteService`. If the connection fails, the
+    /// error is returned and the next call connects again; the request may
+    /// have been handled, so it isn't sent again.
+    pub fn call<T>(
+        &mut self,
+        service_path: &str,
+        service_method: &str,
+        metadata: &Metadata,
+        args: &dyn RpcxParam,
+    ) -> Result<T>
+    where
+        T: RpcxParam + Default,
+    {
+        let seq = self.send(service_path, service_method, metadata, args)?;
+        self.wait(seq)
+    }
+
+    /// Writes a request for `service_path.service_method` without waiting
+    /// for its reply and returns its seq, to be passed to `wait`.
+    ///
+    /// A connection closed by the server while idle, e.g. after its idle
+    /// timeout, is replaced before writing.
+    pub fn send(
+        &mut self,
+        service_path: &str,
+        service_method: &str,
+        metadata: &Metadata,
+        args: &dyn RpcxParam,
+    ) -> Result<u64> {
+        let seq = self.seq;
+        self.seq += 1;
+        let data = self.encode_request(seq, service_path, service_method, metadata, args)?;
+
+        let broken = match &self.conn {
+            Some(conn) => conn.pending.is_empty() && conn.is_closed(),
+            None => true,
+        };
+        if broken {
+            self.reconnect()?;
+        }
+
+        let conn = self.conn.as_mut().unwrap();
+        if let Err(err) = conn.writer.write_all(&data) {
+            self.conn = None;
+            return Err(err.into());
+        }
+        conn.pending.insert(seq);
+        Ok(seq)
+    }
+
+    /// Blocks until the reply of the request `seq` returned by `send`
+    /// arrives and decodes it.
+    pub fn wait<T>(&mut self, seq: u64) -> Result<T>
+    where
+        T: RpcxParam + Default,
+    {
+        let msg = match self.read_reply(seq) {
+            Ok(msg) => msg,
+            Err(err) => {
+                if err.kind() != ErrorKind::Client {
+                    self.conn = None;
+                }
+                return Err(err);
+            }
+        };
+
+        if let Some(err) = service_error(&msg) {
+            return Err(Error::new(ErrorKind::RemoteService, err));
+        }
+        let st = msg.get_serialize_type().unwrap_or(self.opt.serialize_type);
+        let mut reply: T = Default::default();
+        reply.from_slice(st, &msg.payload)?;
+        Ok(reply)
+    }
+
+    fn read_reply(&mut self, seq: u64) -> Result<Message> {
+        let conn = match &mut self.conn {
+            Some(conn) => conn,
+            None => return Err(Error::new(ErrorKind::Client, "connection was closed")),
+        };
+        if let Some(msg) = conn.replies.remove(&seq) {
+            return Ok(msg);
+        }
+        if !conn.pending.contains(&seq) {
+            let err = format!("no request is waiting for a reply with seq {}", seq);
+            return Err(Error::new(ErrorKind::Client, err));
+        }
+
+        loop {
+            let mut msg = Message::new();
+            msg.decode(&mut conn.reader)?;
+            let reply_seq = msg.get_seq();
+            // replies of requests nobody waits for are dropped
+            if !conn.pending.remove(&reply_seq) {
+                continue;
+            }
+            if reply_seq == seq {
+                return Ok(msg);
+            }
+            conn.replies.insert(reply_seq, msg);
+        }
+    }
+
+    fn encode_request(
+        &self,
+        seq: u64,
+        service_path: &str,
+        service_method: &str,
+        metadata: &Metadata,
+        args: &dyn RpcxParam,
+    ) -> Result<Vec<u8>> {
+        let st = self.opt.serialize_type;
+        let mut req = Message::new();
+        req.set_version(0);
+        req.set_message_type(MessageType::Request);
+        req.set_serialize_type(st);
+        req.set_compress_type(self.opt.compress_type);
+        req.set_seq(seq);
+        req.service_path = service_path.to_string();
+        req.service_method = service_method.to_string();
+        req.metadata.replace(metadata.clone());
+        req.payload = args.into_bytes(st)?;
+        Ok(req.encode())
+    }
+}
+
+impl Conn {
+    /// Whether the server closed the connection, checked without blocking.
+    /// Unread data means it is still open.
+    fn is_closed(&self) -> bool {
+        if !self.reader.buffer().is_empty() {
+            return false;
+        }
+        let stream = self.reader.get_ref();
+        if stream.set_nonblocking(true).is_err() {
+            return true;
+        }
+        let closed = match stream.peek(&mut [0u8]) {
+            Ok(n) => n == 0,
+            Err(err) => err.kind() != io::ErrorKind::WouldBlock,
+        };
+        stream.set_nonblocking(false).is_err() || closed
+    }
+}
+
+/// Connects to `addr` and applies the socket options of `opt`.
+fn dial(addr: &str, opt: &Opt) -> Result<TcpStream> {
+    let stream = if opt.connect_timeout.as_millis() == 0 {
+        TcpStream::connect(addr)?
+    } else {
+        let socket_addr: SocketAddr = addr
+            .parse()
+            .map_err(|err| Error::new(ErrorKind::Network, err))?;
+        TcpStream::connect_timeout(&socket_addr, opt.connect_timeout)?
+    };
+
+    if opt.read_timeout.as_millis() > 0 {
+        stream.set_read_timeout(Some(opt.read_timeout))?;
+    }
+    if opt.write_timeout.as_millis() > 0 {
+        stream.set_write_timeout(Some(opt.write_timeout))?;
+    }
+    if let Some(nodelay) = opt.nodelay {
+        stream.set_nodelay(nodelay)?;
+    }
+    if let Some(ttl) = opt.ttl {
+        stream.set_ttl(ttl)?;
+    }
+    Ok(stream)
+}
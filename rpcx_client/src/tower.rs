@@ -0,0 +1,107 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use bytes::BytesMut;
+use rpcx_protocol::*;
+use tower_service::Service;
+
+use super::client::{call_result, Client};
+
+/// A call made through `ClientService`, with its argument already encoded.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub service_path: String,
+    pub service_method: String,
+    pub metadata: Metadata,
+    /// The encoded argument, sent as it is.
+    pub payload: Vec<u8>,
+    /// How `payload` is encoded, sent in the header so the service decodes
+    /// it and encodes its reply the same way.
+    pub serialize_type: SerializeType,
+}
+
+impl Request {
+    /// A request for `service_path.service_method` without metadata.
+    pub fn new(
+        service_path: &str,
+        service_method: &str,
+        payload: Vec<u8>,
+        serialize_type: SerializeType,
+    ) -> Self {
+        Request {
+            service_path: service_path.to_owned(),
+            service_method: service_method.to_owned(),
+            metadata: Metadata::new(),
+            payload,
+            serialize_type,
+        }
+    }
+}
+
+/// The reply to a `Request`, still encoded.
+#[derive(Debug, Clone)]
+pub struct Reply {
+    pub payload: Vec<u8>,
+    /// The serialize type of the request, which the service encodes its
+    /// reply with unless it says otherwise.
+    pub serialize_type: SerializeType,
+}
+
+/// A `tower::Service` making calls with a `Client`, so tower middleware,
+/// e.g. timeouts, retries or load balancing, can be stacked on it.
+///
+/// Clones share the client and its connection. Calls are sent as they are
+/// made and don't wait for each other, so the service is always ready.
+/// Errors reported by the service through `SERVICE_ERROR` fail the call
+/// with `ErrorKind::RemoteService`, connection failures with
+/// `ErrorKind::Client`.
+#[derive(Debug, Clone)]
+pub struct ClientService {
+    client: Arc<Client>,
+}
+
+impl ClientService {
+    pub fn new(client: Client) -> Self {
+        ClientService {
+            client: Arc::new(client),
+        }
+    }
+}
+
+impl Service<Request> for ClientService {
+    type Response = Reply;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Reply>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let st = req.serialize_type;
+        let args = BytesMut::from(&req.payload[..]);
+        let f = self.client.send_with(
+            &req.service_path,
+            &req.service_method,
+            false,
+            false,
+            &req.metadata,
+            &args,
+            st,
+            self.client.opt.compress_type,
+        );
+        Box::pin(async move {
+            match f.await {
+                Some(call) => Ok(Reply {
+                    payload: call_result(&call)?,
+                    serialize_type: st,
+                }),
+                None => Err(Error::from("reply is empty")),
+            }
+        })
+    }
+}
@@ -5,7 +5,8 @@ use std::collections::HashMap;
 use super::selector::ClientSelector;
 
 use super::{
-    client::{Client, Opt},
+    breaker::{BreakerConfig, BreakerState, CircuitBreaker},
+    client::{failed_call, Client, Opt},
     RpcxClient,
 };
 
@@ -14,6 +15,7 @@ use std::{
     boxed::Box,
     cell::RefCell,
     sync::{Arc, Mutex, RwLock, RwLockWriteGuard},
+    time::Duration,
 };
 use strum_macros::{Display, EnumIter, EnumString};
 
@@ -48,12 +50,19 @@ pub enum SelectMode {
     SelectByUser = 1000,
 }
 
+/// How long an endpoint failing a call with a connection error is left out
+/// of the selection of `XClient` by default, see `XClient::set_breaker`.
+pub const DOWN_TIME: Duration = Duration::from_secs(10);
+
 pub struct XClient<S: ClientSelector> {
     pub opt: Opt,
     service_path: String,
     fail_mode: FailMode,
     clients: Arc<RwLock<HashMap<String, RefCell<Client>>>>,
     selector: Box<S>,
+    breaker_config: BreakerConfig,
+    /// The circuit breaker of every endpoint that was called.
+    breakers: HashMap<String, CircuitBreaker>,
 }
 
 unsafe impl<S: ClientSelector> Send for XClient<S> {}
@@ -67,9 +76,100 @@ impl<S: ClientSelector> XClient<S> {
             selector: s,
             clients: Arc::new(RwLock::new(HashMap::new())),
             opt,
+            breaker_config: BreakerConfig {
+                failure_threshold: 1,
+                cooldown: DOWN_TIME,
+            },
+            breakers: HashMap::new(),
+        }
+    }
+
+    /// Sets when the circuit breaker of an endpoint opens, leaving it out
+    /// of the selection, and how long it stays open. By default, one call
+    /// failing with a connection error opens it for `DOWN_TIME`, since
+    /// calls can fail over to other endpoints.
+    pub fn set_breaker(&mut self, config: BreakerConfig) {
+        self.breaker_config = config;
+        self.breakers.clear();
+    }
+
+    /// The state of the circuit breaker of the endpoint `k`.
+    pub fn breaker_state(&self, k: &str) -> BreakerState {
+        self.breakers
+            .get(k)
+            .map_or(BreakerState::Closed, CircuitBreaker::state)
+    }
+
+    /// Picks the endpoint of a call with the selector, passing over the
+    /// endpoints whose circuit breaker is open as long as it picks others.
+    /// If it picks none but those, the call fails fast with an
+    /// `ErrorKind::Network` error.
+    fn select(&mut self, service_method: &str, args: &dyn RpcxParam) -> Result<String> {
+        let open = self
+            .breakers
+            .values()
+            .filter(|b| b.state() != BreakerState::Closed)
+            .count();
+
+        // enough picks to get past every open endpoint with a round robin
+        let mut k = String::new();
+        for _ in 0..=open {
+            k = self
+                .selector
+                .select(&self.service_path, service_method, args);
+            if self.breakers.get(&k).is_none_or(CircuitBreaker::allow) {
+                return Ok(k);
+            }
+        }
+        Err(Error::new(
+            ErrorKind::Network,
+            format!("circuit breaker of {} is open", k),
+        ))
+    }
+
+    /// Reports a call to `k` failing with a connection error to its
+    /// circuit breaker and drops its client, so it is connected again once
+    /// it is selected.
+    fn on_failure(&mut self, k: &str) {
+        let config = self.breaker_config;
+        self.breakers
+            .entry(k.to_owned())
+            .or_insert_with(|| CircuitBreaker::new(config))
+            .on_failure();
+        self.clients.write().unwrap().remove(k);
+    }
+
+    fn on_success(&mut self, k: &str) {
+        if let Some(breaker) = self.breakers.get(k) {
+            breaker.on_success();
         }
     }
 
+    /// Calls `service_method` on the endpoint `k`, connecting to it first
+    /// if there is no client for it. A failed connect is an
+    /// `ErrorKind::Client` error, like a failed connection.
+    fn call_endpoint<T>(
+        &self,
+        k: &str,
+        service_method: &str,
+        is_oneway: bool,
+        metadata: &Metadata,
+        args: &dyn RpcxParam,
+    ) -> Option<Result<T>>
+    where
+        T: RpcxParam + Default,
+    {
+        let mut clients_guard = self.clients.write().unwrap();
+        let client = match self.get_cached_client(&mut clients_guard, k.to_owned()) {
+            Ok(client) => client,
+            Err(err) => return Some(Err(Error::new(ErrorKind::Client, err))),
+        };
+        let service_path = self.service_path.as_str();
+        client
+            .get_mut()
+            .call::<T>(service_path, service_method, is_oneway, metadata, args)
+    }
+
     fn get_cached_client<'a>(
         &'a self,
         clients_guard: &'a mut RwLockWriteGuard<HashMap<String, RefCell<Client>>>,
@@ -85,14 +185,8 @@ impl<S: ClientSelector> XClient<S> {
                     if items.len() == 1 {
                         items.insert(0, "tcp");
                     }
-                    let mut created_client = Client::new(&items[1],Opt::default());
-                    created_client.opt = self.opt;
-                    match created_client.start() {
-                        Ok(_) => {
-                            clients_guard.insert(k.clone(), RefCell::new(created_client));
-                        }
-                        Err(err) => return Err(err),
-                    }
+                    let created_client = Client::connect(items[1], self.opt)?;
+                    clients_guard.insert(k.clone(), RefCell::new(created_client));
                 }
             }
         }
@@ -106,6 +200,13 @@ impl<S: ClientSelector> XClient<S> {
 }
 
 impl<S: ClientSelector> RpcxClient for XClient<S> {
+    /// Calls `service_method` on the endpoint the selector picks.
+    ///
+    /// An endpoint failing the call with a connection error counts towards
+    /// opening its circuit breaker, see `set_breaker`, and the call is
+    /// retried as the fail mode says: `Failover` on other endpoints,
+    /// `Failtry` on a new connection to the same one, whatever its breaker
+    /// says. Oneway calls count as successes, having no reply to tell.
     fn call<T>(
         &mut self,
         service_method: &str,
@@ -116,10 +217,10 @@ impl<S: ClientSelector> RpcxClient for XClient<S> {
     where
         T: RpcxParam + Default,
     {
-        let service_path = self.service_path.as_str();
-        // get a key from selector
-        let selector = &mut (self.selector);
-        let k = selector.select(service_path, service_method, args);
+        let k = match self.select(service_method, args) {
+            Ok(k) => k,
+            Err(err) => return Some(Err(err)),
+        };
         if k.is_empty() {
             return Some(Err(Error::new(
                 ErrorKind::Client,
@@ -127,84 +228,45 @@ impl<S: ClientSelector> RpcxClient for XClient<S> {
             )));
         }
 
-        let mut clients_guard = self.clients.write().unwrap();
-        let client = self.get_cached_client(&mut clients_guard, k.clone());
-        if let Err(err) = client {
-            return Some(Err(Error::new(ErrorKind::Client, err)));
-        }
-        // invoke this client
-        let mut selected_client = client.unwrap().borrow_mut();
-        let opt_rt =
-            (*selected_client).call::<T>(service_path, service_method, is_oneway, metadata, args);
-
-        if is_oneway {
-            return opt_rt;
-        }
-
-        let rt = opt_rt.unwrap();
-
-        match rt {
-            Err(rt_err) => {
-                if rt_err.kind() == ErrorKind::Client {
-                    match self.fail_mode {
-                        FailMode::Failover => {
-                            let mut retry = self.opt.retry;
-                            while retry > 0 {
-                                retry -= 1;
-
-                                // re-select
-                                let mut clients_guard = self.clients.write().unwrap();
-                                let client = self.get_cached_client(&mut clients_guard, k.clone());
-                                if let Err(err) = client {
-                                    return Some(Err(err));
-                                }
-                                let mut selected_client = client.unwrap().borrow_mut();
+        let rt = match self.call_endpoint::<T>(&k, service_method, is_oneway, metadata, args) {
+            Some(rt) => rt,
+            None => {
+                self.on_success(&k);
+                return None;
+            }
+        };
+        let rt_err = match rt {
+            Err(rt_err) if rt_err.kind() == ErrorKind::Client => rt_err,
+            rt => {
+                self.on_success(&k);
+                return Some(rt);
+            }
+        };
+        self.on_failure(&k);
 
-                                let opt_rt = (*selected_client).call::<T>(
-                                    service_path,
-                                    service_method,
-                                    is_oneway,
-                                    metadata,
-                                    args,
-                                );
-                                let rt = opt_rt.unwrap();
-                                if rt.is_ok() {
-                                    return Some(rt);
-                                }
-                                if rt.unwrap_err().kind() == ErrorKind::Client {
-                                    continue;
-                                }
-                            }
-                        }
-                        FailMode::Failfast => return Some(Err(rt_err)),
-                        FailMode::Failtry => {
-                            let mut retry = self.opt.retry;
-                            while retry > 0 {
-                                retry -= 1;
-                                let opt_rt = (*selected_client).call::<T>(
-                                    service_path,
-                                    service_method,
-                                    is_oneway,
-                                    metadata,
-                                    args,
-                                );
-                                let rt = opt_rt.unwrap();
-                                if rt.is_ok() {
-                                    return Some(rt);
-                                }
-                                if rt.unwrap_err().kind() == ErrorKind::Client {
-                                    continue;
-                                }
-                            }
-                        }
-                        FailMode::Failbackup => {}
-                    }
+        for _ in 0..self.opt.retry {
+            let k = match self.fail_mode {
+                // re-select, passing over the endpoints that failed
+                FailMode::Failover => match self.select(service_method, args) {
+                    Ok(k) => k,
+                    Err(_) => break,
+                },
+                FailMode::Failtry => k.clone(),
+                FailMode::Failfast | FailMode::Failbackup => break,
+            };
+            if k.is_empty() {
+                break;
+            }
+            match self.call_endpoint::<T>(&k, service_method, is_oneway, metadata, args)? {
+                Err(err) if err.kind() == ErrorKind::Client => self.on_failure(&k),
+                rt => {
+                    self.on_success(&k);
+                    return Some(rt);
                 }
-
-                Some(Err(rt_err))
             }
-            Ok(r) => Some(Ok(r)),
         }
+
+        Some(Err(rt_err))
     }
 
     fn send<T>(
@@ -217,9 +279,12 @@ impl<S: ClientSelector> RpcxClient for XClient<S> {
     where
         T: RpcxParam + Default + Sync + Send + 'static,
     {
-        let service_path = self.service_path.as_str();
         // get a key from selector
-        let k = self.selector.select(service_path, service_method, args);
+        let k = match self.select(service_method, args) {
+            Ok(k) => k,
+            Err(err) => return failed_call(0, &err),
+        };
+        let service_path = self.service_path.as_str();
         if k.is_empty() {
             let callback = Call::new(0);
             let arc_call = Arc::new(Mutex::new(RefCell::from(callback)));
@@ -4,38 +4,93 @@ extern crate proc_macro;
 
 use proc_macro::TokenStream;
 use quote::quote;
-use syn::{parse_macro_input, DeriveInput};
+use syn::{
+    parse_macro_input, DeriveInput, FnArg, ImplItem, ItemImpl, Meta, NestedMeta, Visibility,
+};
 
-#[proc_macro_derive(RpcxParam)]
+/// Implements `RpcxParam` with serde for JSON and MessagePack.
+///
+/// `#[rpcx(protobuf)]` also encodes Protobuf with the type's
+/// `prost::Message` implementation, and `#[rpcx(protobuf_only)]` encodes
+/// nothing else, for prost types that don't implement serde's traits.
+#[proc_macro_derive(RpcxParam, attributes(rpcx))]
 pub fn rpcx_param(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
 
     let name = input.ident;
+    let mut protobuf = false;
+    let mut serde = true;
+    for flag in rpcx_flags(&input.attrs) {
+        match flag.as_str() {
+            "protobuf" => protobuf = true,
+            "protobuf_only" => {
+                protobuf = true;
+                serde = false;
+            }
+            _ => panic!("unknown rpcx attribute `{}`", flag),
+        }
+    }
+
+    let serde_into = if serde {
+        quote! {
+            SerializeType::JSON => serde_json::to_vec(self).map_err(|err| Error::from(err)),
+            SerializeType::MsgPack => {
+                rmps::to_vec_named(self).map_err(|err| Error::new(ErrorKind::Other, err.to_string()))
+            }
+        }
+    } else {
+        quote! {}
+    };
+    let serde_from = if serde {
+        quote! {
+            SerializeType::JSON => {
+                let param: Self = serde_json::from_slice(data)?;
+                *self = param;
+                Ok(())
+            }
+            SerializeType::MsgPack => {
+                let param: Self = rmps::from_slice(data)
+                    .map_err(|err| Error::new(ErrorKind::Other, err.to_string()))?;
+                *self = param;
+                Ok(())
+            }
+        }
+    } else {
+        quote! {}
+    };
+    let protobuf_into = if protobuf {
+        quote! {
+            SerializeType::Protobuf => Ok(::prost::Message::encode_to_vec(self)),
+        }
+    } else {
+        quote! {}
+    };
+    let protobuf_from = if protobuf {
+        quote! {
+            SerializeType::Protobuf => {
+                let param: Self = ::prost::Message::decode(data)
+                    .map_err(|err| Error::new(ErrorKind::Other, err.to_string()))?;
+                *self = param;
+                Ok(())
+            }
+        }
+    } else {
+        quote! {}
+    };
 
     let expanded = quote! {
         impl RpcxParam for #name {
             fn into_bytes(&self, st: SerializeType) -> Result<Vec<u8>> {
                 match st {
-                    SerializeType::JSON => serde_json::to_vec(self).map_err(|err| Error::from(err)),
-                    SerializeType::MsgPack => {
-                        rmps::to_vec(self).map_err(|err| Error::new(ErrorKind::Other, err.description()))
-                    }
+                    #serde_into
+                    #protobuf_into
                     _ => Err(Error::new(ErrorKind::Other, "unknown format")),
                 }
             }
             fn from_slice(&mut self, st: SerializeType, data: &[u8]) -> Result<()> {
                 match st {
-                    SerializeType::JSON => {
-                        let param: Self = serde_json::from_slice(data)?;
-                        *self = param;
-                        Ok(())
-                    }
-                    SerializeType::MsgPack => {
-                        let param: Self = rmps::from_slice(data)
-                            .map_err(|err| Error::new(ErrorKind::Other, err.description()))?;
-                        *self = param;
-                        Ok(())
-                    }
+                    #serde_from
+                    #protobuf_from
                     _ => Err(Error::new(ErrorKind::Other, "unknown format")),
                 }
             }
@@ -45,3 +100,110 @@ pub fn rpcx_param(input: TokenStream) -> TokenStream {
     // Hand the output tokens back to the compiler
     TokenStream::from(expanded)
 }
+
+/// Implements `RpcxService` for the type of an `impl` block, so
+/// `Server::register_service` registers its methods.
+///
+/// Every `pub fn` taking `&self` is a method. It takes one argument and
+/// returns a `Result` like the functions of `register_func!`: the argument
+/// and the reply implement `RpcxParam`, and an `Err` is sent back as a
+/// service error. Methods are named like Go's exported methods, `get_user`
+/// becoming `GetUser`.
+#[proc_macro_attribute]
+pub fn rpcx_service(_attr: TokenStream, item: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(item as ItemImpl);
+
+    let mut names = Vec::new();
+    let mut idents = Vec::new();
+    let mut arg_types = Vec::new();
+    for item in &input.items {
+        let method = match item {
+            ImplItem::Method(method) => method,
+            _ => continue,
+        };
+        let is_pub = matches!(method.vis, Visibility::Public(_));
+        let mut inputs = method.sig.decl.inputs.iter();
+        if !is_pub || !matches!(inputs.next(), Some(FnArg::SelfRef(_))) {
+            continue;
+        }
+        let ident = &method.sig.ident;
+        match (inputs.next(), inputs.next()) {
+            (Some(FnArg::Captured(arg)), None) => arg_types.push(arg.ty.clone()),
+            _ => panic!(
+                "rpcx_service method `{}` must take `&self` and one argument",
+                ident
+            ),
+        }
+        names.push(go_name(&ident.to_string()));
+        idents.push(ident.clone());
+    }
+
+    let self_ty = &input.self_ty;
+    let (impl_generics, _, where_clause) = input.generics.split_for_impl();
+    let listed = names.clone();
+    let expanded = quote! {
+        #input
+
+        impl #impl_generics RpcxService for #self_ty #where_clause {
+            fn method_names(&self) -> Vec<&'static str> {
+                vec![#(#listed),*]
+            }
+
+            fn call(&self, method: &str, data: &[u8], st: SerializeType) -> Result<Vec<u8>> {
+                match method {
+                    #(
+                        #names => {
+                            let mut args: #arg_types = Default::default();
+                            args.from_slice(st, data)?;
+                            match self.#idents(args) {
+                                Ok(reply) => reply.into_bytes(st),
+                                Err(err) => Err(Error::new(ErrorKind::Server, err.to_string())),
+                            }
+                        }
+                    )*
+                    _ => Err(Error::new(
+                        ErrorKind::ServiceNotFound,
+                        format!("method {} not found", method),
+                    )),
+                }
+            }
+        }
+    };
+
+    TokenStream::from(expanded)
+}
+
+/// Turns a snake case name into the camel case of Go's exported names.
+fn go_name(name: &str) -> String {
+    name.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+/// Collects the words of every `#[rpcx(...)]` attribute.
+fn rpcx_flags(attrs: &[syn::Attribute]) -> Vec<String> {
+    let mut flags = Vec::new();
+    for attr in attrs {
+        if !attr.path.is_ident("rpcx") {
+            continue;
+        }
+        match attr.parse_meta() {
+            Ok(Meta::List(list)) => {
+                for nested in list.nested {
+                    match nested {
+                        NestedMeta::Meta(Meta::Word(word)) => flags.push(word.to_string()),
+                        _ => panic!("expected `#[rpcx(flag, ...)]`"),
+                    }
+                }
+            }
+            _ => panic!("expected `#[rpcx(flag, ...)]`"),
+        }
+    }
+    flags
+}
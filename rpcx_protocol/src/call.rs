@@ -9,7 +9,7 @@ use std::{
     task::{Context, Poll, Waker},
 };
 
-use crate::SerializeType;
+use crate::{ErrorKind, SerializeType};
 
 use bytes::BytesMut;
 
@@ -41,6 +41,9 @@ pub struct Status {
 pub struct Call {
     pub seq: u64,
     pub is_client_error: bool,
+    /// The kind of a client error: `ErrorKind::Client` if the connection
+    /// failed, else why the request couldn't be sent.
+    pub error_kind: ErrorKind,
     pub state: Arc<Mutex<Status>>,
     pub error: String,
     pub reply_data: Vec<u8>,
@@ -51,6 +54,7 @@ impl Call {
         Call {
             seq,
             is_client_error: true,
+            error_kind: ErrorKind::Client,
             state: Arc::new(Mutex::new(Status {
                 ready: false,
                 task: None,
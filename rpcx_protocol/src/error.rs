@@ -32,6 +32,22 @@ pub enum ErrorKind {
     Network,
     Server,
     Serialization,
+    /// No service is registered under the requested path and method.
+    ServiceNotFound,
+    /// The service panicked while handling the request.
+    HandlerPanic,
+    /// A message is larger than the configured limit.
+    MessageTooLarge,
+    /// A deadline or timeout passed before the call completed.
+    Timeout,
+    /// The request was rejected for lack of valid credentials.
+    Unauthorized,
+    /// The request was rejected because its service is called more often
+    /// than it allows, so it may be sent again later.
+    RateLimited,
+    /// The remote service failed the call, reporting why through
+    /// `SERVICE_ERROR`.
+    RemoteService,
     Other,
 }
 
@@ -44,6 +60,13 @@ impl ErrorKind {
             ErrorKind::Network => "network issue",
             ErrorKind::Server => "server error",
             ErrorKind::Serialization => "serialization failure",
+            ErrorKind::ServiceNotFound => "service not found",
+            ErrorKind::HandlerPanic => "service panicked",
+            ErrorKind::MessageTooLarge => "message too large",
+            ErrorKind::Timeout => "timed out",
+            ErrorKind::Unauthorized => "unauthorized",
+            ErrorKind::RateLimited => "rate limited",
+            ErrorKind::RemoteService => "remote service error",
             ErrorKind::Other => "other",
         }
     }
@@ -7,13 +7,25 @@ use strum_macros::{Display, EnumIter, EnumString};
 use std::{
     cell::RefCell,
     collections::hash_map::HashMap,
-    io::{Read, Write},
+    io::{self, Read, Write},
 };
 
-use crate::{Error, Result};
+use crate::{Error, ErrorKind, Result};
 
 const MAGIC_NUMBER: u8 = 0x08;
 pub const SERVICE_ERROR: &str = "__rpcx_error__";
+/// Metadata key of the time, in milliseconds since the unix epoch, after
+/// which the client no longer waits for the reply.
+pub const DEADLINE: &str = "deadline";
+/// Metadata key of the auth token, as set by Go clients with `SetAuth`.
+pub const AUTH_KEY: &str = "__AUTH";
+/// Metadata key marking the replies of a streamed call, which all carry the
+/// seq of the request. Every frame but the last one is marked `STREAM_MORE`;
+/// the last one is marked `STREAM_END`, has no payload and carries the error
+/// if the stream failed.
+pub const STREAM: &str = "__STREAM";
+pub const STREAM_MORE: &str = "more";
+pub const STREAM_END: &str = "end";
 
 #[derive(Debug, Copy, Clone, Display, PartialEq, EnumIter, EnumString, Primitive)]
 pub enum MessageType {
@@ -62,6 +74,11 @@ pub trait RpcxMessage {
     fn get_seq(&self) -> u64;
     fn set_seq(&mut self, seq: u64);
     fn decode<R: ?Sized>(&mut self, r: &mut R) -> Result<()>
+    where
+        R: Read;
+    /// Like `decode`, but fails without allocating if the frame is larger
+    /// than `max_size` bytes.
+    fn decode_with_limit<R: ?Sized>(&mut self, r: &mut R, max_size: usize) -> Result<()>
     where
         R: Read;
     fn encode(&self) -> Vec<u8>;
@@ -90,19 +107,158 @@ impl Message {
         msg
     }
 
+    /// Creates the response to this request. Fails if the header has an
+    /// unknown compress or serialize type.
     pub fn get_reply(&self) -> Result<Self> {
+        let compress_type = self
+            .get_compress_type()
+            .ok_or("unsupported compress type")?;
+        let serialize_type = self
+            .get_serialize_type()
+            .ok_or("unsupported serialize type")?;
+
         let mut reply = Message::new();
         reply.set_version(self.get_version());
-        reply.set_compress_type(self.get_compress_type().unwrap());
+        reply.set_compress_type(compress_type);
         reply.set_message_status_type(MessageStatusType::Normal);
         reply.set_message_type(MessageType::Response);
-        reply.set_serialize_type(self.get_serialize_type().unwrap());
+        reply.set_serialize_type(serialize_type);
         reply.set_seq(self.get_seq());
         reply.service_path = self.service_path.clone();
         reply.service_method = self.service_method.clone();
 
         Ok(reply)
     }
+
+    /// Like `decode_with_limit`, but stops before the payload and returns
+    /// its length, so the payload can be read from `r` as it comes with
+    /// `payload_reader`, or at once with `read_payload`. `max_size` limits
+    /// the frame without its payload.
+    ///
+    /// As with `decode_with_limit`, a `Protocol` error leaves `r` at the
+    /// start of the next frame; here the payload is skipped for it.
+    pub fn decode_head<R: Read + ?Sized>(&mut self, r: &mut R, max_size: usize) -> Result<usize> {
+        r.read_exact(&mut self.header)?;
+        let mut left = read_u32(r)? as usize;
+
+        // the path, the method and the metadata, each with its length
+        let mut head = Vec::new();
+        for _ in 0..3 {
+            let len = read_u32(r)? as usize;
+            let size = head.len() + 4 + len;
+            if size > max_size {
+                return Err(Error::new(
+                    ErrorKind::MessageTooLarge,
+                    format!("message size {} exceeds the limit of {}", size, max_size),
+                ));
+            }
+            if 4 + len > left {
+                return Err(Error::from("truncated message"));
+            }
+            head.extend_from_slice(&write_len(len as u32));
+            let start = head.len();
+            head.resize(start + len, 0);
+            r.read_exact(&mut head[start..])?;
+            left -= 4 + len;
+        }
+        if left < 4 {
+            return Err(Error::from("truncated message"));
+        }
+        let len = read_u32(r)? as usize;
+        left -= 4;
+
+        let res = if len == left {
+            self.decode_head_parts(&mut &head[..]).map(|_| ())
+        } else {
+            Err(Error::from("invalid payload length"))
+        };
+        if let Err(err) = res {
+            io::copy(&mut r.take(left as u64), &mut io::sink())?;
+            return Err(Error::new(ErrorKind::Protocol, err));
+        }
+        Ok(len)
+    }
+
+    /// Reads the payload of `len` bytes `decode_head` left on `r`. Like
+    /// `decode_head`, a `Protocol` error leaves `r` at the next frame.
+    pub fn read_payload<R: Read + ?Sized>(&mut self, r: &mut R, len: usize) -> Result<()> {
+        let mut buf = vec![0u8; len];
+        r.read_exact(&mut buf)?;
+        self.payload = self
+            .decompress(&buf)
+            .map_err(|err| Error::new(ErrorKind::Protocol, err))?;
+        Ok(())
+    }
+
+    /// Returns a reader of the payload `decode_head` left on `r`, which
+    /// should yield only its `len` bytes, e.g. `r.take(len)`. The payload is
+    /// decompressed as it is read.
+    pub fn payload_reader<'a>(&self, r: &'a mut dyn Read) -> Result<Box<dyn Read + 'a>> {
+        match self.get_compress_type() {
+            Some(CompressType::Gzip) => Ok(Box::new(GzDecoder::new(r))),
+            Some(CompressType::CompressNone) => Ok(Box::new(r)),
+            None => Err(Error::from("unsupported compress type")),
+        }
+    }
+
+    /// Decodes everything after the header and the length.
+    fn decode_frame(&mut self, mut buf: &[u8]) -> Result<()> {
+        self.decode_head_parts(&mut buf)?;
+
+        let len = read_len(&mut buf)?;
+        if len != buf.len() {
+            return Err(Error::from("invalid payload length"));
+        }
+        self.payload = self.decompress(buf)?;
+
+        Ok(())
+    }
+
+    /// Checks the types of the header and decodes the path, the method and
+    /// the metadata off `buf`.
+    fn decode_head_parts(&mut self, buf: &mut &[u8]) -> Result<()> {
+        self.get_compress_type()
+            .ok_or("unsupported compress type")?;
+        self.get_serialize_type()
+            .ok_or("unsupported serialize type")?;
+
+        self.service_path = read_str(buf)?;
+        self.service_method = read_str(buf)?;
+
+        let len = read_len(buf)?;
+        let mut metadata_bytes = take(buf, len)?;
+        let mut metadata = self.metadata.borrow_mut();
+        while !metadata_bytes.is_empty() {
+            let key = read_str(&mut metadata_bytes)?;
+            let value = if metadata_bytes.is_empty() {
+                String::new()
+            } else {
+                read_str(&mut metadata_bytes)?
+            };
+            metadata.insert(key, value);
+        }
+        drop(metadata);
+
+        Ok(())
+    }
+
+    /// Decompresses a payload as the header says.
+    fn decompress(&self, buf: &[u8]) -> Result<Vec<u8>> {
+        let mut vp = Vec::with_capacity(buf.len());
+        match self
+            .get_compress_type()
+            .ok_or("unsupported compress type")?
+        {
+            CompressType::Gzip => {
+                let mut deflater = GzDecoder::new(buf);
+                deflater.read_to_end(&mut vp)?;
+            }
+            CompressType::CompressNone => {
+                vp.extend_from_slice(buf);
+            }
+        }
+        Ok(vp)
+    }
 }
 
 impl RpcxMessage for Message {
@@ -169,69 +325,34 @@ impl RpcxMessage for Message {
     }
 
     fn decode<R: ?Sized>(&mut self, r: &mut R) -> Result<()>
+    where
+        R: Read,
+    {
+        self.decode_with_limit(r, usize::MAX)
+    }
+
+    fn decode_with_limit<R: ?Sized>(&mut self, r: &mut R, max_size: usize) -> Result<()>
     where
         R: Read,
     {
         r.read_exact(&mut self.header)?;
 
         let mut buf = [0u8; 4];
-        r.read(&mut buf[..]).map(|_| {})?;
-        let len = BigEndian::read_u32(&buf); //length of all expect header
-        let mut buf = vec![0u8; len as usize];
-        r.read(&mut buf[..]).map(|_| ())?;
-
-        let mut start = 0;
-        // read service_path
-        let len = read_len(&buf[start..(start + 4)]) as usize;
-        let service_path = read_str(&buf[(start + 4)..(start + 4 + len)])?;
-        self.service_path = service_path;
-        start = start + 4 + len;
-        // read service_method
-        let len = read_len(&buf[start..(start + 4)]) as usize;
-        let service_method = read_str(&buf[(start + 4)..(start + 4 + len)])?;
-        self.service_method = service_method;
-
-        start = start + 4 + len;
-        //metadata
-        let len = read_len(&buf[start..(start + 4)]) as usize;
-        let metadata_bytes = &buf[(start + 4)..(start + 4 + len)];
-        let mut meta_start = 0;
-        while meta_start < len {
-            let sl = read_len(&metadata_bytes[meta_start..(meta_start + 4)]) as usize;
-            let key = read_str(&metadata_bytes[(meta_start + 4)..(meta_start + 4 + sl)])?;
-            meta_start = meta_start + 4 + sl;
-            if meta_start < len {
-                let value_len = read_len(&metadata_bytes[meta_start..(meta_start + 4)]) as usize;
-                let value =
-                    read_str(&metadata_bytes[(meta_start + 4)..(meta_start + 4 + value_len)])?;
-                self.metadata.borrow_mut().insert(key, value);
-                meta_start = meta_start + 4 + value_len;
-            } else {
-                self.metadata.borrow_mut().insert(key, String::new());
-                break;
-            }
-        }
-        start = start + 4 + len;
-        // payload
-        let len = read_len(&buf[start..start + 4]) as usize;
-        let payload = &buf[start + 4..];
-        if len != payload.len() {
-            return Err(Error::from("invalid payload length"));
+        r.read_exact(&mut buf[..])?;
+        let len = BigEndian::read_u32(&buf) as usize; //length of all expect header
+        if len > max_size {
+            return Err(Error::new(
+                ErrorKind::MessageTooLarge,
+                format!("message size {} exceeds the limit of {}", len, max_size),
+            ));
         }
+        let mut buf = vec![0u8; len];
+        r.read_exact(&mut buf[..])?;
 
-        let mut vp = Vec::with_capacity(payload.len());
-        match self.get_compress_type().unwrap() {
-            CompressType::Gzip => {
-                let mut deflater = GzDecoder::new(payload);
-                deflater.read_to_end(&mut vp)?;
-            }
-            CompressType::CompressNone => {
-                vp.extend_from_slice(&payload);
-            }
-        }
-        self.payload = vp;
-
-        Ok(())
+        // the whole frame has been read, so an error in it leaves the stream
+        // at the start of the next one.
+        self.decode_frame(&buf)
+            .map_err(|err| Error::new(ErrorKind::Protocol, err))
     }
 
     fn encode(&self) -> Vec<u8> {
@@ -255,10 +376,12 @@ impl RpcxMessage for Message {
         buf.extend_from_slice(&len_bytes);
         buf.extend_from_slice(self.service_method.as_bytes());
 
-        // metadata
+        // metadata, sorted by key so a message is always encoded the same
         let mut metadata_bytes = Vec::<u8>::new();
-        let metadata = self.metadata.borrow_mut();
-        for meta in metadata.iter() {
+        let metadata = self.metadata.borrow();
+        let mut metadata: Vec<_> = metadata.iter().collect();
+        metadata.sort();
+        for meta in metadata {
             let key = meta.0;
             let len_bytes = write_len(key.len() as u32);
             metadata_bytes.extend_from_slice(&len_bytes);
@@ -319,8 +442,25 @@ impl RpcxMessage for Message {
     }
 }
 
-fn read_len(buf: &[u8]) -> u32 {
-    BigEndian::read_u32(&buf[..4])
+/// Splits the first `n` bytes off `buf`.
+fn take<'a>(buf: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+    if buf.len() < n {
+        return Err(Error::from("truncated message"));
+    }
+    let (head, rest) = buf.split_at(n);
+    *buf = rest;
+    Ok(head)
+}
+
+/// Reads a length off a stream.
+fn read_u32<R: Read + ?Sized>(r: &mut R) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(BigEndian::read_u32(&buf))
+}
+
+fn read_len(buf: &mut &[u8]) -> Result<usize> {
+    Ok(BigEndian::read_u32(take(buf, 4)?) as usize)
 }
 
 fn write_len(len: u32) -> [u8; 4] {
@@ -329,10 +469,11 @@ fn write_len(len: u32) -> [u8; 4] {
     buf
 }
 
-fn read_str(buf: &[u8]) -> Result<String> {
-    let s = std::str::from_utf8(&buf).unwrap();
-    let str: String = std::string::String::from(s);
-    Ok(str)
+/// Reads a string prefixed with its length.
+fn read_str(buf: &mut &[u8]) -> Result<String> {
+    let len = read_len(buf)?;
+    let s = std::str::from_utf8(take(buf, len)?).map_err(|_| "invalid utf-8 string")?;
+    Ok(s.to_owned())
 }
 
 fn u64_from_slice(b: &[u8]) -> u64 {
@@ -442,6 +583,178 @@ mod tests {
         );
     }
 
+    #[test]
+    fn encode_golden_request() {
+        let mut msg = Message::new();
+        msg.set_message_type(MessageType::Request);
+        msg.set_serialize_type(SerializeType::JSON);
+        msg.set_seq(7);
+        msg.service_path = "Arith".to_owned();
+        msg.service_method = "Mul".to_owned();
+        let mut metadata = msg.metadata.borrow_mut();
+        metadata.insert("__ID".to_owned(), "1".to_owned());
+        metadata.insert(AUTH_KEY.to_owned(), "tok".to_owned());
+        drop(metadata);
+        msg.payload = br#"{"A":2,"B":3}"#.to_vec();
+
+        // hand-built from the rpcx protocol spec, not captured from the Go
+        // encoder: the header, the length of the rest, then every part
+        // prefixed with its length, metadata sorted by key
+        let golden: Vec<u8> = [
+            &[8, 0, 0, 0x10, 0, 0, 0, 0, 0, 0, 0, 7][..],
+            &[0, 0, 0, 67],
+            &[0, 0, 0, 5],
+            b"Arith",
+            &[0, 0, 0, 3],
+            b"Mul",
+            &[0, 0, 0, 30],
+            &[0, 0, 0, 6],
+            b"__AUTH",
+            &[0, 0, 0, 3],
+            b"tok",
+            &[0, 0, 0, 4],
+            b"__ID",
+            &[0, 0, 0, 1],
+            b"1",
+            &[0, 0, 0, 13],
+            br#"{"A":2,"B":3}"#,
+        ]
+        .concat();
+        assert_eq!(msg.encode(), golden);
+    }
+
+    #[test]
+    fn encode_golden_reply() {
+        let mut request = Message::new();
+        request.set_serialize_type(SerializeType::JSON);
+        request.set_seq(7);
+        request.service_path = "Arith".to_owned();
+        request.service_method = "Mul".to_owned();
+        let mut msg = request.get_reply().unwrap();
+        msg.set_message_status_type(MessageStatusType::Error);
+        msg.metadata
+            .borrow_mut()
+            .insert(SERVICE_ERROR.to_owned(), "divide by zero".to_owned());
+
+        // hand-built like the request: a response, status error
+        let golden: Vec<u8> = [
+            &[8, 0, 0x81, 0x10, 0, 0, 0, 0, 0, 0, 0, 7][..],
+            &[0, 0, 0, 60],
+            &[0, 0, 0, 5],
+            b"Arith",
+            &[0, 0, 0, 3],
+            b"Mul",
+            &[0, 0, 0, 36],
+            &[0, 0, 0, 14],
+            b"__rpcx_error__",
+            &[0, 0, 0, 14],
+            b"divide by zero",
+            &[0, 0, 0, 0],
+        ]
+        .concat();
+        assert_eq!(msg.encode(), golden);
+
+        let mut decoded = Message::new();
+        decoded.decode(&mut &golden[..]).unwrap();
+        assert_eq!(decoded.get_message_type(), Some(MessageType::Response));
+        assert_eq!(decoded.get_error().unwrap(), "divide by zero");
+    }
+
+    #[test]
+    fn decode_with_limit() {
+        let msg_data: [u8; 114] = [
+            8, 0, 0, 16, 0, 0, 0, 0, 73, 150, 2, 210, 0, 0, 0, 98, 0, 0, 0, 5, 65, 114, 105, 116,
+            104, 0, 0, 0, 3, 65, 100, 100, 0, 0, 0, 48, 0, 0, 0, 4, 95, 95, 73, 68, 0, 0, 0, 36,
+            54, 98, 97, 55, 98, 56, 49, 48, 45, 57, 100, 97, 100, 45, 49, 49, 100, 49, 45, 56, 48,
+            98, 52, 45, 48, 48, 99, 48, 52, 102, 100, 52, 51, 48, 99, 57, 0, 0, 0, 26, 123, 10, 9,
+            9, 34, 65, 34, 58, 32, 49, 44, 10, 9, 9, 34, 66, 34, 58, 32, 50, 44, 10, 9, 125, 10, 9,
+        ];
+
+        let mut msg = Message::new();
+        let mut data = &msg_data[..] as &[u8];
+        let err = msg.decode_with_limit(&mut data, 97).unwrap_err();
+        assert_eq!(ErrorKind::MessageTooLarge, err.kind());
+
+        let mut msg = Message::new();
+        let mut data = &msg_data[..] as &[u8];
+        msg.decode_with_limit(&mut data, 98).unwrap();
+        assert_eq!("Arith", msg.service_path);
+    }
+
+    #[test]
+    fn decode_malformed_frame() {
+        let mut msg = Message::new();
+        msg.set_serialize_type(SerializeType::JSON);
+        msg.service_path = "Arith".to_owned();
+        msg.service_method = "Add".to_owned();
+        let frame = msg.encode();
+
+        // invalid utf-8 in the service path, and a metadata length pointing
+        // past the end of the frame
+        let mut bad_path = frame.clone();
+        bad_path[20] = 0xff;
+        let mut bad_metadata = frame.clone();
+        bad_metadata[35] = 0xff;
+
+        let data = [bad_path, bad_metadata, frame].concat();
+        let mut data = &data[..];
+        for _ in 0..2 {
+            let err = Message::new().decode(&mut data).unwrap_err();
+            assert_eq!(ErrorKind::Protocol, err.kind());
+        }
+
+        // the stream is still at the start of the next frame
+        let mut msg = Message::new();
+        msg.decode(&mut data).unwrap();
+        assert_eq!("Arith", msg.service_path);
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn decode_head() {
+        let mut msg = Message::new();
+        msg.set_serialize_type(SerializeType::JSON);
+        msg.set_compress_type(CompressType::Gzip);
+        msg.service_path = "File".to_owned();
+        msg.service_method = "Upload".to_owned();
+        msg.metadata
+            .borrow_mut()
+            .insert("name".to_owned(), "a.txt".to_owned());
+        msg.payload = b"0123456789".repeat(100);
+        let frame = msg.encode();
+        let mut bad_path = frame.clone();
+        bad_path[20] = 0xff;
+
+        let data = [frame.clone(), bad_path, frame].concat();
+        let mut data = &data[..];
+        let mut head = Message::new();
+        let len = head.decode_head(&mut data, 64).unwrap();
+        assert_eq!("Upload", head.service_method);
+        assert_eq!("a.txt", head.metadata.borrow()["name"]);
+        assert!(head.payload.is_empty());
+        let mut payload = Vec::new();
+        head.payload_reader(&mut (&mut data).take(len as u64))
+            .unwrap()
+            .read_to_end(&mut payload)
+            .unwrap();
+        assert_eq!(payload, msg.payload);
+
+        // the payload of a malformed frame is skipped
+        let err = Message::new().decode_head(&mut data, 64).unwrap_err();
+        assert_eq!(ErrorKind::Protocol, err.kind());
+
+        let mut head = Message::new();
+        let len = head.decode_head(&mut data, 64).unwrap();
+        head.read_payload(&mut data, len).unwrap();
+        assert_eq!(head.payload, msg.payload);
+        assert!(data.is_empty());
+
+        let err = Message::new()
+            .decode_head(&mut &msg.encode()[..], 16)
+            .unwrap_err();
+        assert_eq!(ErrorKind::MessageTooLarge, err.kind());
+    }
+
     #[test]
     fn encode() {
         let msg_data: [u8; 114] = [
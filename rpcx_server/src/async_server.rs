@@ -0,0 +1,699 @@
+use super::{
+    accept_delay,
+    dispatch::{self, decode_error_reply, heartbeat_reply, Begun},
+    read_lock, write_lock, Context, Dispatcher, Draining, InvokePlugin, InvokePlugins,
+    NotFoundHandler, RegisterPlugin, RpcxFn, RpcxHandler, ServiceRegistry, Services, SharedMetrics,
+    DEFAULT_MAX_MESSAGE_SIZE,
+};
+use rpcx_protocol::*;
+
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use log::{error, info, warn};
+use socket2::{SockRef, TcpKeepalive};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{tcp::OwnedWriteHalf, TcpListener, TcpStream},
+    sync::Mutex,
+};
+
+/// The reply an async handler eventually produces.
+pub type RpcxFuture = Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send>>;
+
+/// A handler that is awaited on the runtime instead of blocking a thread.
+pub type RpcxAsyncHandler = Box<dyn Fn(&[u8], SerializeType) -> RpcxFuture + Send + Sync>;
+
+pub type AsyncServices = Arc<RwLock<HashMap<(String, String), Arc<RpcxAsyncHandler>>>>;
+
+/// A server that accepts and reads connections on a tokio runtime.
+///
+/// Connections are served by async tasks instead of dedicated threads, so a
+/// single runtime thread can keep many idle connections open. Handlers
+/// registered with `register_async_fn` are awaited on the runtime, the
+/// others run on the blocking thread pool.
+///
+/// Services are registered through a `ServiceRegistry` like those of a
+/// `Server`, so names are checked and register plugins are told the same
+/// way, and the registry may be shared with one.
+pub struct AsyncServer {
+    pub addr: String,
+    registry: ServiceRegistry,
+    draining: Draining,
+    async_services: AsyncServices,
+    invoke_plugins: InvokePlugins,
+    metrics: Option<SharedMetrics>,
+    compression: CompressType,
+    echoed_metadata: Arc<Vec<String>>,
+    not_found: Option<NotFoundHandler>,
+    default_handler: Option<Arc<RpcxHandler>>,
+    max_message_size: usize,
+    nodelay: bool,
+    keepalive: Option<TcpKeepalive>,
+    handler_timeout: Option<Duration>,
+}
+
+impl AsyncServer {
+    pub fn new(s: String) -> Self {
+        AsyncServer::with_registry(s, ServiceRegistry::default())
+    }
+
+    /// Creates a server sharing `services` with another server, e.g. the
+    /// `services` of a threaded `Server`. Use `with_registry` to share its
+    /// register plugins as well.
+    pub fn with_services(s: String, services: Services) -> Self {
+        let registry = ServiceRegistry {
+            services,
+            ..ServiceRegistry::default()
+        };
+        AsyncServer::with_registry(s, registry)
+    }
+
+    /// Creates a server serving the services of `registry`, e.g. the one
+    /// of a threaded `Server`, so both serve what is registered with
+    /// either of them.
+    pub fn with_registry(s: String, registry: ServiceRegistry) -> Self {
+        AsyncServer {
+            addr: s,
+            registry,
+            draining: Arc::new(RwLock::new(HashSet::new())),
+            async_services: Arc::new(RwLock::new(HashMap::new())),
+            invoke_plugins: Arc::new(RwLock::new(Vec::new())),
+            metrics: None,
+            compression: CompressType::CompressNone,
+            echoed_metadata: Arc::new(Vec::new()),
+            not_found: None,
+            default_handler: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            nodelay: true,
+            keepalive: None,
+            handler_timeout: None,
+        }
+    }
+
+    pub fn set_metrics(&mut self, metrics: SharedMetrics) {
+        self.metrics = Some(metrics);
+    }
+
+    /// See `Server::set_draining`.
+    pub fn set_draining(&self, service_path: &str, service_method: &str, draining: bool) {
+        dispatch::set_draining(&self.draining, service_path, service_method, draining);
+    }
+
+    /// See `Server::set_nodelay`.
+    pub fn set_nodelay(&mut self, nodelay: bool) {
+        self.nodelay = nodelay;
+    }
+
+    /// See `Server::set_keepalive`.
+    pub fn set_keepalive(&mut self, keepalive: Option<TcpKeepalive>) {
+        self.keepalive = keepalive;
+    }
+
+    /// See `Server::set_compression`.
+    pub fn set_compression(&mut self, compression: CompressType) {
+        self.compression = compression;
+    }
+
+    /// See `Server::set_echoed_metadata`.
+    pub fn set_echoed_metadata(&mut self, keys: &[&str]) {
+        self.echoed_metadata = Arc::new(keys.iter().map(|k| k.to_string()).collect());
+    }
+
+    /// See `Server::set_not_found_handler`.
+    pub fn set_not_found_handler<F>(&mut self, f: F)
+    where
+        F: Fn(&Message) -> Message + Send + Sync + 'static,
+    {
+        self.not_found = Some(Arc::new(f));
+    }
+
+    /// See `Server::set_default_handler`.
+    pub fn set_default_handler<F>(&mut self, f: F)
+    where
+        F: Fn(&Context, &[u8], SerializeType) -> Result<Vec<u8>> + Send + Sync + 'static,
+    {
+        let handler: RpcxHandler = Box::new(f);
+        self.default_handler = Some(Arc::new(handler));
+    }
+
+    pub fn add_invoke_plugin(&mut self, p: Box<dyn InvokePlugin + Send + Sync>) {
+        let mut plugins = write_lock(&self.invoke_plugins);
+        plugins.push(p);
+    }
+
+    /// See `Server::add_register_plugin`.
+    pub fn add_register_plugin(&mut self, p: Box<dyn RegisterPlugin + Send + Sync>) {
+        let mut plugins = write_lock(&self.registry.register_plugins);
+        plugins.push(p);
+    }
+
+    /// Returns a handle registering and unregistering services, see
+    /// `Server::registry`.
+    pub fn registry(&self) -> ServiceRegistry {
+        self.registry.clone()
+    }
+
+    /// Sets the largest request, in bytes, a client may send.
+    pub fn set_max_message_size(&mut self, max_message_size: usize) {
+        self.max_message_size = max_message_size;
+    }
+
+    /// Answers calls of async handlers that take longer than
+    /// `handler_timeout` with a timeout error. The handler's future is
+    /// dropped, cancelling the work it still had to do.
+    ///
+    /// Blocking handlers can't be cancelled and are not limited.
+    pub fn set_handler_timeout(&mut self, handler_timeout: Option<Duration>) {
+        self.handler_timeout = handler_timeout;
+    }
+
+    /// See `Server::register_fn`.
+    pub fn register_fn(
+        &self,
+        service_path: String,
+        service_method: String,
+        meta: String,
+        f: RpcxFn,
+    ) -> Result<()> {
+        self.registry
+            .register_fn(service_path, service_method, meta, f)
+    }
+
+    /// See `Server::register_handler`.
+    pub fn register_handler(
+        &self,
+        service_path: String,
+        service_method: String,
+        meta: String,
+        handler: RpcxHandler,
+    ) -> Result<()> {
+        self.registry
+            .register_handler(service_path, service_method, meta, handler)
+    }
+
+    /// Registers a handler whose replies are computed by a future, e.g. one
+    /// doing async I/O. It takes precedence over a blocking handler
+    /// registered under the same name.
+    ///
+    /// It is registered like `register_handler`, so the register plugins
+    /// announce it. A threaded `Server` sharing the registry answers its
+    /// calls with an error.
+    pub fn register_async_fn<F>(
+        &self,
+        service_path: String,
+        service_method: String,
+        meta: String,
+        f: F,
+    ) -> Result<()>
+    where
+        F: Fn(&[u8], SerializeType) -> RpcxFuture + Send + Sync + 'static,
+    {
+        let key = (service_path.clone(), service_method.clone());
+        let handler: RpcxAsyncHandler = Box::new(f);
+        // in place before the blocking placeholder, so no call is answered
+        // by the placeholder
+        let replaced = write_lock(&self.async_services).insert(key.clone(), Arc::new(handler));
+
+        let name = format!("{}.{}", service_path, service_method);
+        let unsupported: RpcxHandler = Box::new(move |_, _, _| {
+            Err(Error::from(format!(
+                "{} is only served by an AsyncServer",
+                name
+            )))
+        });
+        let res = self
+            .registry
+            .register_handler(service_path, service_method, meta, unsupported);
+        if res.is_err() {
+            let mut map = write_lock(&self.async_services);
+            match replaced {
+                Some(handler) => map.insert(key, handler),
+                None => map.remove(&key),
+            };
+        }
+        res
+    }
+
+    /// Removes `service_path.service_method`, async or not, see
+    /// `Server::unregister_fn`. Returns the blocking handler, if it had one.
+    pub fn unregister_fn(
+        &self,
+        service_path: String,
+        service_method: String,
+    ) -> Option<Arc<RpcxHandler>> {
+        let key = (service_path, service_method);
+        write_lock(&self.async_services).remove(&key);
+        self.registry.unregister_fn(key.0, key.1)
+    }
+
+    pub async fn start(&self) -> Result<()> {
+        let addr = self
+            .addr
+            .parse::<SocketAddr>()
+            .map_err(|err| Error::new(ErrorKind::Other, err))?;
+
+        let listener = TcpListener::bind(&addr).await?;
+        info!("Listening on: {}", addr);
+        self.start_with_listener(listener).await
+    }
+
+    /// Runs the accept loop; accept errors are handled like
+    /// `Server::start_with_listener` does.
+    pub async fn start_with_listener(&self, listener: TcpListener) -> Result<()> {
+        let mut delay = Duration::ZERO;
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    delay = Duration::ZERO;
+                    if let Err(err) = stream.set_nodelay(self.nodelay) {
+                        warn!("failed to set nodelay: {}", err);
+                    }
+                    if let Some(keepalive) = &self.keepalive {
+                        if let Err(err) = SockRef::from(&stream).set_tcp_keepalive(keepalive) {
+                            warn!("failed to set keepalive: {}", err);
+                        }
+                    }
+                    let dispatcher = Dispatcher {
+                        services: self.registry.services.clone(),
+                        streams: self.registry.streams.clone(),
+                        uploads: self.registry.uploads.clone(),
+                        messages: self.registry.messages.clone(),
+                        draining: self.draining.clone(),
+                        invoke_plugins: self.invoke_plugins.clone(),
+                        metrics: self.metrics.clone(),
+                        compression: self.compression,
+                        echoed_metadata: self.echoed_metadata.clone(),
+                        not_found: self.not_found.clone(),
+                        default_handler: self.default_handler.clone(),
+                    };
+                    let async_services = self.async_services.clone();
+                    let max_message_size = self.max_message_size;
+                    let handler_timeout = self.handler_timeout;
+                    tokio::spawn(async move {
+                        AsyncServer::process(
+                            dispatcher,
+                            async_services,
+                            stream,
+                            max_message_size,
+                            handler_timeout,
+                        )
+                        .await;
+                    });
+                }
+                Err(e) => match accept_delay(&e, delay) {
+                    Some(next) => {
+                        warn!("failed to accept, retrying in {:?}: {}", next, e);
+                        delay = next;
+                        tokio::time::sleep(delay).await;
+                    }
+                    None => {
+                        error!("Unable to accept: {}", e);
+                        return Err(Error::new(ErrorKind::Network, e));
+                    }
+                },
+            }
+        }
+    }
+
+    async fn process(
+        dispatcher: Dispatcher,
+        async_services: AsyncServices,
+        stream: TcpStream,
+        max_message_size: usize,
+        handler_timeout: Option<Duration>,
+    ) {
+        let peer_addr = stream.peer_addr().ok();
+        let (mut reader, writer) = stream.into_split();
+        let writer = Arc::new(Mutex::new(writer));
+
+        loop {
+            let mut msg = Message::new();
+            match read_message(&mut reader, max_message_size, &mut msg).await {
+                Ok(()) if msg.is_heartbeat() => {
+                    if let Some(reply_msg) = heartbeat_reply(&msg) {
+                        if let Err(err) = write_reply(&writer, &reply_msg.encode()).await {
+                            warn!("failed to answer heartbeat: {}", err);
+                        }
+                    }
+                }
+                Ok(()) => {
+                    let dispatcher = dispatcher.clone();
+                    let async_services = async_services.clone();
+                    let writer_cloned = writer.clone();
+                    tokio::spawn(async move {
+                        let reply_msg =
+                            serve(dispatcher, async_services, handler_timeout, peer_addr, msg)
+                                .await;
+                        if let Some(data) = reply_msg.map(|reply_msg| reply_msg.encode()) {
+                            if let Err(err) = write_reply(&writer_cloned, &data).await {
+                                match peer_addr {
+                                    Some(sa) => warn!("failed to reply to {}: {}", sa, err),
+                                    None => warn!("failed to reply: {}", err),
+                                }
+                            }
+                        }
+                    });
+                }
+                Err(err) if err.kind() == ErrorKind::Protocol => {
+                    warn!("failed to decode request: {}", err);
+                    if let Some(reply_msg) = decode_error_reply(&msg, &err) {
+                        if let Err(err) = write_reply(&writer, &reply_msg.encode()).await {
+                            warn!("failed to answer invalid request: {}", err);
+                        }
+                    }
+                }
+                Err(err) => {
+                    error!("failed to read: {}", err);
+                    if let Some(sa) = peer_addr {
+                        info!("client {} is closed", sa)
+                    }
+                    let _ = writer.lock().await.shutdown().await;
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Awaits the async handler of `msg` if it has one, and otherwise calls
+/// its blocking handler on the blocking thread pool.
+async fn serve(
+    dispatcher: Dispatcher,
+    async_services: AsyncServices,
+    handler_timeout: Option<Duration>,
+    peer_addr: Option<SocketAddr>,
+    msg: Message,
+) -> Option<Message> {
+    let key = (msg.service_path.clone(), msg.service_method.clone());
+    // unregistering through the registry only removes the placeholder
+    let f = if read_lock(&dispatcher.services).contains_key(&key) {
+        read_lock(&async_services).get(&key).cloned()
+    } else {
+        None
+    };
+    if f.is_none() {
+        return tokio::task::spawn_blocking(move || dispatcher.dispatch(peer_addr, &msg))
+            .await
+            .ok()
+            .flatten();
+    }
+
+    let begun = dispatcher.begin(peer_addr, &msg, f);
+    match begun {
+        Begun::Call(call, f) => {
+            // the handler runs as its own task so a panic ends up in the
+            // reply instead of tearing down this one.
+            let mut task = tokio::spawn(f(&msg.payload, call.st));
+            let joined = match handler_timeout {
+                Some(t) => match tokio::time::timeout(t, &mut task).await {
+                    Ok(joined) => joined,
+                    Err(_) => {
+                        task.abort();
+                        let err = Error::new(
+                            ErrorKind::Timeout,
+                            format!(
+                                "{}.{} timed out after {:?}",
+                                msg.service_path, msg.service_method, t
+                            ),
+                        );
+                        return dispatcher.finish(&msg, call, Ok(Err(err)));
+                    }
+                },
+                None => task.await,
+            };
+            let res = match joined {
+                Ok(res) => Ok(res),
+                Err(err) => match err.try_into_panic() {
+                    Ok(cause) => Err(cause),
+                    Err(err) => Ok(Err(Error::new(ErrorKind::Server, err.to_string()))),
+                },
+            };
+            dispatcher.finish(&msg, call, res)
+        }
+        Begun::Done(reply_msg) => reply_msg,
+    }
+}
+
+/// Reads one complete frame and decodes it into `msg`.
+///
+/// The frame is buffered in memory first so the blocking `Message::decode`
+/// never waits on the socket.
+async fn read_message<R: AsyncRead + Unpin>(
+    r: &mut R,
+    max_size: usize,
+    msg: &mut Message,
+) -> Result<()> {
+    let mut header = [0u8; 16];
+    r.read_exact(&mut header).await?;
+
+    let len = u32::from_be_bytes([header[12], header[13], header[14], header[15]]) as usize;
+    if len > max_size {
+        return Err(Error::new(
+            ErrorKind::MessageTooLarge,
+            format!("message size {} exceeds the limit of {}", len, max_size),
+        ));
+    }
+    let mut buf = vec![0u8; 16 + len];
+    buf[..16].copy_from_slice(&header);
+    r.read_exact(&mut buf[16..]).await?;
+
+    msg.decode(&mut &buf[..])
+}
+
+async fn write_reply(writer: &Mutex<OwnedWriteHalf>, data: &[u8]) -> std::io::Result<()> {
+    let mut writer = writer.lock().await;
+    writer.write_all(data).await?;
+    writer.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemoryRegisterPlugin, Server};
+
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        time::Instant,
+    };
+
+    fn request(service_method: &str, seq: u64) -> Message {
+        let mut msg = Message::new();
+        msg.set_serialize_type(SerializeType::JSON);
+        msg.set_seq(seq);
+        msg.service_path = "Echo".to_owned();
+        msg.service_method = service_method.to_owned();
+        msg.payload = b"hi".to_vec();
+        msg
+    }
+
+    /// Serves `server` on a loopback port from a task and returns the
+    /// address it listens on.
+    async fn serve(server: AsyncServer) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { server.start_with_listener(listener).await });
+        addr
+    }
+
+    async fn read_reply(conn: &mut TcpStream) -> Message {
+        let mut reply = Message::new();
+        read_message(conn, DEFAULT_MAX_MESSAGE_SIZE, &mut reply)
+            .await
+            .unwrap();
+        reply
+    }
+
+    fn echo(x: &[u8], _: SerializeType) -> Result<Vec<u8>> {
+        Ok(x.to_vec())
+    }
+
+    #[tokio::test]
+    async fn blocking_handlers_do_not_block_the_runtime() {
+        let server = AsyncServer::new(String::new());
+        let slow: RpcxFn = |x, _| {
+            std::thread::sleep(Duration::from_millis(300));
+            Ok(x.to_vec())
+        };
+        server
+            .register_fn("Echo".to_owned(), "Slow".to_owned(), String::new(), slow)
+            .unwrap();
+        server
+            .register_fn("Echo".to_owned(), "Fast".to_owned(), String::new(), echo)
+            .unwrap();
+        let mut conn = TcpStream::connect(serve(server).await).await.unwrap();
+
+        // the test runtime has one thread, which the slow handler would
+        // block unless it runs on the blocking pool
+        let start = Instant::now();
+        conn.write_all(&request("Slow", 1).encode()).await.unwrap();
+        conn.write_all(&request("Fast", 2).encode()).await.unwrap();
+        let first = read_reply(&mut conn).await;
+        assert_eq!(first.get_seq(), 2);
+        assert!(start.elapsed() < Duration::from_millis(250));
+        let second = read_reply(&mut conn).await;
+        assert_eq!(second.get_seq(), 1);
+        assert_eq!(second.get_error(), None);
+        assert_eq!(second.payload, b"hi");
+    }
+
+    #[tokio::test]
+    async fn one_runtime_thread_serves_many_connections() {
+        let server = AsyncServer::new(String::new());
+        server
+            .register_fn("Echo".to_owned(), "Echo".to_owned(), String::new(), echo)
+            .unwrap();
+        let addr = serve(server).await;
+
+        let mut conns = Vec::new();
+        for _ in 0..64 {
+            conns.push(TcpStream::connect(addr).await.unwrap());
+        }
+        for (seq, conn) in conns.iter_mut().enumerate() {
+            conn.write_all(&request("Echo", seq as u64).encode())
+                .await
+                .unwrap();
+        }
+        for (seq, conn) in conns.iter_mut().enumerate().rev() {
+            let reply = read_reply(conn).await;
+            assert_eq!(reply.get_seq(), seq as u64);
+            assert_eq!(reply.payload, b"hi");
+        }
+    }
+
+    #[tokio::test]
+    async fn services_are_registered_through_the_registry() {
+        let plugin = MemoryRegisterPlugin::new();
+        let mut server = Server::new("127.0.0.1:0".to_owned(), 1);
+        server.add_register_plugin(Box::new(plugin.clone()));
+        let async_server = AsyncServer::with_registry(String::new(), server.registry());
+
+        async_server
+            .register_fn("Echo".to_owned(), "Echo".to_owned(), "v=1".to_owned(), echo)
+            .unwrap();
+        assert!(async_server
+            .register_fn(
+                "Echo".to_owned(),
+                "Bad name".to_owned(),
+                String::new(),
+                echo
+            )
+            .is_err());
+        assert_eq!(
+            plugin.registrations(),
+            vec![("Echo".to_owned(), "Echo".to_owned(), "v=1".to_owned())]
+        );
+        // one set of services for both servers
+        assert!(server
+            .get_fn("Echo".to_owned(), "Echo".to_owned())
+            .is_some());
+
+        let mut conn = TcpStream::connect(serve(async_server).await).await.unwrap();
+        conn.write_all(&request("Echo", 1).encode()).await.unwrap();
+        assert_eq!(read_reply(&mut conn).await.payload, b"hi");
+    }
+
+    #[tokio::test]
+    async fn slow_async_handlers_time_out_and_are_dropped() {
+        /// Flips its flag when the handler's future is dropped.
+        struct Dropped(Arc<AtomicBool>);
+        impl Drop for Dropped {
+            fn drop(&mut self) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let dropped = Arc::new(AtomicBool::new(false));
+        let d = dropped.clone();
+        let mut server = AsyncServer::new(String::new());
+        server.set_handler_timeout(Some(Duration::from_millis(100)));
+        server
+            .register_async_fn(
+                "Echo".to_owned(),
+                "Never".to_owned(),
+                String::new(),
+                move |_, _| {
+                    let guard = Dropped(d.clone());
+                    Box::pin(async move {
+                        let _guard = guard;
+                        tokio::time::sleep(Duration::from_secs(10)).await;
+                        Ok(Vec::new())
+                    })
+                },
+            )
+            .unwrap();
+        let mut conn = TcpStream::connect(serve(server).await).await.unwrap();
+
+        let start = Instant::now();
+        conn.write_all(&request("Never", 1).encode()).await.unwrap();
+        let reply = read_reply(&mut conn).await;
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(
+            reply.get_error().as_deref(),
+            Some("Echo.Never timed out after 100ms")
+        );
+        // the aborted task is dropped once the runtime gets to it
+        for _ in 0..100 {
+            if dropped.load(Ordering::SeqCst) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn async_handlers_are_awaited_and_announced() {
+        let plugin = MemoryRegisterPlugin::new();
+        let mut server = AsyncServer::new(String::new());
+        server.add_register_plugin(Box::new(plugin.clone()));
+        server
+            .register_async_fn(
+                "Echo".to_owned(),
+                "Later".to_owned(),
+                "v=2".to_owned(),
+                |x, _| {
+                    let x = x.to_vec();
+                    Box::pin(async move {
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        Ok(x)
+                    })
+                },
+            )
+            .unwrap();
+        assert!(server
+            .register_async_fn("Echo".to_owned(), String::new(), String::new(), |_, _| {
+                Box::pin(async { Ok(Vec::new()) })
+            })
+            .is_err());
+        assert_eq!(read_lock(&server.async_services).len(), 1);
+        assert_eq!(
+            plugin.registrations(),
+            vec![("Echo".to_owned(), "Later".to_owned(), "v=2".to_owned())]
+        );
+
+        // a threaded server sharing the registry can't serve it
+        let f = server.registry.services.read().unwrap()[&("Echo".to_owned(), "Later".to_owned())]
+            .clone();
+        assert!(f(&Context::default(), b"hi", SerializeType::JSON).is_err());
+
+        let registry = server.registry();
+        let mut conn = TcpStream::connect(serve(server).await).await.unwrap();
+        conn.write_all(&request("Later", 1).encode()).await.unwrap();
+        let reply = read_reply(&mut conn).await;
+        assert_eq!(reply.get_error(), None);
+        assert_eq!(reply.payload, b"hi");
+
+        registry.unregister_fn("Echo".to_owned(), "Later".to_owned());
+        assert!(plugin.registrations().is_empty());
+        conn.write_all(&request("Later", 2).encode()).await.unwrap();
+        let reply = read_reply(&mut conn).await;
+        assert!(reply.get_error().unwrap().contains("not found"));
+    }
+}
@@ -0,0 +1,252 @@
+use super::{
+    default_thread_number, health::health_handler, reflection::reflection_handler, write_lock,
+    DispatchMode, RpcxHandler, Server, TcpKeepalive, DEFAULT_BACKLOG, DEFAULT_BUFFER_SIZE,
+    DEFAULT_MAX_MESSAGE_SIZE, HEALTH_SERVICE_METHOD, HEALTH_SERVICE_PATH,
+    REFLECTION_SERVICE_METHOD, REFLECTION_SERVICE_PATH,
+};
+use rpcx_protocol::CompressType;
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize},
+        Arc, Mutex, RwLock,
+    },
+    time::Duration,
+};
+
+use threadpool::ThreadPool;
+
+/// Collects the options of a `Server` before it is created.
+///
+/// Every option defaults to what `Server::new` uses, and each one can still
+/// be changed on the built server with the matching setter.
+#[derive(Debug, Clone)]
+pub struct ServerBuilder {
+    addr: String,
+    thread_number: u32,
+    grace_period: Duration,
+    read_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    max_message_size: usize,
+    max_connections: usize,
+    max_pending_per_conn: usize,
+    dispatch_mode: DispatchMode,
+    health_check: bool,
+    reflection: bool,
+    nodelay: bool,
+    keepalive: Option<TcpKeepalive>,
+    reuse_addr: bool,
+    reuse_port: bool,
+    backlog: i32,
+    buffer_size: usize,
+    compression: CompressType,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        ServerBuilder {
+            addr: String::new(),
+            thread_number: 0,
+            grace_period: Duration::from_secs(30),
+            read_timeout: None,
+            idle_timeout: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            max_connections: 0,
+            max_pending_per_conn: 0,
+            dispatch_mode: DispatchMode::Pooled,
+            health_check: false,
+            reflection: false,
+            nodelay: true,
+            keepalive: None,
+            reuse_addr: true,
+            reuse_port: false,
+            backlog: DEFAULT_BACKLOG,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            compression: CompressType::CompressNone,
+        }
+    }
+}
+
+impl ServerBuilder {
+    pub fn new() -> Self {
+        ServerBuilder::default()
+    }
+
+    /// The address `start` listens on, e.g. `"0.0.0.0:8972"`.
+    pub fn addr(mut self, addr: impl Into<String>) -> Self {
+        self.addr = addr.into();
+        self
+    }
+
+    /// How many handler threads the server has, and so how many requests
+    /// are handled at once, in total over all connections rather than per
+    /// connection. `0`, the default, means `default_thread_number()`.
+    pub fn thread_number(mut self, thread_number: u32) -> Self {
+        self.thread_number = thread_number;
+        self
+    }
+
+    /// See `Server::set_grace_period`.
+    pub fn grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// See `Server::set_read_timeout`.
+    pub fn read_timeout(mut self, read_timeout: Duration) -> Self {
+        self.read_timeout = Some(read_timeout);
+        self
+    }
+
+    /// See `Server::set_idle_timeout`.
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    /// See `Server::set_max_message_size`.
+    pub fn max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// See `Server::set_max_connections`.
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// See `Server::set_dispatch_mode`.
+    pub fn dispatch_mode(mut self, dispatch_mode: DispatchMode) -> Self {
+        self.dispatch_mode = dispatch_mode;
+        self
+    }
+
+    /// See `Server::set_max_pending_per_conn`.
+    pub fn max_pending_per_conn(mut self, max_pending_per_conn: usize) -> Self {
+        self.max_pending_per_conn = max_pending_per_conn;
+        self
+    }
+
+    /// See `Server::set_nodelay`.
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// See `Server::set_keepalive`.
+    pub fn keepalive(mut self, keepalive: TcpKeepalive) -> Self {
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    /// See `Server::set_reuse_addr`.
+    pub fn reuse_addr(mut self, reuse_addr: bool) -> Self {
+        self.reuse_addr = reuse_addr;
+        self
+    }
+
+    /// See `Server::set_reuse_port`.
+    pub fn reuse_port(mut self, reuse_port: bool) -> Self {
+        self.reuse_port = reuse_port;
+        self
+    }
+
+    /// See `Server::set_backlog`.
+    pub fn backlog(mut self, backlog: i32) -> Self {
+        self.backlog = backlog;
+        self
+    }
+
+    /// See `Server::set_buffer_size`.
+    pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+        self.buffer_size = buffer_size;
+        self
+    }
+
+    /// See `Server::set_compression`.
+    pub fn compression(mut self, compression: CompressType) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Registers a health check, `rpcx.health.check`, that load balancers
+    /// and discovery backends can call. It replies with a `HealthStatus`
+    /// encoded like the request and ignores the payload.
+    pub fn with_health_check(mut self) -> Self {
+        self.health_check = true;
+        self
+    }
+
+    /// Registers a reflection service, `rpcx.reflection.list`, so tools can
+    /// ask a running server what it serves. It replies with a list of
+    /// `ServiceInfo` encoded like the request and ignores the payload.
+    pub fn with_reflection(mut self) -> Self {
+        self.reflection = true;
+        self
+    }
+
+    pub fn build(self) -> Server {
+        let thread_number = match self.thread_number {
+            0 => default_thread_number(),
+            n => n,
+        };
+        let closing = Arc::new(AtomicBool::new(false));
+        let metas = Arc::new(RwLock::new(HashMap::new()));
+        let mut services = HashMap::new();
+        let mut builtin = |service_path: &str, service_method: &str, handler: RpcxHandler| {
+            let key = (service_path.to_owned(), service_method.to_owned());
+            write_lock(&metas).insert(key.clone(), String::new());
+            services.insert(key, Arc::new(handler));
+        };
+        if self.health_check {
+            let handler = health_handler(closing.clone());
+            builtin(HEALTH_SERVICE_PATH, HEALTH_SERVICE_METHOD, handler);
+        }
+        if self.reflection {
+            let handler = reflection_handler(metas.clone());
+            builtin(REFLECTION_SERVICE_PATH, REFLECTION_SERVICE_METHOD, handler);
+        }
+        Server {
+            addr: self.addr,
+            services: Arc::new(RwLock::new(services)),
+            streams: Arc::new(RwLock::new(HashMap::new())),
+            uploads: Arc::new(RwLock::new(HashMap::new())),
+            messages: Arc::new(RwLock::new(HashMap::new())),
+            metas,
+            draining: Arc::new(RwLock::new(HashSet::new())),
+            pool: ThreadPool::with_name("rpcx-handler".to_owned(), thread_number as usize),
+            register_plugins: Arc::new(RwLock::new(Vec::new())),
+            connect_plugins: Arc::new(RwLock::new(Vec::new())),
+            invoke_plugins: Arc::new(RwLock::new(Vec::new())),
+            on_connect: None,
+            on_disconnect: None,
+            conn_events: None,
+            metrics: None,
+            compression: self.compression,
+            echoed_metadata: Arc::new(Vec::new()),
+            not_found: None,
+            default_handler: None,
+            closing,
+            local_addrs: Mutex::new(Vec::new()),
+            #[cfg(not(target_os = "windows"))]
+            unix_path: Mutex::new(None),
+            connections: Arc::new(Mutex::new(Vec::new())),
+            grace_period: self.grace_period,
+            read_timeout: self.read_timeout,
+            idle_timeout: self.idle_timeout,
+            max_message_size: self.max_message_size,
+            max_connections: self.max_connections,
+            max_pending_per_conn: self.max_pending_per_conn,
+            dispatch_mode: self.dispatch_mode,
+            nodelay: self.nodelay,
+            keepalive: self.keepalive,
+            reuse_addr: self.reuse_addr,
+            reuse_port: self.reuse_port,
+            backlog: self.backlog,
+            buffer_size: self.buffer_size,
+            active_connections: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
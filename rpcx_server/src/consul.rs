@@ -0,0 +1,223 @@
+use super::{RegisterPlugin, RpcxHandler};
+use rpcx_protocol::*;
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::{BufRead, BufReader, Write},
+    net::TcpStream,
+    sync::{
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use log::warn;
+use serde_json::json;
+
+/// Registers services with a Consul agent.
+///
+/// Every service is registered as a Consul service with a TTL check that a
+/// background thread passes once per check interval, so Consul marks the
+/// server critical once it is gone. For Go clients using the Consul
+/// registry, which read the KV store, the meta of every service is also
+/// written to `{base_path}/{service_path}/tcp@{server_addr}`. Services are
+/// deregistered when their last method is unregistered and when the server
+/// shuts down.
+pub struct ConsulRegisterPlugin {
+    agent: String,
+    server_addr: String,
+    host: String,
+    port: u16,
+    base_path: String,
+    check_interval: Duration,
+    /// The methods registered for each service path.
+    methods: HashMap<String, HashSet<String>>,
+    /// The ids of the registered services, shared with the check thread.
+    service_ids: Arc<Mutex<HashSet<String>>>,
+    check: Option<(Sender<()>, JoinHandle<()>)>,
+}
+
+impl ConsulRegisterPlugin {
+    /// `agent` is the `host:port` of the Consul agent's HTTP API and
+    /// `server_addr` the `host:port` clients reach the server at.
+    pub fn new(agent: &str, server_addr: &str) -> Result<Self> {
+        let (host, port) = server_addr
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse().ok()?)))
+            .ok_or_else(|| Error::from(format!("invalid server address {}", server_addr)))?;
+
+        Ok(ConsulRegisterPlugin {
+            agent: agent.to_owned(),
+            server_addr: server_addr.to_owned(),
+            host: host.to_owned(),
+            port,
+            base_path: "rpcx".to_owned(),
+            check_interval: Duration::from_secs(10),
+            methods: HashMap::new(),
+            service_ids: Arc::new(Mutex::new(HashSet::new())),
+            check: None,
+        })
+    }
+
+    /// Sets the prefix of the KV keys, `rpcx` by default. It must match the
+    /// base path of the clients.
+    pub fn set_base_path(&mut self, base_path: &str) {
+        self.base_path = base_path.trim_matches('/').to_owned();
+    }
+
+    /// Sets how often the check is passed, 10 seconds by default. Consul
+    /// marks a service critical after three intervals without a pass. Only
+    /// takes effect if set before the first service is registered.
+    pub fn set_check_interval(&mut self, check_interval: Duration) {
+        self.check_interval = check_interval;
+    }
+
+    fn service_id(&self, service_path: &str) -> String {
+        format!("{}-{}", service_path, self.server_addr)
+    }
+
+    fn kv_path(&self, service_path: &str) -> String {
+        format!(
+            "/v1/kv/{}/{}/tcp@{}",
+            self.base_path, service_path, self.server_addr
+        )
+    }
+
+    /// Starts the thread passing the checks, unless it is running already.
+    fn start_check(&mut self) {
+        if self.check.is_some() {
+            return;
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let agent = self.agent.clone();
+        let service_ids = self.service_ids.clone();
+        let interval = self.check_interval;
+        let handle = thread::Builder::new()
+            .name("rpcx-consul".to_owned())
+            .spawn(move || loop {
+                match rx.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    _ => return,
+                }
+                let ids: Vec<String> = service_ids.lock().unwrap().iter().cloned().collect();
+                for id in ids {
+                    let path = format!("/v1/agent/check/pass/service:{}", id);
+                    if let Err(err) = request(&agent, "PUT", &path, &[]) {
+                        warn!("failed to pass the consul check of {}: {}", id, err);
+                    }
+                }
+            })
+            .expect("failed to spawn the consul check thread");
+        self.check = Some((tx, handle));
+    }
+
+    /// Removes the Consul service and KV entry of `service_path`.
+    fn deregister(&self, service_path: &str) -> Result<()> {
+        let id = self.service_id(service_path);
+        self.service_ids.lock().unwrap().remove(&id);
+        let path = format!("/v1/agent/service/deregister/{}", id);
+        request(&self.agent, "PUT", &path, &[])?;
+        request(&self.agent, "DELETE", &self.kv_path(service_path), &[])
+    }
+}
+
+impl RegisterPlugin for ConsulRegisterPlugin {
+    fn register_fn(
+        &mut self,
+        service_path: &str,
+        service_method: &str,
+        meta: String,
+        _f: &RpcxHandler,
+    ) -> Result<()> {
+        if !self.methods.contains_key(service_path) {
+            let id = self.service_id(service_path);
+            let ttl = self.check_interval * 3;
+            let service = json!({
+                "ID": id,
+                "Name": service_path,
+                "Address": self.host,
+                "Port": self.port,
+                "Meta": { "rpcx_meta": meta },
+                "Check": {
+                    "Status": "passing",
+                    "TTL": format!("{}ms", ttl.as_millis()),
+                    "DeregisterCriticalServiceAfter": "1m",
+                },
+            });
+            let body = serde_json::to_vec(&service)?;
+            request(&self.agent, "PUT", "/v1/agent/service/register", &body)?;
+            request(
+                &self.agent,
+                "PUT",
+                &self.kv_path(service_path),
+                meta.as_bytes(),
+            )?;
+
+            self.service_ids.lock().unwrap().insert(id);
+            self.start_check();
+        }
+
+        self.methods
+            .entry(service_path.to_owned())
+            .or_default()
+            .insert(service_method.to_owned());
+        Ok(())
+    }
+
+    fn unregister_fn(&mut self, service_path: &str, service_method: &str) -> Result<()> {
+        let methods = match self.methods.get_mut(service_path) {
+            Some(methods) => methods,
+            None => return Ok(()),
+        };
+        methods.remove(service_method);
+        if !methods.is_empty() {
+            return Ok(());
+        }
+        self.methods.remove(service_path);
+        self.deregister(service_path)
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        if let Some((tx, handle)) = self.check.take() {
+            drop(tx);
+            let _ = handle.join();
+        }
+
+        let mut res = Ok(());
+        let service_paths: Vec<String> = self.methods.drain().map(|(path, _)| path).collect();
+        for service_path in service_paths {
+            if let Err(err) = self.deregister(&service_path) {
+                res = Err(err);
+            }
+        }
+        res
+    }
+}
+
+/// Sends a request to the agent's HTTP API and fails unless it answers with
+/// a 2xx status.
+fn request(agent: &str, method: &str, path: &str, body: &[u8]) -> Result<()> {
+    let mut stream = TcpStream::connect(agent)?;
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        method,
+        path,
+        agent,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line)?;
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(Error::new(
+            ErrorKind::Network,
+            format!("consul {} {} failed: {}", method, path, status_line.trim()),
+        )),
+    }
+}
@@ -0,0 +1,53 @@
+use rpcx_protocol::{CompressType, MessageType, Metadata, SerializeType};
+
+use std::{
+    cell::{Cell, RefCell},
+    net::SocketAddr,
+    time::Instant,
+};
+
+/// What a handler knows about the request it serves besides the payload.
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+    /// The service path and method the request was sent to, which differ
+    /// from the ones the handler was registered with for `ANY_METHOD`.
+    pub service_path: String,
+    pub service_method: String,
+    /// The client address, `None` for transports without one such as unix
+    /// sockets.
+    pub peer_addr: Option<SocketAddr>,
+    /// The metadata sent with the request.
+    pub metadata: Metadata,
+    /// When the client stops waiting for the reply, if it said so.
+    pub deadline: Option<Instant>,
+    /// The length of the payload, once decompressed. Upload handlers get
+    /// the length that was sent, compressed if the request is.
+    pub payload_len: usize,
+    /// How the payload was compressed on the wire.
+    pub compress_type: Option<CompressType>,
+    /// Whether the client waits for no reply.
+    pub is_oneway: bool,
+    /// The message type from the header.
+    pub message_type: Option<MessageType>,
+    /// Metadata to send with the reply, e.g. tracing headers.
+    pub reply_metadata: RefCell<Metadata>,
+    /// The serialize type of the reply if it isn't the request's, see
+    /// `set_reply_serialize_type`.
+    pub reply_serialize_type: Cell<Option<SerializeType>>,
+}
+
+impl Context {
+    /// Adds `key` to the metadata of the reply.
+    pub fn set_reply_metadata(&self, key: &str, value: &str) {
+        self.reply_metadata
+            .borrow_mut()
+            .insert(key.to_owned(), value.to_owned());
+    }
+
+    /// Marks the reply as encoded with `st` rather than with the serialize
+    /// type of the request, e.g. for a client that asked for another one in
+    /// the metadata. The handler has to encode its reply with `st` itself.
+    pub fn set_reply_serialize_type(&self, st: SerializeType) {
+        self.reply_serialize_type.set(Some(st));
+    }
+}
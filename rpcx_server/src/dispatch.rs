@@ -0,0 +1,447 @@
+use super::{
+    read_lock,
+    stream::{RpcxStreamHandler, StreamServices, StreamSink},
+    upload::{RpcxUploadHandler, UploadServices},
+    write_lock, Context, Draining, InvokePlugins, MessageServices, NotFoundHandler, RpcxHandler,
+    RpcxMessageHandler, Services, SharedMetrics, ANY_METHOD, COMPRESS_THRESHOLD,
+};
+use rpcx_protocol::*;
+
+use log::warn;
+use std::{
+    any::Any,
+    io::{self, Read},
+    net::SocketAddr,
+    panic::{self, AssertUnwindSafe},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Everything needed to serve a decoded request.
+///
+/// It is shared by the threaded and the async server so both report errors
+/// the same way.
+#[derive(Clone)]
+pub(crate) struct Dispatcher {
+    pub(crate) services: Services,
+    pub(crate) streams: StreamServices,
+    pub(crate) uploads: UploadServices,
+    pub(crate) messages: MessageServices,
+    pub(crate) draining: Draining,
+    pub(crate) invoke_plugins: InvokePlugins,
+    pub(crate) metrics: Option<SharedMetrics>,
+    pub(crate) compression: CompressType,
+    pub(crate) echoed_metadata: Arc<Vec<String>>,
+    pub(crate) not_found: Option<NotFoundHandler>,
+    pub(crate) default_handler: Option<Arc<RpcxHandler>>,
+}
+
+impl Dispatcher {
+    /// Looks up the service addressed by `msg`, invokes it and builds the
+    /// reply. Oneway requests are still served but get no reply.
+    ///
+    /// A request whose `DEADLINE` has passed is not dispatched, and a
+    /// handler finishing after it has its reply replaced by a timeout error.
+    /// Handlers are not interrupted when the deadline passes; they can check
+    /// `Context::deadline` themselves.
+    pub(crate) fn dispatch(&self, peer_addr: Option<SocketAddr>, msg: &Message) -> Option<Message> {
+        let key = self.service_key(msg);
+        if let Some(handler) = self.message_handler(&key) {
+            return self.dispatch_message(peer_addr, msg, handler);
+        }
+        let f = read_lock(&self.services)
+            .get(&key)
+            .cloned()
+            .or_else(|| self.default_handler.clone());
+
+        match self.begin(peer_addr, msg, f) {
+            Begun::Call(call, f) => {
+                let res =
+                    panic::catch_unwind(AssertUnwindSafe(|| f(&call.ctx, &msg.payload, call.st)));
+                self.finish(msg, call, res)
+            }
+            Begun::Done(reply_msg) => reply_msg,
+        }
+    }
+
+    /// Returns the key of the handler serving `msg`: its own if it has a
+    /// handler, else the `ANY_METHOD` of its service path if that has one,
+    /// else its own, which then is not found.
+    pub(crate) fn service_key(&self, msg: &Message) -> (String, String) {
+        let key = (msg.service_path.clone(), msg.service_method.clone());
+        let map = read_lock(&self.services);
+        if map.contains_key(&key) {
+            return key;
+        }
+        let any = (msg.service_path.clone(), ANY_METHOD.to_owned());
+        if map.contains_key(&any) {
+            any
+        } else {
+            key
+        }
+    }
+
+    fn message_handler(&self, key: &(String, String)) -> Option<Arc<RpcxMessageHandler>> {
+        let messages = read_lock(&self.messages);
+        if messages.is_empty() {
+            return None;
+        }
+        messages.get(key).cloned()
+    }
+
+    /// Like `dispatch`, for a handler returning the whole reply, which
+    /// replaces the one `begin` started.
+    fn dispatch_message(
+        &self,
+        peer_addr: Option<SocketAddr>,
+        msg: &Message,
+        handler: Arc<RpcxMessageHandler>,
+    ) -> Option<Message> {
+        match self.begin(peer_addr, msg, Some(handler)) {
+            Begun::Call(mut call, f) => {
+                let res = panic::catch_unwind(AssertUnwindSafe(|| f(msg)));
+                let res = res.map(|res| {
+                    res.map(|mut reply_msg| {
+                        let payload = std::mem::take(&mut reply_msg.payload);
+                        call.reply_msg = reply_msg;
+                        payload
+                    })
+                });
+                self.finish(msg, call, res)
+            }
+            Begun::Done(reply_msg) => reply_msg,
+        }
+    }
+
+    /// Returns the streaming handler addressed by `msg`, if it has one.
+    pub(crate) fn stream_handler(&self, msg: &Message) -> Option<Arc<RpcxStreamHandler>> {
+        if read_lock(&self.streams).is_empty() {
+            return None;
+        }
+        let key = self.service_key(msg);
+        read_lock(&self.streams).get(&key).cloned()
+    }
+
+    /// Like `dispatch`, for streaming handlers. The frames the handler sends
+    /// are written with `write` as they come; the returned reply, marked
+    /// `STREAM_END`, ends the stream.
+    pub(crate) fn dispatch_stream(
+        &self,
+        peer_addr: Option<SocketAddr>,
+        msg: &Message,
+        handler: Arc<RpcxStreamHandler>,
+        write: &mut dyn FnMut(&[u8]) -> io::Result<()>,
+    ) -> Option<Message> {
+        let reply_msg = match self.begin(peer_addr, msg, Some(handler)) {
+            Begun::Call(call, f) => {
+                let write = if msg.is_oneway() { None } else { Some(write) };
+                let mut sink = StreamSink::new(&call.reply_msg, write);
+                let res = panic::catch_unwind(AssertUnwindSafe(|| {
+                    f(&call.ctx, &msg.payload, call.st, &mut sink)
+                }));
+                self.finish(msg, call, res.map(|res| res.map(|()| Vec::new())))
+            }
+            Begun::Done(reply_msg) => reply_msg,
+        }?;
+        reply_msg
+            .metadata
+            .borrow_mut()
+            .insert(STREAM.to_owned(), STREAM_END.to_owned());
+        Some(reply_msg)
+    }
+
+    pub(crate) fn has_uploads(&self) -> bool {
+        !read_lock(&self.uploads).is_empty()
+    }
+
+    /// Returns the upload handler addressed by `msg`, if it has one.
+    pub(crate) fn upload_handler(&self, msg: &Message) -> Option<Arc<RpcxUploadHandler>> {
+        if !self.has_uploads() {
+            return None;
+        }
+        let key = self.service_key(msg);
+        read_lock(&self.uploads).get(&key).cloned()
+    }
+
+    /// Like `dispatch`, for upload handlers, which read the `len` bytes of
+    /// the payload from `payload` rather than from `msg`.
+    pub(crate) fn dispatch_upload(
+        &self,
+        peer_addr: Option<SocketAddr>,
+        msg: &Message,
+        len: usize,
+        handler: Arc<RpcxUploadHandler>,
+        payload: &mut dyn Read,
+    ) -> Option<Message> {
+        match self.begin(peer_addr, msg, Some(handler)) {
+            Begun::Call(mut call, f) => {
+                call.ctx.payload_len = len;
+                let res = panic::catch_unwind(AssertUnwindSafe(|| f(&call.ctx, payload, call.st)));
+                self.finish(msg, call, res)
+            }
+            Begun::Done(reply_msg) => reply_msg,
+        }
+    }
+
+    /// Runs the checks before `handler`, the handler the caller found for
+    /// `msg`, may be called. `dispatch` is `begin`, the call and `finish`;
+    /// they are separate for callers that need to await the handler.
+    pub(crate) fn begin<H>(
+        &self,
+        peer_addr: Option<SocketAddr>,
+        msg: &Message,
+        handler: Option<H>,
+    ) -> Begun<H> {
+        let key = format!("{}.{}", msg.service_path, msg.service_method);
+        let (st, mut reply_msg) = match (msg.get_serialize_type(), msg.get_reply()) {
+            (Some(st), Ok(reply_msg)) => (st, reply_msg),
+            (_, res) => {
+                let err = res.err().map_or_else(
+                    || "unsupported serialize type".to_owned(),
+                    |err| err.to_string(),
+                );
+                let mut reply_msg = bare_reply(msg);
+                set_service_error(&mut reply_msg, err);
+                return Begun::Done(self.reply(msg, &key, reply_msg));
+            }
+        };
+        let deadline = deadline(msg);
+        let rejected = {
+            let plugins = read_lock(&self.invoke_plugins);
+            plugins.iter().find_map(|p| p.pre_call(msg).err())
+        };
+
+        let err = match (rejected, handler) {
+            (Some(err), _) => err,
+            (None, Some(_)) if self.is_draining(msg) => {
+                Error::new(ErrorKind::Server, "service draining")
+            }
+            (None, Some(_)) if is_expired(deadline) => Error::new(
+                ErrorKind::Timeout,
+                format!("deadline of {} exceeded before it was called", key),
+            ),
+            (None, Some(handler)) => {
+                let call = Call {
+                    ctx: Context {
+                        service_path: msg.service_path.clone(),
+                        service_method: msg.service_method.clone(),
+                        peer_addr,
+                        metadata: msg.metadata.borrow().clone(),
+                        deadline,
+                        payload_len: msg.payload.len(),
+                        compress_type: msg.get_compress_type(),
+                        is_oneway: msg.is_oneway(),
+                        message_type: msg.get_message_type(),
+                        reply_metadata: Default::default(),
+                        reply_serialize_type: Default::default(),
+                    },
+                    st,
+                    key,
+                    reply_msg,
+                    start: Instant::now(),
+                };
+                return Begun::Call(call, handler);
+            }
+            (None, None) => match &self.not_found {
+                Some(not_found) => return Begun::Done(self.reply(msg, &key, not_found(msg))),
+                None => Error::new(
+                    ErrorKind::ServiceNotFound,
+                    format!("service {} not found", key),
+                ),
+            },
+        };
+        set_service_error(&mut reply_msg, err.to_string());
+        Begun::Done(self.reply(msg, &key, reply_msg))
+    }
+
+    /// Builds the reply of `call` from what its handler returned.
+    pub(crate) fn finish(
+        &self,
+        msg: &Message,
+        call: Call,
+        res: thread::Result<Result<Vec<u8>>>,
+    ) -> Option<Message> {
+        let Call {
+            ctx,
+            key,
+            mut reply_msg,
+            start,
+            ..
+        } = call;
+        if let Some(metrics) = &self.metrics {
+            let is_error = !matches!(res, Ok(Ok(_)));
+            metrics.record(&key, start.elapsed(), is_error);
+        }
+
+        let res = match res {
+            _ if is_expired(ctx.deadline) => Err(Error::new(
+                ErrorKind::Timeout,
+                format!("deadline of {} exceeded", key),
+            )),
+            Ok(res) => res,
+            Err(cause) => Err(Error::new(ErrorKind::HandlerPanic, panic_message(&*cause))),
+        };
+        reply_msg
+            .metadata
+            .borrow_mut()
+            .extend(ctx.reply_metadata.into_inner());
+        if let Some(st) = ctx.reply_serialize_type.get() {
+            reply_msg.set_serialize_type(st);
+        }
+        match res {
+            Ok(reply) => {
+                if self.compression != CompressType::CompressNone
+                    && reply.len() > COMPRESS_THRESHOLD
+                {
+                    reply_msg.set_compress_type(self.compression);
+                }
+                reply_msg.payload = reply;
+            }
+            Err(err) => set_service_error(&mut reply_msg, err.to_string()),
+        }
+        self.reply(msg, &key, reply_msg)
+    }
+
+    fn is_draining(&self, msg: &Message) -> bool {
+        let draining = read_lock(&self.draining);
+        !draining.is_empty()
+            && draining.contains(&(msg.service_path.clone(), msg.service_method.clone()))
+    }
+
+    /// Copies the echoed metadata of `msg` the handler didn't set into the
+    /// reply, shows it to the invoke plugins and returns it, unless `msg` is
+    /// oneway.
+    fn reply(&self, msg: &Message, key: &str, reply_msg: Message) -> Option<Message> {
+        if !self.echoed_metadata.is_empty() {
+            let metadata = msg.metadata.borrow();
+            let mut reply_metadata = reply_msg.metadata.borrow_mut();
+            for k in self.echoed_metadata.iter() {
+                if let Some(v) = metadata.get(k) {
+                    reply_metadata.entry(k.clone()).or_insert_with(|| v.clone());
+                }
+            }
+        }
+
+        let plugins = read_lock(&self.invoke_plugins);
+        for p in plugins.iter() {
+            if let Err(err) = p.post_call(msg, &reply_msg) {
+                warn!("invoke plugin failed after calling {}: {}", key, err);
+            }
+        }
+        drop(plugins);
+
+        if msg.is_oneway() {
+            return None;
+        }
+        Some(reply_msg)
+    }
+}
+
+pub(crate) fn set_draining(
+    draining: &Draining,
+    service_path: &str,
+    service_method: &str,
+    is_draining: bool,
+) {
+    let key = (service_path.to_owned(), service_method.to_owned());
+    let mut draining = write_lock(draining);
+    if is_draining {
+        draining.insert(key);
+    } else {
+        draining.remove(&key);
+    }
+}
+
+/// The outcome of `Dispatcher::begin`.
+// only lives until the handler is called, not worth boxing the call for
+#[allow(clippy::large_enum_variant)]
+pub(crate) enum Begun<H> {
+    /// The handler may be called with the `Context` and serialize type of
+    /// the call.
+    Call(Call, H),
+    /// The request was answered without calling a handler.
+    Done(Option<Message>),
+}
+
+/// A request on its way to its handler.
+pub(crate) struct Call {
+    pub(crate) ctx: Context,
+    pub(crate) st: SerializeType,
+    key: String,
+    reply_msg: Message,
+    start: Instant,
+}
+
+/// Reads the `DEADLINE` of `msg` as an `Instant`. A deadline that can't be
+/// parsed is ignored.
+fn deadline(msg: &Message) -> Option<Instant> {
+    let metadata = msg.metadata.borrow();
+    let millis: u64 = metadata.get(DEADLINE)?.parse().ok()?;
+    let deadline = UNIX_EPOCH + Duration::from_millis(millis);
+
+    let now = Instant::now();
+    match deadline.duration_since(SystemTime::now()) {
+        Ok(left) => Some(now + left),
+        Err(_) => Some(now),
+    }
+}
+
+fn is_expired(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|deadline| Instant::now() >= deadline)
+}
+
+/// Answers a heartbeat like the Go server does, by echoing it back as a
+/// response. Oneway heartbeats get no answer.
+pub(crate) fn heartbeat_reply(msg: &Message) -> Option<Message> {
+    if msg.is_oneway() {
+        return None;
+    }
+    let mut reply_msg = msg.get_reply().ok()?;
+    reply_msg.set_heartbeat(true);
+    reply_msg.payload = msg.payload.clone();
+    Some(reply_msg)
+}
+
+/// Answers a request that could not be decoded with the reason, so the
+/// client doesn't wait for a reply. Oneway requests get no answer.
+pub(crate) fn decode_error_reply(msg: &Message, err: &Error) -> Option<Message> {
+    if msg.is_oneway() {
+        return None;
+    }
+    let mut reply_msg = bare_reply(msg);
+    set_service_error(&mut reply_msg, format!("invalid request: {}", err));
+    Some(reply_msg)
+}
+
+/// Starts the reply to `msg` like `Message::get_reply`, but only copies
+/// the types of the header that are valid.
+fn bare_reply(msg: &Message) -> Message {
+    let mut reply_msg = Message::new();
+    reply_msg.set_version(msg.get_version());
+    reply_msg.set_message_type(MessageType::Response);
+    if let Some(st) = msg.get_serialize_type() {
+        reply_msg.set_serialize_type(st);
+    }
+    reply_msg.set_seq(msg.get_seq());
+    reply_msg.service_path = msg.service_path.clone();
+    reply_msg.service_method = msg.service_method.clone();
+    reply_msg
+}
+
+pub(crate) fn set_service_error(reply_msg: &mut Message, err: String) {
+    let mut metadata = reply_msg.metadata.borrow_mut();
+    (*metadata).insert(SERVICE_ERROR.to_string(), err);
+    drop(metadata);
+    reply_msg.set_message_status_type(MessageStatusType::Error);
+}
+
+fn panic_message(cause: &(dyn Any + Send)) -> String {
+    if let Some(s) = cause.downcast_ref::<&str>() {
+        format!("service panicked: {}", s)
+    } else if let Some(s) = cause.downcast_ref::<String>() {
+        format!("service panicked: {}", s)
+    } else {
+        "service panicked".to_owned()
+    }
+}
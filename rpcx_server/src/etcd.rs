@@ -0,0 +1,238 @@
+use super::{RegisterPlugin, RpcxHandler};
+use rpcx_protocol::*;
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use etcd_client::{Client, PutOptions};
+use log::warn;
+use tokio::{
+    runtime::{self, Runtime},
+    task::JoinHandle,
+};
+
+/// Registers services in etcd the way the Go rpcx etcd registry does, so Go
+/// clients can discover them.
+///
+/// Every service gets the key `{base_path}/{service_path}/{server_addr}`
+/// with its meta as value, written when its first method is registered and
+/// deleted when its last method is unregistered. All keys are attached to
+/// one lease that a background thread keeps alive, so they disappear once
+/// the server is gone. If the lease is lost, e.g. because etcd restarted, a
+/// new one is granted and the keys are written again.
+///
+/// The plugin runs its own tokio runtime and blocks on it while registering,
+/// so it must not be used from within another runtime.
+pub struct EtcdRegisterPlugin {
+    runtime: Runtime,
+    client: Client,
+    base_path: String,
+    server_addr: String,
+    ttl: Duration,
+    /// The methods registered for each service path.
+    methods: HashMap<String, HashSet<String>>,
+    /// Set once the first service is registered.
+    lease: Option<Arc<Mutex<Lease>>>,
+    keep_alive: Option<JoinHandle<()>>,
+}
+
+/// The lease the keys are attached to and the keys to write again when it
+/// has to be replaced.
+struct Lease {
+    id: i64,
+    keys: HashMap<String, String>,
+}
+
+impl EtcdRegisterPlugin {
+    /// Connects to the etcd cluster at `endpoints`. `server_addr` is the
+    /// address clients reach the server at; Go clients expect it in the
+    /// form `tcp@host:port`.
+    pub fn new<E: AsRef<str>>(endpoints: &[E], server_addr: &str) -> Result<Self> {
+        let runtime = runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("rpcx-etcd")
+            .enable_all()
+            .build()?;
+        let client = runtime
+            .block_on(Client::connect(endpoints, None))
+            .map_err(|e| Error::new(ErrorKind::Network, e))?;
+
+        Ok(EtcdRegisterPlugin {
+            runtime,
+            client,
+            base_path: "/rpcx".to_owned(),
+            server_addr: server_addr.to_owned(),
+            ttl: Duration::from_secs(10),
+            methods: HashMap::new(),
+            lease: None,
+            keep_alive: None,
+        })
+    }
+
+    /// Sets the prefix of the keys, `/rpcx` by default. It must match the
+    /// base path of the clients.
+    pub fn set_base_path(&mut self, base_path: &str) {
+        self.base_path = base_path.trim_end_matches('/').to_owned();
+    }
+
+    /// Sets how long the keys outlive the server, 10 seconds by default.
+    /// Only takes effect if set before the first service is registered.
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = ttl;
+    }
+
+    fn key(&self, service_path: &str) -> String {
+        format!("{}/{}/{}", self.base_path, service_path, self.server_addr)
+    }
+
+    /// Returns the lease, granting it and starting its keepalive first if
+    /// this is the first registration.
+    fn lease(&mut self) -> Result<Arc<Mutex<Lease>>> {
+        if let Some(lease) = &self.lease {
+            return Ok(lease.clone());
+        }
+
+        let ttl = self.ttl.as_secs().max(1) as i64;
+        let id = self
+            .runtime
+            .block_on(self.client.lease_grant(ttl, None))
+            .map_err(|e| Error::new(ErrorKind::Network, e))?
+            .id();
+        let lease = Arc::new(Mutex::new(Lease {
+            id,
+            keys: HashMap::new(),
+        }));
+        let task = keep_alive(self.client.clone(), lease.clone(), ttl);
+        self.keep_alive = Some(self.runtime.spawn(task));
+        self.lease = Some(lease.clone());
+        Ok(lease)
+    }
+}
+
+impl RegisterPlugin for EtcdRegisterPlugin {
+    fn register_fn(
+        &mut self,
+        service_path: &str,
+        service_method: &str,
+        meta: String,
+        _f: &RpcxHandler,
+    ) -> Result<()> {
+        let registered = self.methods.contains_key(service_path);
+        if !registered {
+            let lease = self.lease()?;
+            let key = self.key(service_path);
+            let id = lease.lock().unwrap().id;
+            let options = PutOptions::new().with_lease(id);
+            self.runtime
+                .block_on(self.client.put(key.clone(), meta.clone(), Some(options)))
+                .map_err(|e| Error::new(ErrorKind::Network, e))?;
+            lease.lock().unwrap().keys.insert(key, meta);
+        }
+
+        self.methods
+            .entry(service_path.to_owned())
+            .or_default()
+            .insert(service_method.to_owned());
+        Ok(())
+    }
+
+    fn unregister_fn(&mut self, service_path: &str, service_method: &str) -> Result<()> {
+        let methods = match self.methods.get_mut(service_path) {
+            Some(methods) => methods,
+            None => return Ok(()),
+        };
+        methods.remove(service_method);
+        if !methods.is_empty() {
+            return Ok(());
+        }
+        self.methods.remove(service_path);
+
+        let key = self.key(service_path);
+        if let Some(lease) = &self.lease {
+            lease.lock().unwrap().keys.remove(&key);
+        }
+        self.runtime
+            .block_on(self.client.delete(key, None))
+            .map_err(|e| Error::new(ErrorKind::Network, e))?;
+        Ok(())
+    }
+
+    /// Revokes the lease, which deletes every key at once.
+    fn stop(&mut self) -> Result<()> {
+        self.methods.clear();
+        if let Some(task) = self.keep_alive.take() {
+            task.abort();
+        }
+        let lease = match self.lease.take() {
+            Some(lease) => lease,
+            None => return Ok(()),
+        };
+        let id = lease.lock().unwrap().id;
+        self.runtime
+            .block_on(self.client.lease_revoke(id))
+            .map_err(|e| Error::new(ErrorKind::Network, e))?;
+        Ok(())
+    }
+}
+
+/// Refreshes `lease` three times per `ttl` seconds, replacing it when etcd
+/// no longer knows it.
+async fn keep_alive(mut client: Client, lease: Arc<Mutex<Lease>>, ttl: i64) {
+    let period = Duration::from_millis(ttl as u64 * 1000 / 3);
+    let mut keeper = None;
+    loop {
+        tokio::time::sleep(period).await;
+
+        if keeper.is_none() {
+            let id = lease.lock().unwrap().id;
+            match client.lease_keep_alive(id).await {
+                Ok(k) => keeper = Some(k),
+                Err(err) => {
+                    warn!("failed to keep etcd lease {:x} alive: {}", id, err);
+                    continue;
+                }
+            }
+        }
+
+        let (k, stream) = keeper.as_mut().unwrap();
+        let alive = match k.keep_alive().await {
+            Ok(()) => stream.message().await,
+            Err(err) => Err(err),
+        };
+        match alive {
+            Ok(Some(resp)) if resp.ttl() > 0 => {}
+            Ok(_) => {
+                keeper = None;
+                if let Err(err) = renew(&mut client, &lease, ttl).await {
+                    warn!("failed to replace expired etcd lease: {}", err);
+                }
+            }
+            Err(err) => {
+                keeper = None;
+                warn!("failed to keep etcd lease alive: {}", err);
+            }
+        }
+    }
+}
+
+/// Grants a new lease and writes the registered keys again with it.
+async fn renew(
+    client: &mut Client,
+    lease: &Mutex<Lease>,
+    ttl: i64,
+) -> std::result::Result<(), etcd_client::Error> {
+    let id = client.lease_grant(ttl, None).await?.id();
+    let keys = {
+        let mut lease = lease.lock().unwrap();
+        lease.id = id;
+        lease.keys.clone()
+    };
+    for (key, meta) in keys {
+        let options = PutOptions::new().with_lease(id);
+        client.put(key, meta, Some(options)).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,44 @@
+use super::{typed::encode_reply, RpcxHandler};
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// The service path of the health check, see `ServerBuilder::with_health_check`.
+pub const HEALTH_SERVICE_PATH: &str = "rpcx.health";
+
+/// The service method of the health check.
+pub const HEALTH_SERVICE_METHOD: &str = "check";
+
+/// The reply of the health check, encoded like the request.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthStatus {
+    /// `SERVING`, or `NOT_SERVING` once the server is shutting down.
+    pub status: String,
+    /// How long the server has existed, in seconds.
+    pub uptime_secs: u64,
+}
+
+/// Answers every call with the status of the server `closing` belongs to,
+/// ignoring the payload.
+pub(crate) fn health_handler(closing: Arc<AtomicBool>) -> RpcxHandler {
+    let created = Instant::now();
+    Box::new(move |_, _, st| {
+        let status = if closing.load(Ordering::SeqCst) {
+            "NOT_SERVING"
+        } else {
+            "SERVING"
+        };
+        let reply = HealthStatus {
+            status: status.to_owned(),
+            uptime_secs: created.elapsed().as_secs(),
+        };
+        encode_reply(st, &reply)
+    })
+}
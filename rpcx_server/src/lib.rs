@@ -1,256 +1,1356 @@
 use std::{
     boxed::Box,
-    collections::HashMap,
-    sync::{Arc, RwLock}, borrow::BorrowMut,
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc, Condvar, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard,
+        RwLockWriteGuard,
+    },
+    time::{Duration, Instant},
 };
 
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+#[cfg(not(target_os = "windows"))]
+use std::path::PathBuf;
 
 use rpcx_protocol::*;
 use std::{
-    io::{BufReader, BufWriter, Write},
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
     net::{Shutdown, TcpListener, TcpStream},
 };
 
+use std::thread::{self, JoinHandle};
+
+use log::{error, info, warn};
+pub use socket2::TcpKeepalive;
+use socket2::{Domain, Type};
+use threadpool::ThreadPool;
+
+mod builder;
+pub mod context;
+mod dispatch;
+mod health;
+pub mod metrics;
+pub mod plugin;
+mod reflection;
+mod registry;
+mod service;
+mod stream;
+pub mod transport;
+mod typed;
+mod upload;
+pub use builder::ServerBuilder;
+pub use context::*;
+pub use health::{HealthStatus, HEALTH_SERVICE_METHOD, HEALTH_SERVICE_PATH};
+pub use metrics::*;
+pub use plugin::*;
+pub use reflection::{ServiceInfo, REFLECTION_SERVICE_METHOD, REFLECTION_SERVICE_PATH};
+pub use registry::ServiceRegistry;
+pub use service::RpcxService;
+pub use stream::{RpcxStreamHandler, StreamSink};
+pub use transport::*;
+pub use upload::RpcxUploadHandler;
+
 #[cfg(not(target_os = "windows"))]
-use std::os::unix::io::{AsRawFd, RawFd};
+mod unix;
 
-#[cfg(target_os = "windows")]
-use std::os::windows::io::{AsRawSocket, RawSocket};
+use dispatch::{decode_error_reply, heartbeat_reply, Dispatcher};
+use stream::StreamServices;
+use upload::UploadServices;
 
-#[cfg(target_os = "windows")]
-type RawFd = RawSocket;
+#[cfg(feature = "tokio")]
+pub mod async_server;
+#[cfg(feature = "tokio")]
+pub use async_server::*;
 
-use std::thread;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(feature = "tls")]
+pub use tls::*;
 
-use scoped_threadpool::Pool;
+pub mod consul;
+pub use consul::*;
 
-pub mod plugin;
-pub use plugin::*;
+#[cfg(feature = "etcd")]
+pub mod etcd;
+#[cfg(feature = "etcd")]
+pub use etcd::*;
 
 pub type RpcxFn = fn(&[u8], SerializeType) -> Result<Vec<u8>>;
 
+/// A service function that also gets the `Context` of the request.
+pub type RpcxCtxFn = fn(&Context, &[u8], SerializeType) -> Result<Vec<u8>>;
+
+/// A service handler that may capture state, e.g. a connection pool.
+pub type RpcxHandler = Box<dyn Fn(&Context, &[u8], SerializeType) -> Result<Vec<u8>> + Send + Sync>;
+
+/// Registered handlers keyed by `(service_path, service_method)`.
+pub type Services = Arc<RwLock<HashMap<(String, String), Arc<RpcxHandler>>>>;
+
+/// Locks `lock` for reading, even if a thread panicked while holding it.
+///
+/// The service maps and plugin lists are only changed by single inserts
+/// and removes, so a panic can't leave them half updated and a poisoned
+/// lock would otherwise make every later request and registration panic.
+pub(crate) fn read_lock<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Like `read_lock`, for writing.
+pub(crate) fn write_lock<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Like `read_lock`, for the mutexes keeping the listeners and connections
+/// of the server, so a panicking connection thread can't make `close`, and
+/// so dropping the server, panic.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The service method to register a handler with to serve every method of
+/// its service path.
+///
+/// A request is served by the handler of its own service path and method
+/// if there is one, else by the `ANY_METHOD` handler of its service path,
+/// else by the default handler, see `Server::set_default_handler`, else it
+/// is answered as not found, see `Server::set_not_found_handler`.
+/// The handler gets the method from `Context::service_method` or, for
+/// message handlers, from the request.
+pub const ANY_METHOD: &str = "*";
+
+/// A handler getting the whole request and returning the whole reply, see
+/// `Server::register_message_fn`.
+pub type RpcxMessageHandler = Box<dyn Fn(&Message) -> Result<Message> + Send + Sync>;
+
+type MessageServices = Arc<RwLock<HashMap<(String, String), Arc<RpcxMessageHandler>>>>;
+
+type RegisterPlugins = Arc<RwLock<Vec<Box<dyn RegisterPlugin + Send + Sync>>>>;
+
+/// The meta of every service registered with `Server::register_handler`.
+type Metas = Arc<RwLock<HashMap<(String, String), String>>>;
+
+pub type InvokePlugins = Arc<RwLock<Vec<Box<dyn InvokePlugin + Send + Sync>>>>;
+
+/// Builds the reply to a request for a service that isn't registered, see
+/// `Server::set_not_found_handler`.
+pub type NotFoundHandler = Arc<dyn Fn(&Message) -> Message + Send + Sync>;
+
+/// Called with the peer address of a connection, see `Server::set_on_connect`.
+pub type ConnCallback = Arc<dyn Fn(SocketAddr) + Send + Sync>;
+
+/// What happened to a connection, sent to the channel passed to
+/// `Server::set_conn_events`. `peer` is `None` for transports without a
+/// peer address.
+#[derive(Debug)]
+pub enum ConnEvent {
+    /// The connection was accepted and is about to be served.
+    Opened { peer: Option<SocketAddr> },
+    /// Reading or writing the connection failed, or a request could not be
+    /// decoded. Unless the request was malformed, `Closed` follows.
+    Error {
+        peer: Option<SocketAddr>,
+        err: Error,
+    },
+    /// The connection is closed and its requests are done.
+    Closed { peer: Option<SocketAddr> },
+}
+
+/// The `(service_path, service_method)` of services that are draining.
+type Draining = Arc<RwLock<HashSet<(String, String)>>>;
+
+/// The largest request accepted by default, 64 MiB.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+/// The number of handler threads a server gets when it is asked for `0`,
+/// two per CPU. The threads are shared by all of its connections, however
+/// many there are.
+pub fn default_thread_number() -> u32 {
+    num_cpus::get() as u32 * 2
+}
+
+/// The default capacity of connection buffers, the same as `BufReader`'s.
+pub const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+
+/// The default length of the queue of connections waiting to be accepted,
+/// the one `TcpListener::bind` uses.
+pub const DEFAULT_BACKLOG: i32 = 128;
+
+/// How long the accept loop waits after the first accept error that isn't
+/// about the connection being accepted, e.g. running out of file
+/// descriptors, doubled for every further one in a row up to
+/// `MAX_ACCEPT_DELAY`.
+const MIN_ACCEPT_DELAY: Duration = Duration::from_millis(5);
+const MAX_ACCEPT_DELAY: Duration = Duration::from_secs(1);
+
+/// Replies with a smaller payload are never compressed, see
+/// `Server::set_compression`.
+pub const COMPRESS_THRESHOLD: usize = 1024;
+
+/// Where the handlers of a connection's requests run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DispatchMode {
+    /// On the connection thread, before the next request is read. Saves
+    /// the handoff to the pool for cheap handlers, but a connection's
+    /// requests are handled one after another.
+    Inline,
+    /// On the server's thread pool, so the requests of a connection are
+    /// handled concurrently.
+    #[default]
+    Pooled,
+}
+
+/// A server that serves every connection on its own thread and runs the
+/// handlers on a thread pool.
+///
+/// Handler threads can't be cancelled, so a request's `DEADLINE` is only
+/// checked before its handler is called and after it returns; a handler
+/// that must stop early has to check `Context::deadline` itself.
 pub struct Server {
     pub addr: String,
-    raw_fd: Option<RawFd>,
-    pub services: Arc<RwLock<HashMap<String, Box<RpcxFn>>>>,
-    thread_number: u32,
-    register_plugins: Arc<RwLock<Vec<Box<dyn RegisterPlugin + Send + Sync>>>>,
+    pub services: Services,
+    streams: StreamServices,
+    uploads: UploadServices,
+    messages: MessageServices,
+    metas: Metas,
+    draining: Draining,
+    pool: ThreadPool,
+    register_plugins: RegisterPlugins,
     connect_plugins: Arc<RwLock<Vec<Box<dyn ConnectPlugin + Send + Sync>>>>,
+    invoke_plugins: InvokePlugins,
+    on_connect: Option<ConnCallback>,
+    on_disconnect: Option<ConnCallback>,
+    conn_events: Option<mpsc::Sender<ConnEvent>>,
+    metrics: Option<SharedMetrics>,
+    compression: CompressType,
+    echoed_metadata: Arc<Vec<String>>,
+    not_found: Option<NotFoundHandler>,
+    default_handler: Option<Arc<RpcxHandler>>,
+    closing: Arc<AtomicBool>,
+    local_addrs: Mutex<Vec<SocketAddr>>,
+    #[cfg(not(target_os = "windows"))]
+    unix_path: Mutex<Option<PathBuf>>,
+    connections: Arc<Mutex<Vec<(Arc<dyn WriteHalf>, JoinHandle<()>)>>>,
+    grace_period: Duration,
+    read_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    max_message_size: usize,
+    max_connections: usize,
+    max_pending_per_conn: usize,
+    dispatch_mode: DispatchMode,
+    nodelay: bool,
+    keepalive: Option<TcpKeepalive>,
+    reuse_addr: bool,
+    reuse_port: bool,
+    backlog: i32,
+    buffer_size: usize,
+    active_connections: Arc<AtomicUsize>,
+}
+
+/// Per-connection settings, copied into every connection thread.
+#[derive(Debug, Clone, Copy)]
+struct ConnConfig {
+    read_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    nodelay: bool,
+    buffer_size: usize,
+    max_message_size: usize,
+    max_pending: usize,
+    dispatch_mode: DispatchMode,
+}
+
+impl ConnConfig {
+    /// How long a read on the socket may block. With an idle timeout it
+    /// wakes up often enough to check the idle clock.
+    fn socket_timeout(&self) -> Option<Duration> {
+        match (self.read_timeout, self.idle_timeout) {
+            (Some(read), Some(idle)) => Some(read.min(idle)),
+            (read, idle) => read.or(idle),
+        }
+    }
 }
 
 impl Server {
+    /// Creates a server listening on `s` that handles up to `n` requests
+    /// at once over all its connections, `default_thread_number()` if `n`
+    /// is `0`. See `ServerBuilder` for the other options.
     pub fn new(s: String, n: u32) -> Self {
-        let mut thread_number = n;
-        if n == 0 {
-            thread_number = num_cpus::get() as u32;
-            thread_number *= 2;
+        ServerBuilder::new().addr(s).thread_number(n).build()
+    }
+
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::new()
+    }
+
+    /// Sets how long `shutdown` waits for in-flight requests to finish
+    /// before it closes the remaining connections.
+    pub fn set_grace_period(&mut self, grace_period: Duration) {
+        self.grace_period = grace_period;
+    }
+
+    /// Closes connections that send nothing for `read_timeout`.
+    ///
+    /// `None`, the default, blocks on reads forever.
+    pub fn set_read_timeout(&mut self, read_timeout: Option<Duration>) {
+        self.read_timeout = read_timeout;
+    }
+
+    /// Closes connections that send no request, heartbeats included, for
+    /// `idle_timeout`. Requests still being handled are answered first.
+    ///
+    /// Between requests this replaces the read timeout, which then only
+    /// limits how long reading a request may stall.
+    pub fn set_idle_timeout(&mut self, idle_timeout: Option<Duration>) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    /// Sets the largest request, in bytes, a client may send. Larger frames
+    /// are rejected before their payload is read and close the connection.
+    pub fn set_max_message_size(&mut self, max_message_size: usize) {
+        self.max_message_size = max_message_size;
+    }
+
+    /// Limits how many connections are served at once. Connections accepted
+    /// beyond the limit are closed right away.
+    ///
+    /// `0`, the default, means unlimited.
+    pub fn set_max_connections(&mut self, max_connections: usize) {
+        self.max_connections = max_connections;
+    }
+
+    /// Limits how many requests of one connection are handled or queued
+    /// for the pool at once. Once the limit is hit the connection is not
+    /// read until one of them is done, so a client pipelining requests
+    /// faster than they are handled is slowed down instead of filling
+    /// memory. Heartbeats are answered regardless.
+    ///
+    /// `0`, the default, means unlimited.
+    pub fn set_max_pending_per_conn(&mut self, max_pending_per_conn: usize) {
+        self.max_pending_per_conn = max_pending_per_conn;
+    }
+
+    /// Sets where handlers run, `DispatchMode::Pooled` by default.
+    pub fn set_dispatch_mode(&mut self, dispatch_mode: DispatchMode) {
+        self.dispatch_mode = dispatch_mode;
+    }
+
+    /// Sets `TCP_NODELAY` on accepted connections, so small replies are
+    /// sent right away instead of being delayed by Nagle's algorithm.
+    /// Enabled by default. Has no effect on unix sockets.
+    pub fn set_nodelay(&mut self, nodelay: bool) {
+        self.nodelay = nodelay;
+    }
+
+    /// Sets `SO_KEEPALIVE` on accepted connections with the idle time,
+    /// interval and count of `keepalive`, so the system notices peers that
+    /// went away without closing, e.g. behind a NAT that dropped the
+    /// connection, and the connection is closed. Unlike heartbeats this
+    /// needs nothing from the client. `None`, the default, leaves the
+    /// system's setting alone. Has no effect on unix sockets.
+    pub fn set_keepalive(&mut self, keepalive: Option<TcpKeepalive>) {
+        self.keepalive = keepalive;
+    }
+
+    /// Sets `SO_REUSEADDR` on listeners, so a restarted server can bind
+    /// its port while connections of the previous one are in `TIME_WAIT`.
+    /// Enabled by default. Not set on Windows, where it would let another
+    /// socket take over a port in use.
+    pub fn set_reuse_addr(&mut self, reuse_addr: bool) {
+        self.reuse_addr = reuse_addr;
+    }
+
+    /// Sets `SO_REUSEPORT` on listeners, so several servers can listen on
+    /// the same port and share its connections. Disabled by default. Only
+    /// supported on unix.
+    ///
+    /// `close` wakes the accept loop up with a connection to the port, which
+    /// may be handed to another server on it instead; the accept loop then
+    /// only returns with the next connection it gets.
+    pub fn set_reuse_port(&mut self, reuse_port: bool) {
+        self.reuse_port = reuse_port;
+    }
+
+    /// Sets how many connections the system queues for listeners while the
+    /// server has not accepted them yet, `DEFAULT_BACKLOG` by default.
+    /// Connections beyond it are refused or retried by the client, so
+    /// servers seeing bursts of new connections may want a larger one. The
+    /// system may cap it, e.g. at `net.core.somaxconn` on Linux.
+    pub fn set_backlog(&mut self, backlog: i32) {
+        self.backlog = backlog;
+    }
+
+    /// Sets the capacity of the buffers requests are read through and
+    /// replies are written through. Larger buffers take fewer system calls
+    /// for large messages.
+    pub fn set_buffer_size(&mut self, buffer_size: usize) {
+        self.buffer_size = buffer_size;
+    }
+
+    /// Replies to requests for services that aren't registered with what
+    /// `f` builds from the request, instead of a `SERVICE_ERROR` saying
+    /// "service ... not found". The reply should be started with
+    /// `Message::get_reply` to keep the seq the client waits for.
+    pub fn set_not_found_handler<F>(&mut self, f: F)
+    where
+        F: Fn(&Message) -> Message + Send + Sync + 'static,
+    {
+        self.not_found = Some(Arc::new(f));
+    }
+
+    /// Serves every request no registered handler serves with `f`, e.g. to
+    /// proxy them to another backend, instead of answering them as not
+    /// found. `f` gets the service path and method from its `Context` and
+    /// is called like a registered handler, after the invoke plugins.
+    pub fn set_default_handler<F>(&mut self, f: F)
+    where
+        F: Fn(&Context, &[u8], SerializeType) -> Result<Vec<u8>> + Send + Sync + 'static,
+    {
+        let handler: RpcxHandler = Box::new(f);
+        self.default_handler = Some(Arc::new(handler));
+    }
+
+    /// Calls `f` with the peer address of every TCP connection that is
+    /// served, after the connect plugins have accepted it. It runs on the
+    /// thread of the connection before its first request is read.
+    pub fn set_on_connect<F>(&mut self, f: F)
+    where
+        F: Fn(SocketAddr) + Send + Sync + 'static,
+    {
+        self.on_connect = Some(Arc::new(f));
+    }
+
+    /// Calls `f` with the peer address of every connection passed to the
+    /// `on_connect` callback once it is closed, so the two can count live
+    /// connections.
+    pub fn set_on_disconnect<F>(&mut self, f: F)
+    where
+        F: Fn(SocketAddr) + Send + Sync + 'static,
+    {
+        self.on_disconnect = Some(Arc::new(f));
+    }
+
+    /// Sends what happens to every connection to `events`, so a supervisor
+    /// can watch connection churn and errors, e.g. to alert or to limit a
+    /// misbehaving peer. A client closing its connection between requests
+    /// is no error. Events are dropped once the receiver is gone.
+    pub fn set_conn_events(&mut self, events: mpsc::Sender<ConnEvent>) {
+        self.conn_events = Some(events);
+    }
+
+    /// Reports the latency and outcome of every handler call, and the
+    /// requests that wait for a thread of the pool, to `metrics`.
+    pub fn set_metrics(&mut self, metrics: SharedMetrics) {
+        self.metrics = Some(metrics);
+    }
+
+    /// Compresses reply payloads larger than `COMPRESS_THRESHOLD` with
+    /// `compression`.
+    ///
+    /// With `CompressNone`, the default, replies are compressed like the
+    /// request they answer. Compressed requests are always decompressed.
+    pub fn set_compression(&mut self, compression: CompressType) {
+        self.compression = compression;
+    }
+
+    /// Copies the metadata `keys` of every request, e.g. trace and span
+    /// ids, into its reply. Metadata a handler sets with
+    /// `Context::set_reply_metadata` takes precedence.
+    pub fn set_echoed_metadata(&mut self, keys: &[&str]) {
+        self.echoed_metadata = Arc::new(keys.iter().map(|k| k.to_string()).collect());
+    }
+
+    fn dispatcher(&self) -> Dispatcher {
+        Dispatcher {
+            services: self.services.clone(),
+            streams: self.streams.clone(),
+            uploads: self.uploads.clone(),
+            messages: self.messages.clone(),
+            draining: self.draining.clone(),
+            invoke_plugins: self.invoke_plugins.clone(),
+            metrics: self.metrics.clone(),
+            compression: self.compression,
+            echoed_metadata: self.echoed_metadata.clone(),
+            not_found: self.not_found.clone(),
+            default_handler: self.default_handler.clone(),
         }
-        Server {
-            addr: s,
-            services: Arc::new(RwLock::new(HashMap::new())),
-            thread_number,
-            register_plugins: Arc::new(RwLock::new(Vec::new())),
-            connect_plugins: Arc::new(RwLock::new(Vec::new())),
-            raw_fd: None,
+    }
+
+    fn conn_config(&self) -> ConnConfig {
+        ConnConfig {
+            read_timeout: self.read_timeout,
+            idle_timeout: self.idle_timeout,
+            nodelay: self.nodelay,
+            buffer_size: self.buffer_size,
+            max_message_size: self.max_message_size,
+            max_pending: self.max_pending_per_conn,
+            dispatch_mode: self.dispatch_mode,
         }
     }
 
+    /// Registers `f` as `service_path.service_method`.
+    ///
+    /// All register plugins are invoked first and the first error returned
+    /// by one of them aborts the registration, so the service is only
+    /// reachable once every plugin has accepted it. The plugins that did
+    /// accept it are told to unregister it then, unless it was registered
+    /// already.
+    ///
+    /// Neither name may be empty or contain whitespace or control
+    /// characters; such names are refused with an error.
     pub fn register_fn(
         &mut self,
         service_path: String,
         service_method: String,
         meta: String,
         f: RpcxFn,
-    ) {
-        // invoke register plugins
-        let mut plugins = self.register_plugins.write().unwrap();
-        for p in plugins.iter_mut() {
-            let pp = &mut **p;
-            match pp.register_fn(
-                service_path.as_str(),
-                service_method.as_str(),
-                meta.clone(),
-                f,
-            ) {
-                Ok(_) => {}
-                Err(err) => eprintln!("{}", err),
-            }
-        }
+    ) -> Result<()> {
+        let handler: RpcxHandler = Box::new(move |_, x, st| f(x, st));
+        self.register_handler(service_path, service_method, meta, handler)
+    }
 
-        // invoke service
-        let key = format!("{}.{}", service_path, service_method);
-        let services = self.services.clone();
-        let mut map = services.write().unwrap();
-        map.insert(key, Box::new(f));
+    /// Like `register_fn`, for functions that need the request `Context`.
+    pub fn register_ctx_fn(
+        &mut self,
+        service_path: String,
+        service_method: String,
+        meta: String,
+        f: RpcxCtxFn,
+    ) -> Result<()> {
+        self.register_handler(service_path, service_method, meta, Box::new(f))
     }
 
-    pub fn get_fn(&self, service_path: String, service_method: String) -> Option<RpcxFn> {
+    /// Registers `f` as `service_path.service_method` for raw payloads. It
+    /// gets the payload of requests with `SerializeType::SerializeNone` as
+    /// is and its result is sent back as is, without any codec. Requests
+    /// with another serialize type are answered with an error.
+    pub fn register_raw_fn<F>(
+        &mut self,
+        service_path: String,
+        service_method: String,
+        meta: String,
+        f: F,
+    ) -> Result<()>
+    where
+        F: Fn(&[u8]) -> Result<Vec<u8>> + Send + Sync + 'static,
+    {
         let key = format!("{}.{}", service_path, service_method);
-        let map = self.services.read().unwrap();
-        let box_fn = map.get(&key)?;
-        Some(**box_fn)
+        let handler: RpcxHandler = Box::new(move |_, x, st| match st {
+            SerializeType::SerializeNone => f(x),
+            _ => Err(Error::new(
+                ErrorKind::Other,
+                format!("{} only takes raw payloads", key),
+            )),
+        });
+        self.register_handler(service_path, service_method, meta, handler)
+    }
+
+    /// Registers `f` as `service_path.service_method`, getting the whole
+    /// request, header and metadata included, and returning the whole
+    /// reply, e.g. to dispatch on its own. The reply is written as is, so
+    /// it should be started with `Message::get_reply` to keep the seq the
+    /// client waits for; only the server's compression of large payloads,
+    /// the echoed metadata and the checks other handlers get, like
+    /// `DEADLINE`, still apply. An `Err` is sent back as `SERVICE_ERROR`.
+    ///
+    /// Only the threaded server calls these handlers; an `AsyncServer`
+    /// sharing the services answers with an error.
+    pub fn register_message_fn<F>(
+        &mut self,
+        service_path: String,
+        service_method: String,
+        meta: String,
+        f: F,
+    ) -> Result<()>
+    where
+        F: Fn(&Message) -> Result<Message> + Send + Sync + 'static,
+    {
+        self.registry()
+            .register_message_fn(service_path, service_method, meta, f)
+    }
+
+    /// Registers a handler which, unlike `RpcxFn`, can carry its own state.
+    pub fn register_handler(
+        &mut self,
+        service_path: String,
+        service_method: String,
+        meta: String,
+        handler: RpcxHandler,
+    ) -> Result<()> {
+        self.registry()
+            .register_handler(service_path, service_method, meta, handler)
+    }
+
+    /// Returns a handle registering and unregistering services from other
+    /// threads, also once the server serves and can't be borrowed mutably.
+    pub fn registry(&self) -> ServiceRegistry {
+        ServiceRegistry {
+            services: self.services.clone(),
+            streams: self.streams.clone(),
+            uploads: self.uploads.clone(),
+            messages: self.messages.clone(),
+            metas: self.metas.clone(),
+            register_plugins: self.register_plugins.clone(),
+        }
+    }
+
+    pub fn get_fn(&self, service_path: String, service_method: String) -> Option<Arc<RpcxHandler>> {
+        let map = read_lock(&self.services);
+        map.get(&(service_path, service_method)).cloned()
+    }
+
+    /// Returns the `(service_path, service_method)` of every registered
+    /// service, in no particular order.
+    pub fn list_services(&self) -> Vec<(String, String)> {
+        let map = read_lock(&self.services);
+        map.keys().cloned().collect()
+    }
+
+    /// Returns the meta `service_path.service_method` was registered with.
+    pub fn service_meta(&self, service_path: &str, service_method: &str) -> Option<String> {
+        let metas = read_lock(&self.metas);
+        metas
+            .get(&(service_path.to_owned(), service_method.to_owned()))
+            .cloned()
     }
 
+    pub fn service_count(&self) -> usize {
+        read_lock(&self.services).len()
+    }
+
+    /// Makes `service_path.service_method` answer every call with a
+    /// "service draining" error while `draining` is set, e.g. so clients
+    /// fail over before it is unregistered. Other services keep working.
+    pub fn set_draining(&self, service_path: &str, service_method: &str, draining: bool) {
+        dispatch::set_draining(&self.draining, service_path, service_method, draining);
+    }
+
+    /// Removes `service_path.service_method` and returns its handler.
+    ///
+    /// The register plugins are told first, so discovery backends stop
+    /// announcing the service before it is removed. Their errors are only
+    /// logged; the service is removed regardless.
+    pub fn unregister_fn(
+        &mut self,
+        service_path: String,
+        service_method: String,
+    ) -> Option<Arc<RpcxHandler>> {
+        self.registry().unregister_fn(service_path, service_method)
+    }
+
+    /// Serves `msg` on the calling thread as if it was read from a
+    /// connection and returns its reply, if it gets one, e.g. to measure
+    /// dispatching without a client. Stream and upload handlers need a
+    /// connection, so their requests get an error reply.
+    pub fn dispatch(&self, msg: &Message) -> Option<Message> {
+        if msg.is_heartbeat() {
+            return heartbeat_reply(msg);
+        }
+        self.dispatcher().dispatch(None, msg)
+    }
+
+    /// Serves the connections `listener` accepts until the server is
+    /// closed. Failed accepts are logged and retried, waiting a little
+    /// longer for every one in a row that isn't about the connection being
+    /// accepted; only an error meaning `listener` can't accept anymore is
+    /// returned.
     pub fn start_with_listener(&self, listener: TcpListener) -> Result<()> {
-        let thread_number = self.thread_number;
+        self.init_register_plugins(&listener)?;
+        self.serve(listener.incoming(), Ok)
+    }
+
+    /// Remembers the address of `listener` and hands it to the register
+    /// plugins.
+    fn init_register_plugins(&self, listener: &TcpListener) -> Result<()> {
+        let addr = listener.local_addr()?;
+        let mut local_addrs = lock(&self.local_addrs);
+        if !local_addrs.contains(&addr) {
+            local_addrs.push(addr);
+        }
+        drop(local_addrs);
+
+        let mut plugins = write_lock(&self.register_plugins);
+        for p in plugins.iter_mut() {
+            p.init(addr)?;
+        }
+        Ok(())
+    }
 
-        'accept_loop: for stream in listener.incoming() {
+    /// Runs the accept loop, turning every accepted stream into the
+    /// transport its requests are read from with `wrap`.
+    ///
+    /// Accept errors are logged and accepting goes on, after a wait if they
+    /// may last, see `accept_delay`. Only an error meaning the listener
+    /// can't accept anymore is returned.
+    fn serve<C, S, I, F>(&self, incoming: I, wrap: F) -> Result<()>
+    where
+        C: Socket,
+        S: Transport,
+        I: Iterator<Item = io::Result<C>>,
+        F: Fn(C) -> io::Result<S>,
+    {
+        let config = self.conn_config();
+
+        let mut delay = Duration::ZERO;
+        for stream in incoming {
+            if self.closing.load(Ordering::SeqCst) {
+                break;
+            }
             match stream {
                 Ok(stream) => {
-                    let services_cloned = self.services.clone();
-                    thread::spawn(move || {
-                        Server::process(thread_number, services_cloned, stream);
+                    delay = Duration::ZERO;
+                    let peer = stream.peer_addr().ok();
+                    if let Some(peer) = peer {
+                        if let Err(err) = self.handle_connect(peer) {
+                            info!("client {} is rejected: {}", peer, err);
+                            continue;
+                        }
+                    }
+                    if let Err(err) = stream.set_read_timeout(config.socket_timeout()) {
+                        warn!("failed to set read timeout: {}", err);
+                    }
+                    if let Err(err) = stream.set_nodelay(config.nodelay) {
+                        warn!("failed to set nodelay: {}", err);
+                    }
+                    if let Some(keepalive) = &self.keepalive {
+                        if let Err(err) = stream.set_keepalive(keepalive) {
+                            warn!("failed to set keepalive: {}", err);
+                        }
+                    }
+                    let active = self.active_connections.fetch_add(1, Ordering::SeqCst);
+                    if self.max_connections > 0 && active >= self.max_connections {
+                        self.active_connections.fetch_sub(1, Ordering::SeqCst);
+                        warn!(
+                            "too many connections ({}), closing a new one",
+                            self.max_connections
+                        );
+                        continue;
+                    }
+
+                    let (reader, writer) = match wrap(stream).and_then(Transport::split) {
+                        Ok(halves) => halves,
+                        Err(err) => {
+                            self.active_connections.fetch_sub(1, Ordering::SeqCst);
+                            error!("failed to set up connection: {}", err);
+                            continue;
+                        }
+                    };
+                    // `shutdown` closes the connection through the same
+                    // writer the replies go through.
+                    let writer = Arc::new(writer);
+                    let tracked_writer: Arc<dyn WriteHalf> = writer.clone();
+
+                    let on_connect = peer.zip(self.on_connect.clone());
+                    let on_disconnect = peer.zip(self.on_disconnect.clone());
+
+                    let conn_events = self.conn_events.clone();
+                    let dispatcher = self.dispatcher();
+                    let active_connections = self.active_connections.clone();
+                    let pool = self.pool.clone();
+                    let name = match peer {
+                        Some(peer) => format!("rpcx-conn-{}", peer),
+                        None => "rpcx-conn".to_owned(),
+                    };
+                    let spawned = thread::Builder::new().name(name).spawn(move || {
+                        if let Some((peer, on_connect)) = on_connect {
+                            on_connect(peer);
+                        }
+                        if let Some(events) = &conn_events {
+                            let _ = events.send(ConnEvent::Opened { peer });
+                        }
+                        Server::process(config, dispatcher, pool, reader, writer, &conn_events);
+                        active_connections.fetch_sub(1, Ordering::SeqCst);
+                        if let Some((peer, on_disconnect)) = on_disconnect {
+                            on_disconnect(peer);
+                        }
+                        if let Some(events) = &conn_events {
+                            let _ = events.send(ConnEvent::Closed { peer });
+                        }
                     });
+                    let handle = match spawned {
+                        Ok(handle) => handle,
+                        Err(err) => {
+                            self.active_connections.fetch_sub(1, Ordering::SeqCst);
+                            error!("failed to spawn connection thread: {}", err);
+                            continue;
+                        }
+                    };
+
+                    let mut connections = lock(&self.connections);
+                    connections.retain(|(_, handle)| !handle.is_finished());
+                    connections.push((tracked_writer, handle));
                 }
-                Err(e) => {
-                    //println!("Unable to accept: {}", e);
-                    return Err(Error::new(ErrorKind::Network, e));
-                }
+                Err(e) => match accept_delay(&e, delay) {
+                    Some(next) => {
+                        warn!("failed to accept, retrying in {:?}: {}", next, e);
+                        delay = next;
+                        thread::sleep(delay);
+                    }
+                    None => {
+                        error!("Unable to accept: {}", e);
+                        return Err(Error::new(ErrorKind::Network, e));
+                    }
+                },
             }
         }
 
         Ok(())
     }
-    #[cfg(target_os = "windows")]
-    pub fn start(&mut self) -> Result<()> {
-        let addr = self
-            .addr
-            .parse::<SocketAddr>()
-            .map_err(|err| Error::new(ErrorKind::Other, err))?;
 
-        let listener = TcpListener::bind(&addr)?;
-        println!("Listening on: {}", addr);
-        self.raw_fd = Some(listener.as_raw_socket());
+    /// Asks every connect plugin whether `peer` may connect.
+    fn handle_connect(&self, peer: SocketAddr) -> Result<()> {
+        let plugins = read_lock(&self.connect_plugins);
+        for p in plugins.iter() {
+            p.handle_connect(peer)?;
+        }
+        Ok(())
+    }
+
+    /// Stops accepting connections like `close` and drains the open ones.
+    ///
+    /// Open connections stop reading new requests but finish the ones in
+    /// flight; whatever is still running after the grace period is shut
+    /// down.
+    pub fn shutdown(&self) {
+        // `close` logs the errors of the register plugins
+        let _ = self.close();
 
-        self.start_with_listener(listener)
+        let connections: Vec<_> = lock(&self.connections).drain(..).collect();
+        for (stream, _) in connections.iter() {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+
+        let deadline = Instant::now() + self.grace_period;
+        while Instant::now() < deadline
+            && connections.iter().any(|(_, handle)| !handle.is_finished())
+        {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        for (stream, handle) in connections {
+            if handle.is_finished() {
+                let _ = handle.join();
+            } else {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
     }
 
-    #[cfg(not(target_os = "windows"))]
+    /// Binds `addr` and serves connections until the server is shut down.
+    /// `bind` and `start_with_listener` do the same in two steps.
     pub fn start(&mut self) -> Result<()> {
+        let listener = self.bind()?;
+        self.start_with_listener(listener)
+    }
+
+    /// Like `start`, but listens on every address `addrs` resolves to, e.g.
+    /// on both an IPv4 and an IPv6 address, and ignores `addr`.
+    ///
+    /// Every listener gets its own accept loop. Returns once all of them
+    /// have stopped, with the first error one of them stopped with.
+    pub fn start_on<A: ToSocketAddrs>(&self, addrs: A) -> Result<()> {
+        let mut listeners = Vec::new();
+        for addr in addrs.to_socket_addrs()? {
+            listeners.push(self.listen_on(addr)?);
+        }
+        if listeners.is_empty() {
+            return Err(Error::from("no address to listen on"));
+        }
+
+        let server = self;
+        thread::scope(|s| {
+            let loops: Vec<_> = listeners
+                .into_iter()
+                .map(|listener| {
+                    let name = match listener.local_addr() {
+                        Ok(addr) => format!("rpcx-accept-{}", addr),
+                        Err(_) => "rpcx-accept".to_owned(),
+                    };
+                    thread::Builder::new()
+                        .name(name)
+                        .spawn_scoped(s, move || server.start_with_listener(listener))
+                })
+                .collect();
+            loops
+                .into_iter()
+                .map(|handle| match handle {
+                    Ok(handle) => handle
+                        .join()
+                        .unwrap_or_else(|_| Err(Error::from("accept loop panicked"))),
+                    Err(err) => Err(Error::new(ErrorKind::Other, err)),
+                })
+                .fold(Ok(()), Result::and)
+        })
+    }
+
+    /// Binds `addr` and returns the listener, to be served by
+    /// `start_with_listener`, so the server can be bound before it is sent
+    /// to the thread running its accept loop.
+    ///
+    /// The caller owns the listener. `close` and dropping the server only
+    /// stop accept loops, which drop the listener they serve; a listener
+    /// that is never served stays open until the caller drops it.
+    ///
+    /// ```no_run
+    /// # use rpcx_server::Server;
+    /// # use std::thread;
+    /// let mut server = Server::new("127.0.0.1:0".to_owned(), 0);
+    /// let listener = server.bind().unwrap();
+    /// let addr = listener.local_addr().unwrap();
+    /// thread::spawn(move || server.start_with_listener(listener));
+    /// ```
+    pub fn bind(&mut self) -> Result<TcpListener> {
         let addr = self
             .addr
             .parse::<SocketAddr>()
             .map_err(|err| Error::new(ErrorKind::Other, err))?;
+        self.listen_on(addr)
+    }
 
-        let listener = TcpListener::bind(&addr)?;
-        println!("Listening on: {}", addr);
-        self.raw_fd = Some(listener.as_raw_fd());
-        println!("{}", self.raw_fd);
-        self.start_with_listener(listener)
+    fn listen_on(&self, addr: SocketAddr) -> Result<TcpListener> {
+        let socket = socket2::Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+        #[cfg(not(target_os = "windows"))]
+        socket.set_reuse_address(self.reuse_addr)?;
+        if self.reuse_port {
+            #[cfg(not(any(target_os = "windows", target_os = "solaris", target_os = "illumos")))]
+            socket.set_reuse_port(true)?;
+            #[cfg(any(target_os = "windows", target_os = "solaris", target_os = "illumos"))]
+            return Err(Error::from(
+                "SO_REUSEPORT is not supported on this platform",
+            ));
+        }
+        socket.bind(&addr.into())?;
+        socket.listen(self.backlog)?;
+
+        let listener: TcpListener = socket.into();
+        let local_addr = listener.local_addr()?;
+        lock(&self.local_addrs).push(local_addr);
+        info!("Listening on: {}", local_addr);
+        Ok(listener)
+    }
+
+    /// Returns the address the server listens on once it is bound, e.g. to
+    /// learn the port picked for port 0. With several listeners, this is
+    /// the first one.
+    pub fn bound_addr(&self) -> Option<SocketAddr> {
+        lock(&self.local_addrs).first().copied()
     }
 
-    pub fn close(&self) {
-        if let Some(raw_fd) = self.raw_fd {
-            unsafe {
-                libc::close(raw_fd as i32);
+    /// Stops accepting connections, leaving the open ones alone.
+    ///
+    /// The register plugins are stopped first so clients stop picking this
+    /// server, and the first error one of them returns is returned once
+    /// the rest are stopped. Every accept loop is then woken up by a
+    /// loopback connection, sees the server is closing and returns, which
+    /// drops and so closes its listener. Closing again does nothing.
+    pub fn close(&self) -> Result<()> {
+        if self.closing.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let mut res = Ok(());
+        let mut plugins = write_lock(&self.register_plugins);
+        for p in plugins.iter_mut() {
+            if let Err(err) = p.stop() {
+                warn!("failed to stop register plugin: {}", err);
+                if res.is_ok() {
+                    res = Err(err);
+                }
             }
         }
+        drop(plugins);
+
+        for addr in lock(&self.local_addrs).iter() {
+            let _ = TcpStream::connect(wakeup_addr(*addr));
+        }
+        #[cfg(not(target_os = "windows"))]
+        if let Some(path) = &*lock(&self.unix_path) {
+            let _ = std::os::unix::net::UnixStream::connect(path);
+        }
+        res
     }
-    fn process(
-        thread_number: u32,
-        service: Arc<RwLock<HashMap<String, Box<RpcxFn>>>>,
-        stream: TcpStream,
+
+    /// Reads the requests of a connection and hands them to `pool`.
+    fn process<R: Read, W: WriteHalf + 'static>(
+        config: ConnConfig,
+        dispatcher: Dispatcher,
+        pool: ThreadPool,
+        reader: R,
+        writer: Arc<W>,
+        events: &Option<mpsc::Sender<ConnEvent>>,
     ) {
-        let services_cloned = service;
-        let local_stream = stream.try_clone().unwrap();
-
-        let mut pool = Pool::new(thread_number);
-        pool.scoped(|scoped| {
-            let mut reader = BufReader::new(stream.try_clone().unwrap());
-            loop {
-                let mut msg = Message::new();
-                match msg.decode(&mut reader) {
-                    Ok(()) => {
-                        let service_path = &msg.service_path;
-                        let service_method = &msg.service_method;
-                        let key = format!("{}.{}", service_path, service_method);
-                        let map = &services_cloned.read().unwrap();
-                        match map.get(&key) {
-                            Some(box_fn) => {
-                                let f = **box_fn;
-                                let local_stream_in_child = local_stream.try_clone().unwrap();
-
-                                scoped.execute(move || {
-                                    invoke_fn(local_stream_in_child.try_clone().unwrap(), msg, f)
-                                });
-                            }
-                            None => {
-                                let err = format!("service {} not found", key);
-                                let mut reply_msg = msg.get_reply().unwrap();
-                                let mut metadata = reply_msg.metadata.borrow_mut();
-                                (*metadata).insert(SERVICE_ERROR.to_string(), err);
-                                drop(metadata);
-                                reply_msg.set_message_status_type(MessageStatusType::Error);
-                                let data = reply_msg.encode();
-                                let mut writer = BufWriter::new(local_stream.try_clone().unwrap());
-                                writer.write_all(&data).unwrap();
-                                writer.flush().unwrap();
-                            }
+        let peer_addr = writer.peer_addr().ok();
+        let dispatcher = Arc::new(dispatcher);
+        let replies = Arc::new(Mutex::new(BufWriter::with_capacity(
+            config.buffer_size,
+            ReplyWriter(writer.clone()),
+        )));
+
+        // every in-flight request holds a sender, so `recv` returns once
+        // all of them are done.
+        let (in_flight, all_done) = mpsc::channel::<()>();
+        let pending = Arc::new(Pending::new(config.max_pending));
+
+        let mut timed_out = None;
+        let mut last_request = Instant::now();
+        let mut reader = BufReader::with_capacity(config.buffer_size, reader);
+        loop {
+            if let Some(idle_timeout) = config.idle_timeout {
+                // wait for the next request without consuming any of it, so
+                // a timeout leaves the stream intact
+                match reader.fill_buf() {
+                    Ok(_) => {}
+                    Err(err) if is_io_timeout(&err) => {
+                        if last_request.elapsed() < idle_timeout {
+                            continue;
                         }
+                        timed_out = Some("idle");
+                        break;
                     }
                     Err(err) => {
-                        eprintln!("failed to read: {}", err.to_string());
-                        match local_stream.shutdown(Shutdown::Both) {
-                            Ok(()) => {
-                                if let Ok(sa) = local_stream.peer_addr() {
-                                    println!("client {} is closed", sa)
-                                }
-                            }
-                            Err(e) => {
-                                if let Ok(sa) = local_stream.peer_addr() {
-                                    println!("client {} is closed. err: {}", sa, e)
-                                }
-                            }
+                        error!("failed to read: {}", err);
+                        report_error(events, peer_addr, err.into());
+                        break;
+                    }
+                }
+            }
+
+            let mut msg = Message::new();
+            let res = if dispatcher.has_uploads() {
+                read_request(
+                    &mut reader,
+                    &mut msg,
+                    &config,
+                    &replies,
+                    peer_addr,
+                    &dispatcher,
+                )
+            } else {
+                msg.decode_with_limit(&mut reader, config.max_message_size)
+                    .map(|()| true)
+            };
+            if res.is_ok() {
+                last_request = Instant::now();
+            }
+            match res {
+                // an upload, already served
+                Ok(false) => {}
+                Ok(true) if msg.is_heartbeat() => {
+                    if let Some(reply_msg) = heartbeat_reply(&msg) {
+                        if let Err(err) = write_reply(&replies, peer_addr, &reply_msg.encode()) {
+                            report_error(events, peer_addr, err.into());
+                            break;
+                        }
+                    }
+                }
+                Ok(true) if config.dispatch_mode == DispatchMode::Inline => {
+                    if let Err(err) = invoke_fn(&replies, peer_addr, msg, &dispatcher) {
+                        report_error(events, peer_addr, err.into());
+                        break;
+                    }
+                }
+                Ok(true) => {
+                    pending.acquire();
+                    if let Some(metrics) = &dispatcher.metrics {
+                        if pool.active_count() + pool.queued_count() >= pool.max_count() {
+                            metrics.record_pool_saturated();
+                        }
+                    }
+                    let dispatcher = dispatcher.clone();
+                    let replies = replies.clone();
+                    let in_flight = in_flight.clone();
+                    let pending = pending.clone();
+
+                    pool.execute(move || {
+                        // a connection that can't be written to anymore is
+                        // shut down, which stops the read loop
+                        let _ = invoke_fn(&replies, peer_addr, msg, &dispatcher);
+                        pending.release();
+                        drop(in_flight);
+                    });
+                }
+                // the frame was read completely, so the next one can still
+                // be served
+                Err(err) if err.kind() == ErrorKind::Protocol => {
+                    warn!("failed to decode request: {}", err);
+                    let reply_msg = decode_error_reply(&msg, &err);
+                    report_error(events, peer_addr, err);
+                    if let Some(reply_msg) = reply_msg {
+                        if let Err(err) = write_reply(&replies, peer_addr, &reply_msg.encode()) {
+                            report_error(events, peer_addr, err.into());
+                            break;
+                        }
+                    }
+                }
+                Err(err) => {
+                    if is_timeout(&err) {
+                        timed_out = Some("read");
+                    } else {
+                        error!("failed to read: {}", err);
+                        if !is_eof(&err) {
+                            report_error(events, peer_addr, err);
                         }
-                        return;
                     }
+                    break;
                 }
             }
-        });
+        }
+
+        drop(in_flight);
+        let _ = all_done.recv();
+
+        // the in-flight requests are done, so their replies have been
+        // written by now.
+        match writer.shutdown(Shutdown::Both) {
+            Ok(()) => {
+                if let Ok(sa) = writer.peer_addr() {
+                    match timed_out {
+                        Some(timeout) => info!("client {} is closed after {} timeout", sa, timeout),
+                        None => info!("client {} is closed", sa),
+                    }
+                }
+            }
+            Err(e) => {
+                if let Ok(sa) = writer.peer_addr() {
+                    warn!("client {} is closed. err: {}", sa, e)
+                }
+            }
+        }
     }
 }
 
-fn invoke_fn(stream: TcpStream, msg: Message, f: RpcxFn) {
-    let mut reply_msg = msg.get_reply().unwrap();
-    let res = f(&msg.payload, msg.get_serialize_type().unwrap());
-    match res {
-        Ok(reply) => {
-            reply_msg.payload = reply;
-        }
-        Err(err) => {
-            let mut metadata = reply_msg.metadata.borrow_mut();
-            (*metadata).insert(SERVICE_ERROR.to_string(), err.to_string());
-            drop(metadata);
-            reply_msg.set_message_status_type(MessageStatusType::Error);
-        }
+/// Dropping a server closes it like `close`, unless it is closed already,
+/// so the register plugins deregister its services.
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.close();
     }
+}
 
-    let data = reply_msg.encode();
+/// Counts the requests of a connection that are pending in the pool.
+struct Pending {
+    max: usize,
+    count: Mutex<usize>,
+    released: Condvar,
+}
 
-    let mut writer = BufWriter::new(stream.try_clone().unwrap());
+impl Pending {
+    /// `max` of `0` means unlimited.
+    fn new(max: usize) -> Self {
+        Pending {
+            max,
+            count: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
 
-    match writer.write_all(&data) {
-        Ok(()) => {}
-        Err(_err) => {}
+    /// Waits until fewer than `max` requests are pending and adds one.
+    fn acquire(&self) {
+        let mut count = self.count.lock().unwrap();
+        while self.max > 0 && *count >= self.max {
+            count = self.released.wait(count).unwrap();
+        }
+        *count += 1;
     }
-    match writer.flush() {
-        Ok(()) => {}
-        Err(_err) => {}
+
+    fn release(&self) {
+        *self.count.lock().unwrap() -= 1;
+        self.released.notify_one();
     }
 }
 
-#[macro_export]
-macro_rules! register_func {
-    ($rpc_server:expr, $tokio_rt:expr, $service_path:expr, $service_method:expr, $service_fn:expr, $meta:expr, $arg_type:ty) => {{
-        let f: RpcxFn = |x, st| {
-            // TODO change ProtoArgs to $arg_typ
-            let mut args: $arg_type = Default::default();
-            args.from_slice(st, x)?;
-            let res = $tokio_rt.block_on($service_fn(args));
-            match res{
-                Ok(res)=>{res.into_bytes(st)},
-                Err(e)=>{Err(rpcx::Error::new(rpcx::ErrorKind::Server, e.to_string()))}
+/// Adapts a `WriteHalf` to `Write`, so replies can be buffered.
+struct ReplyWriter<W>(Arc<W>);
+
+impl<W: WriteHalf> Write for ReplyWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            match self.0.write_reply(buf) {
+                Ok(()) => return Ok(buf.len()),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The replies of a connection are written under one lock, so replies of
+/// concurrent requests can't interleave on the wire.
+type Replies<W> = Mutex<BufWriter<ReplyWriter<W>>>;
+
+/// Reads the next request like `Message::decode_with_limit`, except that a
+/// request for an upload handler is served right away, passing its payload
+/// to the handler as it is read. Returns whether `msg` is still to be
+/// served, which it isn't once it was an upload.
+fn read_request<R: Read, W: WriteHalf>(
+    reader: &mut R,
+    msg: &mut Message,
+    config: &ConnConfig,
+    replies: &Replies<W>,
+    peer_addr: Option<SocketAddr>,
+    dispatcher: &Dispatcher,
+) -> Result<bool> {
+    let len = msg.decode_head(reader, config.max_message_size)?;
+    let handler = match dispatcher.upload_handler(msg) {
+        Some(handler) if !msg.is_heartbeat() => handler,
+        _ => {
+            if len > config.max_message_size {
+                return Err(Error::new(
+                    ErrorKind::MessageTooLarge,
+                    format!(
+                        "payload size {} exceeds the limit of {}",
+                        len, config.max_message_size
+                    ),
+                ));
+            }
+            msg.read_payload(reader, len)?;
+            return Ok(true);
+        }
+    };
+
+    let mut payload = reader.take(len as u64);
+    let reply_msg = {
+        let mut body = msg.payload_reader(&mut payload)?;
+        dispatcher.dispatch_upload(peer_addr, msg, len, handler, &mut *body)
+    };
+    // skip what the handler didn't read, so the next request can be read
+    io::copy(&mut payload, &mut io::sink())?;
+    if payload.limit() > 0 {
+        return Err(Error::from("truncated message"));
+    }
+    if let Some(reply_msg) = reply_msg {
+        write_reply(replies, peer_addr, &reply_msg.encode())?;
+    }
+    Ok(false)
+}
+
+/// Handles a request and writes its reply, failing if the reply, served or
+/// not found alike, could not be written.
+fn invoke_fn<W: WriteHalf>(
+    replies: &Replies<W>,
+    peer_addr: Option<SocketAddr>,
+    msg: Message,
+    dispatcher: &Dispatcher,
+) -> io::Result<()> {
+    let reply_msg = match dispatcher.stream_handler(&msg) {
+        Some(handler) => {
+            let mut write = |data: &[u8]| write_reply(replies, peer_addr, data);
+            dispatcher.dispatch_stream(peer_addr, &msg, handler, &mut write)
+        }
+        None => dispatcher.dispatch(peer_addr, &msg),
+    };
+    match reply_msg {
+        Some(reply_msg) => write_reply(replies, peer_addr, &reply_msg.encode()),
+        None => Ok(()),
+    }
+}
+
+/// Writes and flushes an encoded reply, the one way replies get to the
+/// client. Interrupted writes are retried; other errors are logged and
+/// returned.
+fn write_reply<W: WriteHalf>(
+    replies: &Replies<W>,
+    peer_addr: Option<SocketAddr>,
+    data: &[u8],
+) -> io::Result<()> {
+    let mut replies = replies.lock().unwrap();
+    let res = replies.write_all(data).and_then(|()| replies.flush());
+    if let Err(err) = &res {
+        match peer_addr {
+            Some(sa) => warn!("failed to reply to {}: {}", sa, err),
+            None => warn!("failed to reply: {}", err),
+        }
+        // nobody reads the replies anymore; shutting the connection down
+        // wakes the read loop up so it stops reading requests too.
+        if is_disconnect(err) {
+            let _ = replies.get_ref().0.shutdown(Shutdown::Both);
+        }
+    }
+    res
+}
+
+fn is_disconnect(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+    )
+}
+
+/// Returns how long the accept loop waits before accepting again after
+/// `err`, given the wait after the error before it in a row, or `None` if
+/// `err` means the listener can't accept anymore.
+///
+/// A connection failing before it is accepted doesn't affect the next one,
+/// which is accepted right away. Other errors, e.g. running out of file
+/// descriptors, may last until connections are closed, so they are waited
+/// out instead of being retried in a busy loop.
+pub(crate) fn accept_delay(err: &io::Error, last: Duration) -> Option<Duration> {
+    match err.kind() {
+        io::ErrorKind::InvalidInput => None,
+        io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::Interrupted
+        | io::ErrorKind::WouldBlock => Some(Duration::ZERO),
+        _ => Some((last * 2).clamp(MIN_ACCEPT_DELAY, MAX_ACCEPT_DELAY)),
+    }
+}
+
+/// Returns an address a loopback connection to `addr` can be made on, since
+/// connecting to an unspecified address is not portable.
+fn wakeup_addr(mut addr: SocketAddr) -> SocketAddr {
+    if addr.ip().is_unspecified() {
+        match addr {
+            SocketAddr::V4(_) => addr.set_ip(Ipv4Addr::LOCALHOST.into()),
+            SocketAddr::V6(_) => addr.set_ip(Ipv6Addr::LOCALHOST.into()),
+        }
+    }
+    addr
+}
+
+fn is_timeout(err: &Error) -> bool {
+    match err.get_ref().and_then(|e| e.downcast_ref::<io::Error>()) {
+        Some(e) => is_io_timeout(e),
+        None => false,
+    }
+}
+
+/// Whether reading failed because the client closed the connection.
+fn is_eof(err: &Error) -> bool {
+    match err.get_ref().and_then(|e| e.downcast_ref::<io::Error>()) {
+        Some(e) => e.kind() == io::ErrorKind::UnexpectedEof,
+        None => false,
+    }
+}
+
+/// Sends `err` to the connection events, if there are any.
+fn report_error(events: &Option<mpsc::Sender<ConnEvent>>, peer: Option<SocketAddr>, err: Error) {
+    if let Some(events) = events {
+        let _ = events.send(ConnEvent::Error { peer, err });
+    }
+}
+
+fn is_io_timeout(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut
+}
+
+#[macro_export]
+macro_rules! register_func {
+    ($rpc_server:expr, $tokio_rt:expr, $service_path:expr, $service_method:expr, $service_fn:expr, $meta:expr, $arg_type:ty) => {{
+        let f: RpcxFn = |x, st| {
+            // TODO change ProtoArgs to $arg_typ
+            let mut args: $arg_type = Default::default();
+            args.from_slice(st, x)?;
+            let res = $tokio_rt.block_on($service_fn(args));
+            match res {
+                Ok(res) => res.into_bytes(st),
+                Err(e) => Err(rpcx::Error::new(rpcx::ErrorKind::Server, e.to_string())),
             }
         };
         $rpc_server.register_fn(
@@ -258,6 +1358,1603 @@ macro_rules! register_func {
             $service_method.to_string(),
             $meta,
             f,
-        );
+        )
     }};
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A JSON request for `service_path.service_method`.
+    fn request(service_path: &str, service_method: &str) -> Message {
+        let mut msg = Message::new();
+        msg.set_serialize_type(SerializeType::JSON);
+        msg.service_path = service_path.to_owned();
+        msg.service_method = service_method.to_owned();
+        msg
+    }
+
+    /// Serves `server` on a loopback port from another thread and returns
+    /// it shared with that thread, so the test can shut it down, with the
+    /// address it listens on and the thread running its accept loop.
+    fn serve(mut server: Server) -> (Arc<Server>, SocketAddr, JoinHandle<Result<()>>) {
+        let listener = server.bind().unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Arc::new(server);
+        let s = server.clone();
+        let handle = thread::spawn(move || s.start_with_listener(listener));
+        (server, addr, handle)
+    }
+
+    /// Shuts down a server started by `serve` and waits for it to stop.
+    fn stop(server: &Server, handle: JoinHandle<Result<()>>) {
+        server.shutdown();
+        handle.join().unwrap().unwrap();
+    }
+
+    /// Writes `msg` to `conn` and reads the reply.
+    fn call(conn: &mut TcpStream, msg: &Message) -> Message {
+        conn.write_all(&msg.encode()).unwrap();
+        let mut reply = Message::new();
+        reply.decode(conn).unwrap();
+        reply
+    }
+
+    #[test]
+    fn dotted_names_do_not_collide() {
+        let mut server = Server::new("127.0.0.1:0".to_owned(), 1);
+        let f: RpcxFn = |_, _| Ok(b"a.b/c".to_vec());
+        let g: RpcxFn = |_, _| Ok(b"a/b.c".to_vec());
+        server
+            .register_fn("a.b".to_owned(), "c".to_owned(), String::new(), f)
+            .unwrap();
+        server
+            .register_fn("a".to_owned(), "b.c".to_owned(), String::new(), g)
+            .unwrap();
+        assert_eq!(server.service_count(), 2);
+
+        let ctx = Context::default();
+        let f = server.get_fn("a.b".to_owned(), "c".to_owned()).unwrap();
+        assert_eq!(f(&ctx, &[], SerializeType::JSON).unwrap(), b"a.b/c");
+        let g = server.get_fn("a".to_owned(), "b.c".to_owned()).unwrap();
+        assert_eq!(g(&ctx, &[], SerializeType::JSON).unwrap(), b"a/b.c");
+    }
+
+    #[test]
+    fn register_plugins_follow_registrations() {
+        let plugin = MemoryRegisterPlugin::new();
+        let mut server = Server::new("127.0.0.1:0".to_owned(), 1);
+        server.add_register_plugin(Box::new(plugin.clone()));
+
+        let f: RpcxFn = |x, _| Ok(x.to_vec());
+        for method in &["Add", "Mul"] {
+            server
+                .register_fn("Arith".to_owned(), method.to_string(), "v=1".to_owned(), f)
+                .unwrap();
+        }
+        assert_eq!(
+            plugin.registrations(),
+            vec![
+                ("Arith".to_owned(), "Add".to_owned(), "v=1".to_owned()),
+                ("Arith".to_owned(), "Mul".to_owned(), "v=1".to_owned()),
+            ]
+        );
+
+        server.unregister_fn("Arith".to_owned(), "Add".to_owned());
+        assert_eq!(
+            plugin.registrations(),
+            vec![("Arith".to_owned(), "Mul".to_owned(), "v=1".to_owned())]
+        );
+
+        server.shutdown();
+        assert!(plugin.registrations().is_empty());
+    }
+
+    #[test]
+    fn token_auth_rejects_other_tokens() {
+        let mut server = Server::new("127.0.0.1:0".to_owned(), 1);
+        server.add_invoke_plugin(Box::new(TokenAuthPlugin::new("Bearer secret")));
+        let f: RpcxFn = |x, _| Ok(x.to_vec());
+        server
+            .register_fn("Echo".to_owned(), "Echo".to_owned(), String::new(), f)
+            .unwrap();
+        let dispatcher = server.dispatcher();
+
+        let call = |token: Option<&str>| {
+            let mut msg = request("Echo", "Echo");
+            msg.payload = b"hi".to_vec();
+            if let Some(token) = token {
+                let mut metadata = msg.metadata.borrow_mut();
+                metadata.insert(AUTH_KEY.to_owned(), token.to_owned());
+            }
+            dispatcher.dispatch(None, &msg).unwrap()
+        };
+
+        for token in &[None, Some("Bearer wrong")] {
+            let reply = call(*token);
+            assert_eq!(reply.get_error().as_deref(), Some("unauthorized"));
+            assert!(reply.payload.is_empty());
+        }
+        let reply = call(Some("Bearer secret"));
+        assert_eq!(reply.get_error(), None);
+        assert_eq!(reply.payload, b"hi");
+    }
+
+    #[test]
+    fn rate_limit_rejects_calls_over_the_limit() {
+        let mut limits = HashMap::new();
+        limits.insert("Echo.Slow".to_owned(), 2);
+        let mut server = Server::new("127.0.0.1:0".to_owned(), 1);
+        server.add_invoke_plugin(Box::new(RateLimitPlugin::new(limits)));
+        let f: RpcxFn = |x, _| Ok(x.to_vec());
+        for method in &["Slow", "Fast"] {
+            server
+                .register_fn("Echo".to_owned(), method.to_string(), String::new(), f)
+                .unwrap();
+        }
+        let dispatcher = server.dispatcher();
+
+        let call = |method: &str| {
+            let msg = request("Echo", method);
+            dispatcher.dispatch(None, &msg).unwrap().get_error()
+        };
+
+        assert_eq!(call("Slow"), None);
+        assert_eq!(call("Slow"), None);
+        assert_eq!(call("Slow").as_deref(), Some("rate limited"));
+        for _ in 0..10 {
+            assert_eq!(call("Fast"), None);
+        }
+
+        let mut limits = HashMap::new();
+        limits.insert("Echo.Slow".to_owned(), 0);
+        let err = RateLimitPlugin::new(limits)
+            .pre_call(&request("Echo", "Slow"))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::RateLimited);
+    }
+
+    #[test]
+    fn unknown_serialize_type_gets_an_error_reply() {
+        let mut server = Server::new("127.0.0.1:0".to_owned(), 1);
+        let f: RpcxFn = |x, _| Ok(x.to_vec());
+        server
+            .register_fn("Echo".to_owned(), "Echo".to_owned(), String::new(), f)
+            .unwrap();
+
+        let mut msg = Message::new();
+        msg.header[3] = 0xf0;
+        msg.set_seq(3);
+        msg.service_path = "Echo".to_owned();
+        msg.service_method = "Echo".to_owned();
+        let reply = server.dispatcher().dispatch(None, &msg).unwrap();
+        assert_eq!(reply.get_seq(), 3);
+        assert_eq!(
+            reply.get_error().as_deref(),
+            Some("unsupported serialize type")
+        );
+    }
+
+    #[test]
+    fn typed_handlers_serialize_like_the_request() {
+        #[derive(serde::Deserialize)]
+        struct Args {
+            a: u64,
+            b: u64,
+        }
+        #[derive(serde::Serialize)]
+        struct Reply {
+            c: u64,
+        }
+
+        let offset = 100;
+        let mut server = Server::new("127.0.0.1:0".to_owned(), 1);
+        server
+            .register_typed(
+                "Arith".to_owned(),
+                "Add".to_owned(),
+                String::new(),
+                move |args: Args| Reply {
+                    c: args.a + args.b + offset,
+                },
+            )
+            .unwrap();
+
+        let ctx = Context::default();
+        let f = server.get_fn("Arith".to_owned(), "Add".to_owned()).unwrap();
+        let reply = f(&ctx, br#"{"a":1,"b":2}"#, SerializeType::JSON).unwrap();
+        assert_eq!(reply, br#"{"c":103}"#);
+        let args = [0x82, 0xa1, b'a', 0x01, 0xa1, b'b', 0x02];
+        let reply = f(&ctx, &args, SerializeType::MsgPack).unwrap();
+        assert_eq!(reply, [0x81, 0xa1, b'c', 0x67]);
+        assert!(f(&ctx, b"{", SerializeType::JSON).is_err());
+    }
+
+    #[test]
+    fn fallible_handlers_reply_with_their_error() {
+        let mut server = Server::new("127.0.0.1:0".to_owned(), 1);
+        server
+            .register_fallible(
+                "Arith".to_owned(),
+                "Div".to_owned(),
+                String::new(),
+                |(a, b): (u64, u64)| a.checked_div(b).ok_or("division by zero"),
+            )
+            .unwrap();
+        let dispatcher = server.dispatcher();
+
+        let call = |args: &[u8]| {
+            let mut msg = request("Arith", "Div");
+            msg.payload = args.to_vec();
+            dispatcher.dispatch(None, &msg).unwrap()
+        };
+
+        let reply = call(b"[6,3]");
+        assert_eq!(reply.get_error(), None);
+        assert_eq!(reply.payload, b"2");
+        let reply = call(b"[6,0]");
+        assert_eq!(reply.get_error().as_deref(), Some("division by zero"));
+        assert!(reply.payload.is_empty());
+    }
+
+    #[test]
+    fn idle_connections_are_closed() {
+        let mut server = Server::new("127.0.0.1:0".to_owned(), 1);
+        server.set_idle_timeout(Some(Duration::from_millis(300)));
+        let (server, addr, handle) = serve(server);
+
+        let mut conn = TcpStream::connect(addr).unwrap();
+        let mut heartbeat = Message::new();
+        heartbeat.set_heartbeat(true);
+        // heartbeats keep the connection open longer than the idle timeout
+        for _ in 0..3 {
+            thread::sleep(Duration::from_millis(150));
+            conn.write_all(&heartbeat.encode()).unwrap();
+            Message::new().decode(&mut conn).unwrap();
+        }
+
+        let idle = Instant::now();
+        conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(conn.read(&mut [0; 1]).unwrap(), 0);
+        assert!(idle.elapsed() >= Duration::from_millis(250));
+
+        stop(&server, handle);
+    }
+
+    #[test]
+    fn connection_callbacks_get_the_peer_addr() {
+        let mut server = Server::new("127.0.0.1:0".to_owned(), 1);
+        let (tx, rx) = mpsc::channel();
+        let disconnected = tx.clone();
+        server.set_on_connect(move |peer| tx.send(("connect", peer)).unwrap());
+        server.set_on_disconnect(move |peer| disconnected.send(("disconnect", peer)).unwrap());
+        let (server, addr, handle) = serve(server);
+
+        let conn = TcpStream::connect(addr).unwrap();
+        let peer = conn.local_addr().unwrap();
+        let timeout = Duration::from_secs(5);
+        assert_eq!(rx.recv_timeout(timeout).unwrap(), ("connect", peer));
+        drop(conn);
+        assert_eq!(rx.recv_timeout(timeout).unwrap(), ("disconnect", peer));
+
+        stop(&server, handle);
+    }
+
+    #[test]
+    fn connection_events_are_sent() {
+        let mut server = Server::builder()
+            .addr("127.0.0.1:0".to_owned())
+            .max_message_size(16)
+            .build();
+        let (tx, rx) = mpsc::channel();
+        server.set_conn_events(tx);
+        let (server, addr, handle) = serve(server);
+        let timeout = Duration::from_secs(5);
+
+        let mut conn = TcpStream::connect(addr).unwrap();
+        let peer = Some(conn.local_addr().unwrap());
+        let mut msg = Message::new();
+        msg.service_path = "Too".to_owned();
+        msg.service_method = "Large".to_owned();
+        conn.write_all(&msg.encode()).unwrap();
+        match rx.recv_timeout(timeout).unwrap() {
+            ConnEvent::Opened { peer: p } => assert_eq!(p, peer),
+            event => panic!("unexpected {:?}", event),
+        }
+        match rx.recv_timeout(timeout).unwrap() {
+            ConnEvent::Error { peer: p, err } => {
+                assert_eq!(p, peer);
+                assert_eq!(err.kind(), ErrorKind::MessageTooLarge);
+            }
+            event => panic!("unexpected {:?}", event),
+        }
+        match rx.recv_timeout(timeout).unwrap() {
+            ConnEvent::Closed { peer: p } => assert_eq!(p, peer),
+            event => panic!("unexpected {:?}", event),
+        }
+
+        // closing between requests is no error
+        let conn = TcpStream::connect(addr).unwrap();
+        drop(conn);
+        assert!(matches!(
+            rx.recv_timeout(timeout).unwrap(),
+            ConnEvent::Opened { .. }
+        ));
+        assert!(matches!(
+            rx.recv_timeout(timeout).unwrap(),
+            ConnEvent::Closed { .. }
+        ));
+
+        stop(&server, handle);
+    }
+
+    #[test]
+    fn raw_handlers_get_the_payload_as_is() {
+        let mut server = Server::new("127.0.0.1:0".to_owned(), 1);
+        server
+            .register_raw_fn("Raw".to_owned(), "Reverse".to_owned(), String::new(), |x| {
+                Ok(x.iter().rev().cloned().collect())
+            })
+            .unwrap();
+        let dispatcher = server.dispatcher();
+        let mut msg = Message::new();
+        msg.set_serialize_type(SerializeType::SerializeNone);
+        msg.service_path = "Raw".to_owned();
+        msg.service_method = "Reverse".to_owned();
+        msg.payload = vec![0, 0xff, 0x80, b'{'];
+
+        let reply = dispatcher.dispatch(None, &msg).unwrap();
+        assert_eq!(reply.get_error(), None);
+        assert_eq!(
+            reply.get_serialize_type(),
+            Some(SerializeType::SerializeNone)
+        );
+        assert_eq!(reply.payload, [b'{', 0x80, 0xff, 0]);
+
+        msg.set_serialize_type(SerializeType::JSON);
+        let reply = dispatcher.dispatch(None, &msg).unwrap();
+        assert_eq!(
+            reply.get_error().unwrap(),
+            "Raw.Reverse only takes raw payloads"
+        );
+    }
+
+    #[test]
+    fn reply_metadata_is_echoed_and_set_by_handlers() {
+        let mut server = Server::new("127.0.0.1:0".to_owned(), 1);
+        server.set_echoed_metadata(&["trace", "span"]);
+        let f: RpcxCtxFn = |ctx, x, _| {
+            ctx.set_reply_metadata("span", "child");
+            Ok(x.to_vec())
+        };
+        server
+            .register_ctx_fn("Echo".to_owned(), "Echo".to_owned(), String::new(), f)
+            .unwrap();
+        let dispatcher = server.dispatcher();
+        let mut msg = request("Echo", "Echo");
+        for (k, v) in &[("trace", "t1"), ("span", "parent"), ("user", "u1")] {
+            msg.metadata
+                .borrow_mut()
+                .insert(k.to_string(), v.to_string());
+        }
+
+        let reply = dispatcher.dispatch(None, &msg).unwrap();
+        let metadata = reply.metadata.borrow();
+        assert_eq!(metadata.get("trace").unwrap(), "t1");
+        assert_eq!(metadata.get("span").unwrap(), "child");
+        assert_eq!(metadata.get("user"), None);
+        drop(metadata);
+
+        // error replies carry the echoed metadata as well
+        msg.service_method = "Missing".to_owned();
+        let reply = dispatcher.dispatch(None, &msg).unwrap();
+        assert!(reply.get_error().is_some());
+        assert_eq!(reply.metadata.borrow().get("span").unwrap(), "parent");
+    }
+
+    #[test]
+    fn pipelined_replies_carry_their_seq() {
+        let mut server = Server::new("127.0.0.1:0".to_owned(), 4);
+        let f: RpcxFn = |x, _| {
+            let delay: u64 = std::str::from_utf8(x).unwrap().parse().unwrap();
+            thread::sleep(Duration::from_millis(delay));
+            Ok(x.to_vec())
+        };
+        server
+            .register_fn("Sleep".to_owned(), "Sleep".to_owned(), String::new(), f)
+            .unwrap();
+        let (server, addr, handle) = serve(server);
+
+        // later requests finish first
+        let mut conn = TcpStream::connect(addr).unwrap();
+        let delays: HashMap<u64, &str> = [(1, "300"), (2, "200"), (3, "100"), (4, "0")]
+            .iter()
+            .cloned()
+            .collect();
+        for seq in 1..=4 {
+            let mut msg = Message::new();
+            msg.set_serialize_type(SerializeType::JSON);
+            msg.set_seq(seq);
+            msg.service_path = "Sleep".to_owned();
+            msg.service_method = "Sleep".to_owned();
+            msg.payload = delays[&seq].as_bytes().to_vec();
+            conn.write_all(&msg.encode()).unwrap();
+        }
+
+        let mut seqs = Vec::new();
+        for _ in 1..=4 {
+            let mut reply = Message::new();
+            reply.decode(&mut conn).unwrap();
+            assert_eq!(reply.get_error(), None);
+            assert_eq!(reply.payload, delays[&reply.get_seq()].as_bytes());
+            seqs.push(reply.get_seq());
+        }
+        assert_eq!(seqs, [4, 3, 2, 1]);
+
+        stop(&server, handle);
+    }
+
+    #[test]
+    fn pending_requests_per_connection_are_limited() {
+        let mut server = Server::builder()
+            .addr("127.0.0.1:0".to_owned())
+            .thread_number(8)
+            .max_pending_per_conn(2)
+            .build();
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let (r, m) = (running.clone(), most.clone());
+        let handler: RpcxHandler = Box::new(move |_, x, _| {
+            let now = r.fetch_add(1, Ordering::SeqCst) + 1;
+            m.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(50));
+            r.fetch_sub(1, Ordering::SeqCst);
+            Ok(x.to_vec())
+        });
+        server
+            .register_handler("Slow".to_owned(), "Echo".to_owned(), String::new(), handler)
+            .unwrap();
+        let (server, addr, handle) = serve(server);
+
+        let mut conn = TcpStream::connect(addr).unwrap();
+        for seq in 0..6 {
+            let mut msg = Message::new();
+            msg.set_serialize_type(SerializeType::JSON);
+            msg.set_seq(seq);
+            msg.service_path = "Slow".to_owned();
+            msg.service_method = "Echo".to_owned();
+            conn.write_all(&msg.encode()).unwrap();
+        }
+        for _ in 0..6 {
+            let mut reply = Message::new();
+            reply.decode(&mut conn).unwrap();
+            assert_eq!(reply.get_error(), None);
+        }
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+
+        stop(&server, handle);
+    }
+
+    #[test]
+    fn requests_waiting_for_the_pool_are_counted() {
+        let mut server = Server::new("127.0.0.1:0".to_owned(), 1);
+        let metrics = Arc::new(MemoryMetrics::new());
+        server.set_metrics(metrics.clone());
+        let handler: RpcxHandler = Box::new(|_, x, _| {
+            thread::sleep(Duration::from_millis(50));
+            Ok(x.to_vec())
+        });
+        server
+            .register_handler("Slow".to_owned(), "Echo".to_owned(), String::new(), handler)
+            .unwrap();
+        let (server, addr, handle) = serve(server);
+
+        let mut conn = TcpStream::connect(addr).unwrap();
+        for seq in 0..3 {
+            let mut msg = Message::new();
+            msg.set_serialize_type(SerializeType::JSON);
+            msg.set_seq(seq);
+            msg.service_path = "Slow".to_owned();
+            msg.service_method = "Echo".to_owned();
+            conn.write_all(&msg.encode()).unwrap();
+        }
+        for _ in 0..3 {
+            let mut reply = Message::new();
+            reply.decode(&mut conn).unwrap();
+        }
+        // the first one got the only thread
+        assert_eq!(metrics.pool_saturated(), 2);
+
+        stop(&server, handle);
+    }
+
+    #[test]
+    fn inline_handlers_run_on_the_connection_thread() {
+        let mut server = Server::builder()
+            .addr("127.0.0.1:0".to_owned())
+            .thread_number(8)
+            .dispatch_mode(DispatchMode::Inline)
+            .build();
+        let handler: RpcxHandler = Box::new(|_, _, _| {
+            let name = thread::current().name().unwrap_or_default().to_owned();
+            Ok(name.into_bytes())
+        });
+        server
+            .register_handler(
+                "Thread".to_owned(),
+                "Name".to_owned(),
+                String::new(),
+                handler,
+            )
+            .unwrap();
+        let (server, addr, handle) = serve(server);
+
+        let mut conn = TcpStream::connect(addr).unwrap();
+        for seq in 0..3 {
+            let mut msg = Message::new();
+            msg.set_serialize_type(SerializeType::SerializeNone);
+            msg.set_seq(seq);
+            msg.service_path = "Thread".to_owned();
+            msg.service_method = "Name".to_owned();
+            conn.write_all(&msg.encode()).unwrap();
+        }
+        let name = format!("rpcx-conn-{}", conn.local_addr().unwrap());
+        for seq in 0..3 {
+            let mut reply = Message::new();
+            reply.decode(&mut conn).unwrap();
+            assert_eq!(reply.get_seq(), seq);
+            assert_eq!(reply.payload, name.as_bytes());
+        }
+
+        stop(&server, handle);
+    }
+
+    #[test]
+    fn health_check_reports_the_status() {
+        let server = Server::builder().with_health_check().build();
+        let dispatcher = server.dispatcher();
+        let check = || {
+            let msg = request(HEALTH_SERVICE_PATH, HEALTH_SERVICE_METHOD);
+            let reply = dispatcher.dispatch(None, &msg).unwrap();
+            assert_eq!(reply.get_error(), None);
+            serde_json::from_slice::<HealthStatus>(&reply.payload).unwrap()
+        };
+
+        assert_eq!(check().status, "SERVING");
+        server.closing.store(true, Ordering::SeqCst);
+        assert_eq!(check().status, "NOT_SERVING");
+        assert_eq!(Server::builder().build().service_count(), 0);
+    }
+
+    #[test]
+    fn reflection_lists_services_with_their_meta() {
+        let mut server = Server::builder().with_reflection().build();
+        let f: RpcxFn = |x, _| Ok(x.to_vec());
+        server
+            .register_fn("Echo".to_owned(), "Echo".to_owned(), "v=1".to_owned(), f)
+            .unwrap();
+        server
+            .register_fn("Echo".to_owned(), "Gone".to_owned(), String::new(), f)
+            .unwrap();
+        server.unregister_fn("Echo".to_owned(), "Gone".to_owned());
+        assert_eq!(server.service_meta("Echo", "Echo").unwrap(), "v=1");
+
+        let msg = request(REFLECTION_SERVICE_PATH, REFLECTION_SERVICE_METHOD);
+        let reply = server.dispatcher().dispatch(None, &msg).unwrap();
+        let services: Vec<ServiceInfo> = serde_json::from_slice(&reply.payload).unwrap();
+        let listed: Vec<_> = services
+            .iter()
+            .map(|s| {
+                (
+                    s.service_path.as_str(),
+                    s.service_method.as_str(),
+                    s.meta.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            listed,
+            [("Echo", "Echo", "v=1"), ("rpcx.reflection", "list", "")]
+        );
+    }
+
+    #[test]
+    fn close_stops_accepting_but_keeps_connections() {
+        let server = Server::new("127.0.0.1:0".to_owned(), 1);
+        let (server, addr, handle) = serve(server);
+
+        let mut conn = TcpStream::connect(addr).unwrap();
+        let mut heartbeat = Message::new();
+        heartbeat.set_heartbeat(true);
+        let mut ping = || {
+            let reply = call(&mut conn, &heartbeat);
+            assert!(reply.is_heartbeat());
+        };
+        ping();
+
+        server.close().unwrap();
+        server.close().unwrap();
+        handle.join().unwrap().unwrap();
+        assert!(TcpStream::connect(addr).is_err());
+        ping();
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn servers_start_with_an_inherited_socket() {
+        use std::os::unix::io::IntoRawFd;
+
+        let server = Arc::new(Server::new("127.0.0.1:0".to_owned(), 1));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let fd = listener.into_raw_fd();
+        let s = server.clone();
+        let handle = thread::spawn(move || unsafe { s.start_with_raw_fd(fd) });
+
+        let mut conn = TcpStream::connect(addr).unwrap();
+        let mut heartbeat = Message::new();
+        heartbeat.set_heartbeat(true);
+        let reply = call(&mut conn, &heartbeat);
+        assert!(reply.is_heartbeat());
+
+        server.close().unwrap();
+        handle.join().unwrap().unwrap();
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    fn message_handlers_get_the_whole_request() {
+        let mut server = Server::new("127.0.0.1:0".to_owned(), 1);
+        server
+            .register_message_fn("Msg".to_owned(), "Echo".to_owned(), String::new(), |msg| {
+                let user = msg.metadata.borrow().get("user").cloned();
+                let user = user.ok_or_else(|| Error::from("no user"))?;
+                let mut reply_msg = msg.get_reply()?;
+                reply_msg
+                    .metadata
+                    .borrow_mut()
+                    .insert("hello".to_owned(), user);
+                reply_msg.payload = format!("{}.{}", msg.service_path, msg.service_method).into();
+                Ok(reply_msg)
+            })
+            .unwrap();
+        let dispatcher = server.dispatcher();
+        let mut msg = Message::new();
+        msg.set_serialize_type(SerializeType::SerializeNone);
+        msg.set_seq(9);
+        msg.service_path = "Msg".to_owned();
+        msg.service_method = "Echo".to_owned();
+
+        let reply = dispatcher.dispatch(None, &msg).unwrap();
+        assert_eq!(reply.get_error().unwrap(), "no user");
+
+        msg.metadata
+            .borrow_mut()
+            .insert("user".to_owned(), "gopher".to_owned());
+        let reply = dispatcher.dispatch(None, &msg).unwrap();
+        assert_eq!(reply.get_error(), None);
+        assert_eq!(reply.get_seq(), 9);
+        assert_eq!(reply.metadata.borrow()["hello"], "gopher");
+        assert_eq!(reply.payload, b"Msg.Echo");
+    }
+
+    #[test]
+    fn any_method_handlers_serve_the_unregistered_methods() {
+        let mut server = Server::new("127.0.0.1:0".to_owned(), 1);
+        let any: RpcxHandler = Box::new(|ctx, _, _| Ok(ctx.service_method.clone().into()));
+        server
+            .register_handler("Dyn".to_owned(), ANY_METHOD.to_owned(), String::new(), any)
+            .unwrap();
+        let exact: RpcxHandler = Box::new(|_, _, _| Ok(b"exact".to_vec()));
+        server
+            .register_handler("Dyn".to_owned(), "Exact".to_owned(), String::new(), exact)
+            .unwrap();
+        let dispatcher = server.dispatcher();
+
+        let call = |path: &str, method: &str| {
+            let mut msg = Message::new();
+            msg.set_serialize_type(SerializeType::SerializeNone);
+            msg.service_path = path.to_owned();
+            msg.service_method = method.to_owned();
+            dispatcher.dispatch(None, &msg).unwrap()
+        };
+        assert_eq!(call("Dyn", "Exact").payload, b"exact");
+        assert_eq!(call("Dyn", "Other").payload, b"Other");
+        assert_eq!(
+            call("Static", "Other").get_error().unwrap(),
+            "service Static.Other not found"
+        );
+    }
+
+    #[test]
+    fn poisoned_service_maps_are_still_used() {
+        let mut server = Server::new("127.0.0.1:0".to_owned(), 1);
+        let services = server.services.clone();
+        let _ = thread::spawn(move || {
+            let _map = services.write().unwrap();
+            panic!("poisoning the services");
+        })
+        .join();
+        assert!(server.services.is_poisoned());
+
+        let handler: RpcxHandler = Box::new(|_, x, _| Ok(x.to_vec()));
+        server
+            .register_handler("Echo".to_owned(), "Echo".to_owned(), String::new(), handler)
+            .unwrap();
+        let mut msg = Message::new();
+        msg.set_serialize_type(SerializeType::SerializeNone);
+        msg.service_path = "Echo".to_owned();
+        msg.service_method = "Echo".to_owned();
+        msg.payload = b"still here".to_vec();
+        let reply = server.dispatcher().dispatch(None, &msg).unwrap();
+        assert_eq!(reply.payload, b"still here");
+    }
+
+    #[test]
+    fn dropped_servers_are_closed() {
+        let plugin = MemoryRegisterPlugin::new();
+        let mut server = Server::new("127.0.0.1:0".to_owned(), 1);
+        server.add_register_plugin(Box::new(plugin.clone()));
+        let handler: RpcxHandler = Box::new(|_, x, _| Ok(x.to_vec()));
+        server
+            .register_handler("Echo".to_owned(), "Echo".to_owned(), String::new(), handler)
+            .unwrap();
+        assert_eq!(plugin.registrations().len(), 1);
+
+        drop(server);
+        assert!(plugin.registrations().is_empty());
+    }
+
+    #[test]
+    fn services_are_registered_while_serving() {
+        let server = Server::new("127.0.0.1:0".to_owned(), 1);
+        let registry = server.registry();
+        let (server, addr, handle) = serve(server);
+
+        let mut conn = TcpStream::connect(addr).unwrap();
+        let mut call = || {
+            let mut msg = Message::new();
+            msg.set_serialize_type(SerializeType::SerializeNone);
+            msg.service_path = "Late".to_owned();
+            msg.service_method = "Echo".to_owned();
+            msg.payload = b"late".to_vec();
+            call(&mut conn, &msg)
+        };
+        assert!(call().get_error().is_some());
+
+        thread::spawn(move || {
+            let handler: RpcxHandler = Box::new(|_, x, _| Ok(x.to_vec()));
+            registry
+                .register_handler("Late".to_owned(), "Echo".to_owned(), String::new(), handler)
+                .unwrap();
+        })
+        .join()
+        .unwrap();
+        let reply = call();
+        assert_eq!(reply.get_error(), None);
+        assert_eq!(reply.payload, b"late");
+
+        server
+            .registry()
+            .unregister_fn("Late".to_owned(), "Echo".to_owned());
+        assert!(call().get_error().is_some());
+
+        stop(&server, handle);
+    }
+
+    #[test]
+    fn malformed_service_names_are_refused() {
+        let mut server = Server::new("127.0.0.1:0".to_owned(), 1);
+        let mut register = |path: &str, method: &str| {
+            let handler: RpcxHandler = Box::new(|_, x, _| Ok(x.to_vec()));
+            server.register_handler(path.to_owned(), method.to_owned(), String::new(), handler)
+        };
+        for (path, method) in &[("", "Add"), ("Ar ith", "Add"), ("Arith", "Add\n")] {
+            assert!(register(path, method).is_err(), "{:?}", (path, method));
+        }
+        assert_eq!(
+            register("Arith", "").unwrap_err().to_string(),
+            "invalid service method \"\""
+        );
+        register("rpcx.Arith", "Add").unwrap();
+        register("Arith", ANY_METHOD).unwrap();
+        assert_eq!(server.service_count(), 2);
+    }
+
+    #[test]
+    fn not_found_replies_can_be_customized() {
+        let mut server = Server::new("127.0.0.1:0".to_owned(), 1);
+        server.set_not_found_handler(|msg| {
+            let mut reply_msg = msg.get_reply().unwrap();
+            reply_msg.set_message_status_type(MessageStatusType::Error);
+            reply_msg
+                .metadata
+                .borrow_mut()
+                .insert("code".to_owned(), "404".to_owned());
+            reply_msg.payload = format!("did you mean {}.Add?", msg.service_path).into();
+            reply_msg
+        });
+        let mut msg = Message::new();
+        msg.set_serialize_type(SerializeType::JSON);
+        msg.set_seq(3);
+        msg.service_path = "Arith".to_owned();
+        msg.service_method = "Ad".to_owned();
+
+        let reply = server.dispatcher().dispatch(None, &msg).unwrap();
+        assert_eq!(reply.get_seq(), 3);
+        assert_eq!(reply.metadata.borrow()["code"], "404");
+        assert_eq!(reply.payload, b"did you mean Arith.Add?");
+    }
+
+    #[test]
+    fn contexts_show_the_request_header() {
+        let mut server = Server::new("127.0.0.1:0".to_owned(), 1);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let s = seen.clone();
+        let handler: RpcxHandler = Box::new(move |ctx, _, _| {
+            let header = (
+                ctx.payload_len,
+                ctx.compress_type,
+                ctx.is_oneway,
+                ctx.message_type,
+            );
+            s.lock().unwrap().push(header);
+            Ok(Vec::new())
+        });
+        server
+            .register_handler(
+                "Ctx".to_owned(),
+                "Header".to_owned(),
+                String::new(),
+                handler,
+            )
+            .unwrap();
+        let dispatcher = server.dispatcher();
+        let mut msg = Message::new();
+        msg.set_message_type(MessageType::Request);
+        msg.set_serialize_type(SerializeType::JSON);
+        msg.set_compress_type(CompressType::Gzip);
+        msg.service_path = "Ctx".to_owned();
+        msg.service_method = "Header".to_owned();
+        msg.payload = b"[1,2,3]".to_vec();
+        dispatcher.dispatch(None, &msg).unwrap();
+        msg.set_oneway(true);
+        msg.set_compress_type(CompressType::CompressNone);
+        assert!(dispatcher.dispatch(None, &msg).is_none());
+
+        let request = Some(MessageType::Request);
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [
+                (7, Some(CompressType::Gzip), false, request),
+                (7, Some(CompressType::CompressNone), true, request),
+            ]
+        );
+    }
+
+    #[test]
+    fn connections_wait_in_the_backlog() {
+        let mut server = Server::builder()
+            .addr("127.0.0.1:0".to_owned())
+            .backlog(16)
+            .build();
+        let listener = server.bind().unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // queued by the system before the server accepts any
+        let mut conns: Vec<TcpStream> = (0..8).map(|_| TcpStream::connect(addr).unwrap()).collect();
+
+        let server = Arc::new(server);
+        let s = server.clone();
+        let handle = thread::spawn(move || s.start_with_listener(listener));
+        for conn in &mut conns {
+            let mut msg = Message::new();
+            msg.set_heartbeat(true);
+            let reply = call(conn, &msg);
+            assert!(reply.is_heartbeat());
+        }
+
+        stop(&server, handle);
+    }
+
+    /// A connection failing its first writes with `failures`.
+    struct FlakyWriter {
+        failures: Mutex<Vec<io::ErrorKind>>,
+        written: Mutex<Vec<u8>>,
+    }
+
+    impl WriteHalf for FlakyWriter {
+        fn write_reply(&self, buf: &[u8]) -> io::Result<()> {
+            if let Some(kind) = self.failures.lock().unwrap().pop() {
+                return Err(kind.into());
+            }
+            self.written.lock().unwrap().extend_from_slice(buf);
+            Ok(())
+        }
+
+        fn shutdown(&self, _: Shutdown) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn peer_addr(&self) -> io::Result<SocketAddr> {
+            Err(io::ErrorKind::NotConnected.into())
+        }
+    }
+
+    #[test]
+    fn interrupted_replies_are_written_again() {
+        let writer = Arc::new(FlakyWriter {
+            failures: Mutex::new(vec![io::ErrorKind::Interrupted; 2]),
+            written: Mutex::new(Vec::new()),
+        });
+        let replies = Mutex::new(BufWriter::new(ReplyWriter(writer.clone())));
+
+        write_reply(&replies, None, b"first").unwrap();
+        assert_eq!(*writer.written.lock().unwrap(), b"first");
+
+        writer
+            .failures
+            .lock()
+            .unwrap()
+            .push(io::ErrorKind::BrokenPipe);
+        let err = write_reply(&replies, None, b"second").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn uploads_are_read_as_they_arrive() {
+        let mut server = Server::builder()
+            .addr("127.0.0.1:0".to_owned())
+            .max_message_size(1024)
+            .build();
+        server
+            .register_upload_fn(
+                "File".to_owned(),
+                "Upload".to_owned(),
+                String::new(),
+                |ctx, r, _| {
+                    // reads at most `limit` bytes, leaving the rest
+                    let limit: u64 = ctx.metadata["limit"].parse().unwrap();
+                    let mut buf = [0u8; 4096];
+                    let mut read = 0;
+                    let mut r = r.take(limit);
+                    loop {
+                        match r.read(&mut buf)? {
+                            0 => break,
+                            n => read += n,
+                        }
+                    }
+                    Ok(format!("{} of {}", read, ctx.payload_len).into_bytes())
+                },
+            )
+            .unwrap();
+        let handler: RpcxHandler = Box::new(|_, x, _| Ok(x.to_vec()));
+        server
+            .register_handler("Echo".to_owned(), "Echo".to_owned(), String::new(), handler)
+            .unwrap();
+        let (server, addr, handle) = serve(server);
+
+        let mut conn = TcpStream::connect(addr).unwrap();
+        let mut call = |path: &str, method: &str, limit: u64, payload: Vec<u8>| {
+            let mut msg = Message::new();
+            msg.set_serialize_type(SerializeType::SerializeNone);
+            msg.service_path = path.to_owned();
+            msg.service_method = method.to_owned();
+            msg.metadata
+                .borrow_mut()
+                .insert("limit".to_owned(), limit.to_string());
+            msg.payload = payload;
+            call(&mut conn, &msg)
+        };
+
+        // far over the message size limit, but never buffered
+        let reply = call("File", "Upload", u64::MAX, vec![7; 1 << 20]);
+        assert_eq!(reply.get_error(), None);
+        assert_eq!(reply.payload, b"1048576 of 1048576");
+
+        let reply = call("File", "Upload", 10, vec![7; 100_000]);
+        assert_eq!(reply.payload, b"10 of 100000");
+
+        // the unread payload was skipped
+        let reply = call("Echo", "Echo", 0, b"echo".to_vec());
+        assert_eq!(reply.get_error(), None);
+        assert_eq!(reply.payload, b"echo");
+
+        stop(&server, handle);
+    }
+
+    #[test]
+    fn keepalive_is_set_on_tcp_sockets() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (conn, _) = listener.accept().unwrap();
+        assert!(!socket2::SockRef::from(&conn).keepalive().unwrap());
+
+        let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(30));
+        Socket::set_keepalive(&conn, &keepalive).unwrap();
+        let sock = socket2::SockRef::from(&conn);
+        assert!(sock.keepalive().unwrap());
+        #[cfg(target_os = "linux")]
+        assert_eq!(sock.keepalive_time().unwrap(), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn thread_number_is_the_pool_size() {
+        assert!(default_thread_number() >= 2);
+        let server = Server::new("127.0.0.1:0".to_owned(), 0);
+        assert_eq!(server.pool.max_count(), default_thread_number() as usize);
+        let server = Server::builder().thread_number(3).build();
+        assert_eq!(server.pool.max_count(), 3);
+    }
+
+    #[test]
+    fn handlers_may_pick_the_reply_serialize_type() {
+        let mut server = Server::new("127.0.0.1:0".to_owned(), 1);
+        let handler: RpcxHandler = Box::new(|ctx, x, st| {
+            let args: Vec<u32> = serde_json::from_slice(x)?;
+            let reply_st = match ctx.metadata.get("reply") {
+                Some(reply) => reply.parse().map_err(|_| Error::from("bad reply type"))?,
+                None => st,
+            };
+            ctx.set_reply_serialize_type(reply_st);
+            typed::encode_reply(reply_st, &args.iter().sum::<u32>())
+        });
+        server
+            .register_handler("Sum".to_owned(), "Sum".to_owned(), String::new(), handler)
+            .unwrap();
+        let dispatcher = server.dispatcher();
+        let mut msg = request("Sum", "Sum");
+        msg.payload = b"[1, 2, 3]".to_vec();
+
+        let reply = dispatcher.dispatch(None, &msg).unwrap();
+        assert_eq!(reply.get_serialize_type(), Some(SerializeType::JSON));
+        assert_eq!(reply.payload, b"6");
+
+        msg.metadata
+            .borrow_mut()
+            .insert("reply".to_owned(), "MsgPack".to_owned());
+        let reply = dispatcher.dispatch(None, &msg).unwrap();
+        assert_eq!(reply.get_error(), None);
+        assert_eq!(reply.get_serialize_type(), Some(SerializeType::MsgPack));
+        assert_eq!(reply.payload, [6]);
+    }
+
+    #[test]
+    fn default_handlers_serve_the_unmatched_requests() {
+        let mut server = Server::new("127.0.0.1:0".to_owned(), 1);
+        let any: RpcxHandler = Box::new(|_, _, _| Ok(b"any".to_vec()));
+        server
+            .register_handler("Dyn".to_owned(), ANY_METHOD.to_owned(), String::new(), any)
+            .unwrap();
+        server.set_not_found_handler(|_| unreachable!());
+        server.set_default_handler(|ctx, x, _| {
+            let mut reply = format!("{}.{}:", ctx.service_path, ctx.service_method).into_bytes();
+            reply.extend_from_slice(x);
+            Ok(reply)
+        });
+        let dispatcher = server.dispatcher();
+
+        let call = |path: &str, method: &str| {
+            let mut msg = Message::new();
+            msg.set_serialize_type(SerializeType::SerializeNone);
+            msg.service_path = path.to_owned();
+            msg.service_method = method.to_owned();
+            msg.payload = b"hi".to_vec();
+            dispatcher.dispatch(None, &msg).unwrap()
+        };
+        assert_eq!(call("Dyn", "Other").payload, b"any");
+        let reply = call("Proxied", "Get");
+        assert_eq!(reply.get_error(), None);
+        assert_eq!(reply.payload, b"Proxied.Get:hi");
+    }
+
+    #[test]
+    fn transient_accept_errors_are_retried() {
+        let server = Server::new("127.0.0.1:0".to_owned(), 1);
+        let incoming: Vec<io::Result<TcpStream>> = vec![
+            Err(io::Error::other("too many open files")),
+            Err(io::ErrorKind::ConnectionAborted.into()),
+            Err(io::Error::other("too many open files")),
+        ];
+        assert!(server.serve(incoming.into_iter(), Ok).is_ok());
+
+        let incoming: Vec<io::Result<TcpStream>> = vec![
+            Err(io::ErrorKind::InvalidInput.into()),
+            Err(io::ErrorKind::ConnectionAborted.into()),
+        ];
+        let err = server.serve(incoming.into_iter(), Ok).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Network);
+
+        let other = io::Error::other("too many open files");
+        let mut delay = Duration::ZERO;
+        for expected in &[5, 10, 20] {
+            delay = accept_delay(&other, delay).unwrap();
+            assert_eq!(delay, Duration::from_millis(*expected));
+        }
+        let delay = accept_delay(&other, Duration::from_millis(800)).unwrap();
+        assert_eq!(delay, MAX_ACCEPT_DELAY);
+        let reset = io::ErrorKind::ConnectionReset.into();
+        assert_eq!(accept_delay(&reset, delay), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn poisoned_locks_do_not_make_close_panic() {
+        use std::panic::{self, AssertUnwindSafe};
+
+        let mut server = Server::new("127.0.0.1:0".to_owned(), 1);
+        let _listener = server.bind().unwrap();
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _addrs = server.local_addrs.lock().unwrap();
+            let _connections = server.connections.lock().unwrap();
+            panic!("poisons the locks");
+        }));
+        assert!(server.local_addrs.is_poisoned());
+        assert!(server.close().is_ok());
+        assert!(server.bound_addr().is_some());
+    }
+
+    #[test]
+    fn failed_registrations_are_withdrawn_from_the_plugins_before() {
+        struct FailingPlugin;
+        impl RegisterPlugin for FailingPlugin {
+            fn register_fn(&mut self, _: &str, _: &str, _: String, _: &RpcxHandler) -> Result<()> {
+                Err(Error::from("registry unavailable"))
+            }
+        }
+
+        let plugin = MemoryRegisterPlugin::new();
+        let mut server = Server::new("127.0.0.1:0".to_owned(), 1);
+        server.add_register_plugin(Box::new(plugin.clone()));
+        server.add_register_plugin(Box::new(FailingPlugin));
+
+        let f: RpcxFn = |x, _| Ok(x.to_vec());
+        let res = server.register_fn("Arith".to_owned(), "Add".to_owned(), String::new(), f);
+        assert_eq!(res.unwrap_err().to_string(), "registry unavailable");
+        assert!(plugin.registrations().is_empty());
+        assert_eq!(server.service_count(), 0);
+    }
+
+    #[test]
+    fn requests_are_dispatched_without_a_connection() {
+        let mut server = Server::new("127.0.0.1:0".to_owned(), 1);
+        let f: RpcxFn = |x, _| Ok(x.to_vec());
+        server
+            .register_fn("Echo".to_owned(), "Echo".to_owned(), String::new(), f)
+            .unwrap();
+        let mut msg = Message::new();
+        msg.set_message_type(MessageType::Request);
+        msg.set_serialize_type(SerializeType::JSON);
+        msg.set_seq(7);
+        msg.service_path = "Echo".to_owned();
+        msg.service_method = "Echo".to_owned();
+        msg.payload = b"\"hi\"".to_vec();
+
+        let reply = server.dispatch(&msg).unwrap();
+        assert_eq!(reply.get_error(), None);
+        assert_eq!(reply.get_seq(), 7);
+        assert_eq!(reply.payload, msg.payload);
+
+        msg.set_heartbeat(true);
+        let reply = server.dispatch(&msg).unwrap();
+        assert!(reply.is_heartbeat());
+        msg.set_oneway(true);
+        assert!(server.dispatch(&msg).is_none());
+    }
+
+    #[test]
+    fn draining_services_are_not_called() {
+        let mut server = Server::new("127.0.0.1:0".to_owned(), 1);
+        let f: RpcxFn = |x, _| Ok(x.to_vec());
+        for method in &["Old", "New"] {
+            server
+                .register_fn("Echo".to_owned(), method.to_string(), String::new(), f)
+                .unwrap();
+        }
+        let dispatcher = server.dispatcher();
+        let call = |method: &str| {
+            let msg = request("Echo", method);
+            dispatcher.dispatch(None, &msg).unwrap().get_error()
+        };
+
+        server.set_draining("Echo", "Old", true);
+        assert_eq!(call("Old").as_deref(), Some("service draining"));
+        assert_eq!(call("New"), None);
+        server.set_draining("Echo", "Old", false);
+        assert_eq!(call("Old"), None);
+    }
+
+    #[test]
+    fn register_plugins_get_the_bound_addr() {
+        let plugin = MemoryRegisterPlugin::new();
+        let mut server = Server::new("127.0.0.1:0".to_owned(), 1);
+        server.add_register_plugin(Box::new(plugin.clone()));
+        assert_eq!(plugin.addr(), None);
+        assert_eq!(server.bound_addr(), None);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Arc::new(server);
+        let s = server.clone();
+        let handle = thread::spawn(move || s.start_with_listener(listener));
+        while plugin.addr().is_none() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(plugin.addr(), Some(addr));
+        assert_eq!(server.bound_addr(), Some(addr));
+
+        stop(&server, handle);
+    }
+
+    #[test]
+    fn rejected_connections_are_closed_unread() {
+        struct Reject(Mutex<Vec<SocketAddr>>);
+        impl ConnectPlugin for Arc<Reject> {
+            fn handle_connect(&self, peer: SocketAddr) -> Result<()> {
+                self.0.lock().unwrap().push(peer);
+                Err(Error::from("rejected"))
+            }
+        }
+
+        let mut server = Server::new("127.0.0.1:0".to_owned(), 1);
+        let called = Arc::new(AtomicBool::new(false));
+        let c = called.clone();
+        let echo: RpcxHandler = Box::new(move |_, x, _| {
+            c.store(true, Ordering::SeqCst);
+            Ok(x.to_vec())
+        });
+        server
+            .register_handler("Echo".to_owned(), "Echo".to_owned(), String::new(), echo)
+            .unwrap();
+        let plugin = Arc::new(Reject(Mutex::new(Vec::new())));
+        server.add_connect_plugin(Box::new(plugin.clone()));
+        let (server, addr, handle) = serve(server);
+
+        let mut conn = TcpStream::connect(addr).unwrap();
+        conn.write_all(&request("Echo", "Echo").encode()).unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        // unread data makes the close a reset
+        assert!(matches!(conn.read(&mut [0; 1]), Ok(0) | Err(_)));
+        assert_eq!(*plugin.0.lock().unwrap(), [conn.local_addr().unwrap()]);
+        assert!(!called.load(Ordering::SeqCst));
+
+        stop(&server, handle);
+    }
+
+    #[test]
+    fn connections_over_the_limit_are_closed() {
+        let mut server = Server::new("127.0.0.1:0".to_owned(), 1);
+        server.set_max_connections(2);
+        let (server, addr, handle) = serve(server);
+
+        let connect = || {
+            let conn = TcpStream::connect(addr).unwrap();
+            conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            conn
+        };
+        let served = |conn: &mut TcpStream| {
+            let mut heartbeat = Message::new();
+            heartbeat.set_heartbeat(true);
+            conn.write_all(&heartbeat.encode()).is_ok() && Message::new().decode(conn).is_ok()
+        };
+
+        let mut first = connect();
+        let mut second = connect();
+        assert!(served(&mut first));
+        assert!(served(&mut second));
+        assert!(!served(&mut connect()));
+        assert!(served(&mut first));
+        assert!(served(&mut second));
+
+        // the slot is given back once the connection thread is done
+        drop(first);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !served(&mut connect()) {
+            assert!(Instant::now() < deadline, "no slot was freed");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(served(&mut second));
+
+        stop(&server, handle);
+    }
+
+    /// Serves a handler sleeping for `sleep` with `grace_period`, sends it a
+    /// request and returns the connection once the handler is running.
+    fn sleeping(
+        sleep: Duration,
+        grace_period: Duration,
+    ) -> (Arc<Server>, JoinHandle<Result<()>>, TcpStream) {
+        let mut server = Server::new("127.0.0.1:0".to_owned(), 1);
+        server.set_grace_period(grace_period);
+        let (started, running) = mpsc::channel();
+        let started = Mutex::new(started);
+        let slow: RpcxHandler = Box::new(move |_, x, _| {
+            started.lock().unwrap().send(()).unwrap();
+            thread::sleep(sleep);
+            Ok(x.to_vec())
+        });
+        server
+            .register_handler("Echo".to_owned(), "Slow".to_owned(), String::new(), slow)
+            .unwrap();
+        let (server, addr, handle) = serve(server);
+
+        let mut conn = TcpStream::connect(addr).unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        conn.write_all(&request("Echo", "Slow").encode()).unwrap();
+        running.recv_timeout(Duration::from_secs(5)).unwrap();
+        (server, handle, conn)
+    }
+
+    #[test]
+    fn shutdown_waits_for_requests_in_flight() {
+        let (server, handle, mut conn) =
+            sleeping(Duration::from_millis(300), Duration::from_secs(5));
+
+        let start = Instant::now();
+        stop(&server, handle);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert!(start.elapsed() < Duration::from_secs(4));
+
+        let mut reply = Message::new();
+        reply.decode(&mut conn).unwrap();
+        assert_eq!(reply.get_error(), None);
+        assert_eq!(conn.read(&mut [0; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn shutdown_cuts_off_requests_after_the_grace_period() {
+        let (server, handle, mut conn) =
+            sleeping(Duration::from_secs(3), Duration::from_millis(100));
+
+        let start = Instant::now();
+        stop(&server, handle);
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        // closed without the reply
+        assert!(Message::new().decode(&mut conn).is_err());
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn stalled_requests_are_closed_after_the_read_timeout() {
+        let mut server = Server::new("127.0.0.1:0".to_owned(), 1);
+        server.set_read_timeout(Some(Duration::from_millis(200)));
+        let (server, addr, handle) = serve(server);
+
+        let mut conn = TcpStream::connect(addr).unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let frame = request("Echo", "Echo").encode();
+        conn.write_all(&frame[..frame.len() / 2]).unwrap();
+
+        let stalled = Instant::now();
+        assert_eq!(conn.read(&mut [0; 1]).unwrap(), 0);
+        assert!(stalled.elapsed() >= Duration::from_millis(150));
+        assert!(stalled.elapsed() < Duration::from_secs(4));
+
+        stop(&server, handle);
+    }
+
+    /// A socket path in the temp dir no other test uses, without a file.
+    #[cfg(not(target_os = "windows"))]
+    fn unix_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rpcx-{}-{}.sock", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    /// Serves `server` on the unix socket at `path` from another thread
+    /// and connects to it.
+    #[cfg(not(target_os = "windows"))]
+    fn serve_unix(
+        server: Server,
+        path: &std::path::Path,
+    ) -> (
+        Arc<Server>,
+        JoinHandle<Result<()>>,
+        std::os::unix::net::UnixStream,
+    ) {
+        let server = Arc::new(server);
+        let s = server.clone();
+        let p = path.to_str().unwrap().to_owned();
+        let handle = thread::spawn(move || s.start_unix(&p));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let conn = loop {
+            match std::os::unix::net::UnixStream::connect(path) {
+                Ok(conn) => break conn,
+                Err(err) => assert!(Instant::now() < deadline, "{}", err),
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        (server, handle, conn)
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn requests_are_served_on_unix_sockets() {
+        let path = unix_path("serve");
+        let mut server = Server::new(String::new(), 1);
+        let echo: RpcxFn = |x, _| Ok(x.to_vec());
+        server
+            .register_fn("Echo".to_owned(), "Echo".to_owned(), String::new(), echo)
+            .unwrap();
+        let (server, handle, mut conn) = serve_unix(server, &path);
+
+        let mut msg = request("Echo", "Echo");
+        msg.payload = b"\"hi\"".to_vec();
+        conn.write_all(&msg.encode()).unwrap();
+        let mut reply = Message::new();
+        reply.decode(&mut conn).unwrap();
+        assert_eq!(reply.get_error(), None);
+        assert_eq!(reply.payload, msg.payload);
+
+        stop(&server, handle);
+        assert!(!path.exists());
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn stale_unix_socket_files_are_replaced() {
+        let path = unix_path("stale");
+        // a listener dropped without removing its file, like a crashed server's
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let (server, handle, mut conn) = serve_unix(Server::new(String::new(), 1), &path);
+        let mut heartbeat = Message::new();
+        heartbeat.set_heartbeat(true);
+        conn.write_all(&heartbeat.encode()).unwrap();
+        let mut reply = Message::new();
+        reply.decode(&mut conn).unwrap();
+        assert!(reply.is_heartbeat());
+
+        // a live server's socket is left alone
+        let p = path.to_str().unwrap().to_owned();
+        assert!(Server::new(String::new(), 1).start_unix(&p).is_err());
+        assert!(path.exists());
+
+        stop(&server, handle);
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn close_wakes_the_unix_accept_loop() {
+        let path = unix_path("close");
+        let (server, handle, conn) = serve_unix(Server::new(String::new(), 1), &path);
+        drop(conn);
+
+        let start = Instant::now();
+        server.close().unwrap();
+        handle.join().unwrap().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert!(!path.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn start_on_serves_every_address() {
+        let addrs: Vec<SocketAddr> = vec!["127.0.0.1:0".parse().unwrap(); 2];
+        let server = Arc::new(Server::new(String::new(), 1));
+        let s = server.clone();
+        let handle = thread::spawn(move || s.start_on(&addrs[..]));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while server.local_addrs.lock().unwrap().len() < 2 {
+            assert!(Instant::now() < deadline, "not listening");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let bound = server.local_addrs.lock().unwrap().clone();
+        assert_ne!(bound[0], bound[1]);
+        let mut heartbeat = Message::new();
+        heartbeat.set_heartbeat(true);
+        for addr in bound {
+            let mut conn = TcpStream::connect(addr).unwrap();
+            assert!(call(&mut conn, &heartbeat).is_heartbeat());
+        }
+
+        // every accept loop is woken up and stopped
+        stop(&server, handle);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reuse_port_lets_servers_share_a_port() {
+        let mut first = Server::new("127.0.0.1:0".to_owned(), 1);
+        first.set_reuse_port(true);
+        let listener = first.bind().unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut second = Server::new(addr.to_string(), 1);
+        assert!(second.bind().is_err());
+        second.set_reuse_port(true);
+        let shared = second.bind().unwrap();
+        assert_eq!(shared.local_addr().unwrap(), addr);
+
+        // either listener may get a connection, so both are served
+        let first = Arc::new(first);
+        let second = Arc::new(second);
+        let handles: Vec<_> = vec![(first.clone(), listener), (second.clone(), shared)]
+            .into_iter()
+            .map(|(s, l)| thread::spawn(move || s.start_with_listener(l)))
+            .collect();
+        let mut heartbeat = Message::new();
+        heartbeat.set_heartbeat(true);
+        for _ in 0..4 {
+            let mut conn = TcpStream::connect(addr).unwrap();
+            assert!(call(&mut conn, &heartbeat).is_heartbeat());
+        }
+
+        // the connection waking up an accept loop may be handed to the
+        // other one, so they are woken until both are done
+        first.close().unwrap();
+        second.close().unwrap();
+        while handles.iter().any(|handle| !handle.is_finished()) {
+            let _ = TcpStream::connect(addr);
+            thread::sleep(Duration::from_millis(10));
+        }
+        for handle in handles {
+            handle.join().unwrap().unwrap();
+        }
+    }
+
+    #[test]
+    fn side_handlers_are_registered_through_the_registry() {
+        let plugin = MemoryRegisterPlugin::new();
+        let mut server = Server::new("127.0.0.1:0".to_owned(), 1);
+        server.add_register_plugin(Box::new(plugin.clone()));
+        let registry = server.registry();
+        registry
+            .register_message_fn("Side".to_owned(), "Msg".to_owned(), "m".to_owned(), |msg| {
+                let mut reply = msg.get_reply()?;
+                reply.payload = b"message".to_vec();
+                Ok(reply)
+            })
+            .unwrap();
+        registry
+            .register_stream_fn(
+                "Side".to_owned(),
+                "Stream".to_owned(),
+                "s".to_owned(),
+                |_, _, _, _| Ok(()),
+            )
+            .unwrap();
+        registry
+            .register_upload_fn(
+                "Side".to_owned(),
+                "Upload".to_owned(),
+                "u".to_owned(),
+                |_, _, _| Ok(Vec::new()),
+            )
+            .unwrap();
+
+        let registered: Vec<_> = plugin
+            .registrations()
+            .into_iter()
+            .map(|(_, method, meta)| (method, meta))
+            .collect();
+        assert_eq!(
+            registered,
+            [
+                ("Msg".to_owned(), "m".to_owned()),
+                ("Stream".to_owned(), "s".to_owned()),
+                ("Upload".to_owned(), "u".to_owned()),
+            ]
+        );
+        let key = |method: &str| ("Side".to_owned(), method.to_owned());
+        assert!(read_lock(&server.streams).contains_key(&key("Stream")));
+        assert!(read_lock(&server.uploads).contains_key(&key("Upload")));
+        let reply = server.dispatch(&request("Side", "Msg")).unwrap();
+        assert_eq!(reply.payload, b"message");
+
+        // a plain handler replaces them
+        registry
+            .register_fn(
+                "Side".to_owned(),
+                "Stream".to_owned(),
+                String::new(),
+                |x, _| Ok(x.to_vec()),
+            )
+            .unwrap();
+        assert!(read_lock(&server.streams).is_empty());
+    }
+}
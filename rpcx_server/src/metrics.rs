@@ -0,0 +1,98 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// Collects per-service call statistics.
+///
+/// `key` is `service_path.service_method`. `record` is called once per
+/// handler invocation from the dispatching threads, so implementations
+/// must be cheap and thread-safe.
+pub trait Metrics {
+    fn record(&self, key: &str, duration: Duration, is_error: bool);
+
+    /// Called when a request is handed to the thread pool while all its
+    /// threads are busy, so it has to wait in the queue. Often means the
+    /// pool is too small.
+    fn record_pool_saturated(&self) {}
+}
+
+pub type SharedMetrics = Arc<dyn Metrics + Send + Sync>;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct MethodStats {
+    pub count: u64,
+    pub total_nanos: u128,
+    pub errors: u64,
+}
+
+/// A `Metrics` implementation keeping the counters in memory.
+#[derive(Debug, Default)]
+pub struct MemoryMetrics {
+    stats: Mutex<HashMap<String, MethodStats>>,
+    pool_saturated: AtomicU64,
+}
+
+impl MemoryMetrics {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Returns a copy of the counters collected so far.
+    pub fn snapshot(&self) -> HashMap<String, MethodStats> {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Returns how many requests had to wait for a thread of the pool.
+    pub fn pool_saturated(&self) -> u64 {
+        self.pool_saturated.load(Ordering::Relaxed)
+    }
+}
+
+impl Metrics for MemoryMetrics {
+    fn record(&self, key: &str, duration: Duration, is_error: bool) {
+        let mut stats = self.stats.lock().unwrap();
+        let entry = stats.entry(key.to_owned()).or_default();
+        entry.count += 1;
+        entry.total_nanos += duration.as_nanos();
+        if is_error {
+            entry.errors += 1;
+        }
+    }
+
+    fn record_pool_saturated(&self) {
+        self.pool_saturated.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_metrics() {
+        let metrics = MemoryMetrics::new();
+        metrics.record("Arith.Mul", Duration::from_nanos(10), false);
+        metrics.record("Arith.Mul", Duration::from_nanos(30), true);
+        metrics.record("Arith.Add", Duration::from_nanos(5), false);
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(
+            MethodStats {
+                count: 2,
+                total_nanos: 40,
+                errors: 1
+            },
+            snapshot["Arith.Mul"]
+        );
+        assert_eq!(1, snapshot["Arith.Add"].count);
+
+        assert_eq!(0, metrics.pool_saturated());
+        metrics.record_pool_saturated();
+        assert_eq!(1, metrics.pool_saturated());
+    }
+}
@@ -1,28 +1,245 @@
-use super::{RpcxFn, Server};
+use super::{write_lock, RpcxHandler, Server};
 #[allow(unused_imports)]
 use rpcx_protocol::*;
-use std::net::TcpStream;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex, RwLock},
+    time::Instant,
+};
 impl Server {
     pub fn add_register_plugin(&mut self, p: Box<dyn RegisterPlugin + Send + Sync>) {
-        let mut plugins = self.register_plugins.write().unwrap();
+        let mut plugins = write_lock(&self.register_plugins);
         plugins.push(p);
     }
     pub fn add_connect_plugin(&mut self, p: Box<dyn ConnectPlugin + Send + Sync>) {
-        let mut plugins = self.connect_plugins.write().unwrap();
+        let mut plugins = write_lock(&self.connect_plugins);
+        plugins.push(p);
+    }
+    pub fn add_invoke_plugin(&mut self, p: Box<dyn InvokePlugin + Send + Sync>) {
+        let mut plugins = write_lock(&self.invoke_plugins);
         plugins.push(p);
     }
 }
 
 pub trait RegisterPlugin {
+    /// Called with the address the server is bound to once it starts
+    /// listening on TCP, which is the address discovery backends should
+    /// publish, and once more for every further listener. An error stops
+    /// the server from starting. Does nothing by default.
+    fn init(&mut self, _addr: SocketAddr) -> Result<()> {
+        Ok(())
+    }
+
     fn register_fn(
         &mut self,
         service_path: &str,
         service_method: &str,
         meta: String,
-        f: RpcxFn,
+        f: &RpcxHandler,
     ) -> Result<()>;
+
+    /// Called when a service is unregistered, so the plugin can e.g. remove
+    /// it from a registry. Does nothing by default.
+    fn unregister_fn(&mut self, _service_path: &str, _service_method: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called when the server shuts down, so the plugin can withdraw
+    /// everything it announced. Does nothing by default.
+    fn stop(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A `RegisterPlugin` that keeps the registered services in memory, e.g. to
+/// check in tests what would have been published.
+///
+/// Clones share the registrations, so keep a clone to read them after the
+/// plugin has been added to a server. It records `(service_path,
+/// service_method, meta)` in registration order; unregistered services are
+/// removed and `stop` removes all of them.
+#[derive(Clone, Default)]
+pub struct MemoryRegisterPlugin {
+    addr: Arc<RwLock<Option<SocketAddr>>>,
+    registrations: Arc<RwLock<Vec<(String, String, String)>>>,
+}
+
+impl MemoryRegisterPlugin {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the address passed to `init`, if the server has started.
+    pub fn addr(&self) -> Option<SocketAddr> {
+        *self.addr.read().unwrap()
+    }
+
+    /// Returns the services registered so far.
+    pub fn registrations(&self) -> Vec<(String, String, String)> {
+        self.registrations.read().unwrap().clone()
+    }
+}
+
+impl RegisterPlugin for MemoryRegisterPlugin {
+    fn init(&mut self, addr: SocketAddr) -> Result<()> {
+        *self.addr.write().unwrap() = Some(addr);
+        Ok(())
+    }
+
+    fn register_fn(
+        &mut self,
+        service_path: &str,
+        service_method: &str,
+        meta: String,
+        _f: &RpcxHandler,
+    ) -> Result<()> {
+        let mut registrations = self.registrations.write().unwrap();
+        registrations.retain(|(path, method, _)| path != service_path || method != service_method);
+        registrations.push((service_path.to_owned(), service_method.to_owned(), meta));
+        Ok(())
+    }
+
+    fn unregister_fn(&mut self, service_path: &str, service_method: &str) -> Result<()> {
+        let mut registrations = self.registrations.write().unwrap();
+        registrations.retain(|(path, method, _)| path != service_path || method != service_method);
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.registrations.write().unwrap().clear();
+        Ok(())
+    }
 }
 
+/// Decides whether an accepted connection is served.
+///
+/// `handle_connect` runs on the accept loop before a thread is spawned for
+/// the connection; if any plugin returns an error the connection is closed
+/// right away. Unix socket clients have no address and are not checked.
 pub trait ConnectPlugin {
-    fn connected(&mut self, conn: &TcpStream) -> Result<()>;
+    fn handle_connect(&self, peer: SocketAddr) -> Result<()>;
+}
+
+/// Hooks run around every service call.
+///
+/// An error from `pre_call` rejects the request: the handler is skipped and
+/// the error is sent back to the client as `SERVICE_ERROR`. `post_call` sees
+/// the reply before it is written, including error replies.
+pub trait InvokePlugin {
+    fn pre_call(&self, msg: &Message) -> Result<()>;
+    fn post_call(&self, msg: &Message, reply: &Message) -> Result<()>;
+}
+
+/// Rejects requests that don't carry the configured token, replying
+/// "unauthorized" without calling the handler.
+///
+/// The token is read from the `AUTH_KEY` metadata unless another key is
+/// set, and must match exactly, e.g. `"Bearer <token>"` if clients send it
+/// like that.
+pub struct TokenAuthPlugin {
+    key: String,
+    token: String,
+}
+
+impl TokenAuthPlugin {
+    pub fn new(token: &str) -> Self {
+        TokenAuthPlugin {
+            key: AUTH_KEY.to_owned(),
+            token: token.to_owned(),
+        }
+    }
+
+    /// Sets the metadata key the token is read from.
+    pub fn set_key(&mut self, key: &str) {
+        self.key = key.to_owned();
+    }
+}
+
+impl InvokePlugin for TokenAuthPlugin {
+    fn pre_call(&self, msg: &Message) -> Result<()> {
+        match msg.metadata.borrow().get(&self.key) {
+            Some(token) if *token == self.token => Ok(()),
+            _ => Err(Error::new(ErrorKind::Unauthorized, "unauthorized")),
+        }
+    }
+
+    fn post_call(&self, _msg: &Message, _reply: &Message) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Caps the requests per second of single methods, replying "rate limited"
+/// without calling the handler once the cap is reached.
+///
+/// Every configured `service_path.service_method` gets a token bucket
+/// holding up to `max_rps` tokens, refilled at `max_rps` tokens per second,
+/// so short bursts up to the cap are allowed. Methods without a limit are
+/// unlimited.
+#[derive(Default)]
+pub struct RateLimitPlugin {
+    buckets: HashMap<String, Mutex<TokenBucket>>,
+}
+
+struct TokenBucket {
+    max_rps: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.max_rps).min(self.max_rps);
+        self.refilled = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+impl RateLimitPlugin {
+    /// Limits every `service_path.service_method` in `limits` to its
+    /// requests per second.
+    pub fn new(limits: HashMap<String, u32>) -> Self {
+        let mut plugin = Self::default();
+        for (key, max_rps) in limits {
+            plugin.set_limit(&key, max_rps);
+        }
+        plugin
+    }
+
+    /// Limits `key`, a `service_path.service_method`, to `max_rps` requests
+    /// per second.
+    pub fn set_limit(&mut self, key: &str, max_rps: u32) {
+        let bucket = TokenBucket {
+            max_rps: max_rps as f64,
+            tokens: max_rps as f64,
+            refilled: Instant::now(),
+        };
+        self.buckets.insert(key.to_owned(), Mutex::new(bucket));
+    }
+}
+
+impl InvokePlugin for RateLimitPlugin {
+    fn pre_call(&self, msg: &Message) -> Result<()> {
+        if self.buckets.is_empty() {
+            return Ok(());
+        }
+        let key = format!("{}.{}", msg.service_path, msg.service_method);
+        match self.buckets.get(&key) {
+            Some(bucket) if !bucket.lock().unwrap().take() => {
+                Err(Error::new(ErrorKind::RateLimited, "rate limited"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn post_call(&self, _msg: &Message, _reply: &Message) -> Result<()> {
+        Ok(())
+    }
 }
@@ -0,0 +1,38 @@
+use super::{read_lock, typed::encode_reply, Metas, RpcxHandler};
+
+use serde::{Deserialize, Serialize};
+
+/// The service path of the reflection service, see
+/// `ServerBuilder::with_reflection`.
+pub const REFLECTION_SERVICE_PATH: &str = "rpcx.reflection";
+
+/// The service method listing the registered services.
+pub const REFLECTION_SERVICE_METHOD: &str = "list";
+
+/// A registered service as listed by the reflection service.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServiceInfo {
+    pub service_path: String,
+    pub service_method: String,
+    /// The meta the service was registered with.
+    pub meta: String,
+}
+
+/// Answers every call with the services in `metas`, sorted by name and
+/// encoded like the request, ignoring the payload.
+pub(crate) fn reflection_handler(metas: Metas) -> RpcxHandler {
+    Box::new(move |_, _, st| {
+        let mut services: Vec<ServiceInfo> = read_lock(&metas)
+            .iter()
+            .map(|((service_path, service_method), meta)| ServiceInfo {
+                service_path: service_path.clone(),
+                service_method: service_method.clone(),
+                meta: meta.clone(),
+            })
+            .collect();
+        services.sort_by(|a, b| {
+            (&a.service_path, &a.service_method).cmp(&(&b.service_path, &b.service_method))
+        });
+        encode_reply(st, &services)
+    })
+}
@@ -0,0 +1,180 @@
+use super::{
+    read_lock, stream::StreamServices, upload::UploadServices, write_lock, MessageServices, Metas,
+    RegisterPlugins, RpcxFn, RpcxHandler, RpcxMessageHandler, Services,
+};
+use rpcx_protocol::*;
+
+use std::sync::Arc;
+
+use log::warn;
+
+/// A handle registering and unregistering the services of a `Server`, also
+/// while it serves, see `Server::registry`.
+///
+/// Every clone shares the server's services, which are behind locks, so a
+/// handle can be sent to and used from any thread. A request is served by
+/// the handler registered when it is dispatched; requests already being
+/// handled finish with the handler they got.
+///
+/// A default registry belongs to no server yet, e.g. to be handed to
+/// `AsyncServer::with_registry`.
+#[derive(Clone, Default)]
+pub struct ServiceRegistry {
+    pub(crate) services: Services,
+    pub(crate) streams: StreamServices,
+    pub(crate) uploads: UploadServices,
+    pub(crate) messages: MessageServices,
+    pub(crate) metas: Metas,
+    pub(crate) register_plugins: RegisterPlugins,
+}
+
+impl ServiceRegistry {
+    /// See `Server::register_fn`.
+    pub fn register_fn(
+        &self,
+        service_path: String,
+        service_method: String,
+        meta: String,
+        f: RpcxFn,
+    ) -> Result<()> {
+        let handler: RpcxHandler = Box::new(move |_, x, st| f(x, st));
+        self.register_handler(service_path, service_method, meta, handler)
+    }
+
+    /// See `Server::register_handler`.
+    pub fn register_handler(
+        &self,
+        service_path: String,
+        service_method: String,
+        meta: String,
+        handler: RpcxHandler,
+    ) -> Result<()> {
+        self.register(service_path, service_method, meta, handler, |_, _| {})
+    }
+
+    /// See `Server::register_message_fn`.
+    pub fn register_message_fn<F>(
+        &self,
+        service_path: String,
+        service_method: String,
+        meta: String,
+        f: F,
+    ) -> Result<()>
+    where
+        F: Fn(&Message) -> Result<Message> + Send + Sync + 'static,
+    {
+        // like register_stream_fn, a placeholder for plugins and listings
+        let unsupported: RpcxHandler = Box::new(|_, _, _| {
+            Err(Error::new(
+                ErrorKind::Server,
+                "message handlers are not supported",
+            ))
+        });
+        let handler: RpcxMessageHandler = Box::new(f);
+        self.register(
+            service_path,
+            service_method,
+            meta,
+            unsupported,
+            |registry, key| {
+                write_lock(&registry.messages).insert(key, Arc::new(handler));
+            },
+        )
+    }
+
+    /// Registers `handler` like `register_handler`, calling `insert` right
+    /// before it is inserted, once the register plugins have announced it.
+    ///
+    /// Streaming, upload and message handlers are inserted by `insert`, with
+    /// a placeholder as `handler` for the dispatchers that can't call them,
+    /// so no request finds the placeholder without them.
+    pub(crate) fn register<F>(
+        &self,
+        service_path: String,
+        service_method: String,
+        meta: String,
+        handler: RpcxHandler,
+        insert: F,
+    ) -> Result<()>
+    where
+        F: FnOnce(&Self, (String, String)),
+    {
+        check_name("path", &service_path)?;
+        check_name("method", &service_method)?;
+
+        // invoke register plugins
+        let mut plugins = write_lock(&self.register_plugins);
+        let failed = plugins.iter_mut().enumerate().find_map(|(i, p)| {
+            p.register_fn(
+                service_path.as_str(),
+                service_method.as_str(),
+                meta.clone(),
+                &handler,
+            )
+            .err()
+            .map(|err| (i, err))
+        });
+        if let Some((i, err)) = failed {
+            // the plugins before have announced a service that won't be
+            // registered, unless it is already with another handler
+            let key = (service_path, service_method);
+            if !read_lock(&self.services).contains_key(&key) {
+                for p in plugins[..i].iter_mut() {
+                    if let Err(err) = p.unregister_fn(key.0.as_str(), key.1.as_str()) {
+                        warn!("failed to unregister {}.{}: {}", key.0, key.1, err);
+                    }
+                }
+            }
+            return Err(err);
+        }
+        drop(plugins);
+
+        let key = (service_path, service_method);
+        write_lock(&self.metas).insert(key.clone(), meta);
+        // replaces a streaming, upload or message handler as well
+        write_lock(&self.streams).remove(&key);
+        write_lock(&self.uploads).remove(&key);
+        write_lock(&self.messages).remove(&key);
+        insert(self, key.clone());
+        // invoke service
+        let mut map = write_lock(&self.services);
+        map.insert(key, Arc::new(handler));
+        Ok(())
+    }
+
+    /// See `Server::unregister_fn`.
+    pub fn unregister_fn(
+        &self,
+        service_path: String,
+        service_method: String,
+    ) -> Option<Arc<RpcxHandler>> {
+        let mut plugins = write_lock(&self.register_plugins);
+        for p in plugins.iter_mut() {
+            if let Err(err) = p.unregister_fn(service_path.as_str(), service_method.as_str()) {
+                warn!(
+                    "failed to unregister {}.{}: {}",
+                    service_path, service_method, err
+                );
+            }
+        }
+        drop(plugins);
+
+        let key = (service_path, service_method);
+        write_lock(&self.metas).remove(&key);
+        write_lock(&self.streams).remove(&key);
+        write_lock(&self.uploads).remove(&key);
+        write_lock(&self.messages).remove(&key);
+        let mut map = write_lock(&self.services);
+        map.remove(&key)
+    }
+}
+
+/// Fails unless `name`, a service path or method, is non-empty and free of
+/// whitespace and control characters, which no client sends.
+fn check_name(what: &str, name: &str) -> Result<()> {
+    let invalid = |c: char| c.is_whitespace() || c.is_control();
+    if name.is_empty() || name.contains(invalid) {
+        return Err(Error::from(format!("invalid service {} {:?}", what, name)));
+    }
+    Ok(())
+}
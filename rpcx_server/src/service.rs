@@ -0,0 +1,51 @@
+use super::{RpcxHandler, Server};
+use rpcx_protocol::*;
+
+use std::sync::Arc;
+
+/// A service struct whose methods are all registered at once with
+/// `Server::register_service`, like the methods of a receiver in Go.
+///
+/// It is usually implemented with `#[rpcx_service]` on the `impl` block of
+/// the methods.
+pub trait RpcxService: Send + Sync + 'static {
+    /// The names of the methods clients call.
+    fn method_names(&self) -> Vec<&'static str>;
+
+    /// Calls the method named `method` with its encoded argument and
+    /// returns its encoded reply.
+    fn call(&self, method: &str, args: &[u8], st: SerializeType) -> Result<Vec<u8>>;
+}
+
+impl Server {
+    /// Registers every method of `service` under `service_path` with
+    /// `meta`, as if each was registered with `register_handler`. The
+    /// methods share the one `service`. If a method can't be registered, the
+    /// ones before it are unregistered again and the error is returned.
+    pub fn register_service<S: RpcxService>(
+        &mut self,
+        service_path: &str,
+        meta: String,
+        service: S,
+    ) -> Result<()> {
+        let service = Arc::new(service);
+        let methods = service.method_names();
+        for (i, method) in methods.iter().copied().enumerate() {
+            let s = service.clone();
+            let handler: RpcxHandler = Box::new(move |_, x, st| s.call(method, x, st));
+            let res = self.register_handler(
+                service_path.to_owned(),
+                method.to_owned(),
+                meta.clone(),
+                handler,
+            );
+            if let Err(err) = res {
+                for method in &methods[..i] {
+                    self.unregister_fn(service_path.to_owned(), (*method).to_owned());
+                }
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+}
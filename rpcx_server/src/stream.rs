@@ -0,0 +1,119 @@
+use super::{write_lock, Context, RpcxHandler, Server, ServiceRegistry};
+use rpcx_protocol::*;
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    io,
+    sync::{Arc, RwLock},
+};
+
+/// A handler that sends its reply in several frames through a
+/// `StreamSink`, see `Server::register_stream_fn`.
+pub type RpcxStreamHandler =
+    Box<dyn Fn(&Context, &[u8], SerializeType, &mut StreamSink) -> Result<()> + Send + Sync>;
+
+/// Registered streaming handlers keyed by `(service_path, service_method)`.
+pub(crate) type StreamServices = Arc<RwLock<HashMap<(String, String), Arc<RpcxStreamHandler>>>>;
+
+/// Sends the frames of a streamed reply.
+pub struct StreamSink<'a> {
+    /// The header of every frame, that of the reply.
+    header: [u8; 12],
+    service_path: String,
+    service_method: String,
+    /// `None` for oneway requests, whose frames are dropped.
+    write: Option<&'a mut dyn FnMut(&[u8]) -> io::Result<()>>,
+}
+
+impl<'a> StreamSink<'a> {
+    pub(crate) fn new(
+        reply_msg: &Message,
+        write: Option<&'a mut dyn FnMut(&[u8]) -> io::Result<()>>,
+    ) -> Self {
+        StreamSink {
+            header: reply_msg.header,
+            service_path: reply_msg.service_path.clone(),
+            service_method: reply_msg.service_method.clone(),
+            write,
+        }
+    }
+
+    /// Sends `payload` as the next frame. Fails once the connection is
+    /// broken, so the handler can stop.
+    pub fn send(&mut self, payload: Vec<u8>) -> Result<()> {
+        let write = match &mut self.write {
+            Some(write) => write,
+            None => return Ok(()),
+        };
+        let mut metadata = Metadata::new();
+        metadata.insert(STREAM.to_owned(), STREAM_MORE.to_owned());
+        let frame = Message {
+            header: self.header,
+            service_path: self.service_path.clone(),
+            service_method: self.service_method.clone(),
+            metadata: RefCell::new(metadata),
+            payload,
+        };
+        write(&frame.encode()).map_err(|err| Error::new(ErrorKind::Network, err))
+    }
+}
+
+impl Server {
+    /// Registers `f` as `service_path.service_method`, sending its reply in
+    /// frames: every payload it passes to the `StreamSink` is written right
+    /// away as a reply marked `STREAM_MORE`, and once it returns a final
+    /// reply marked `STREAM_END` ends the stream, with its error if it
+    /// failed. Clients read the frames with `Client::call_stream`.
+    ///
+    /// Only the threaded server streams; an `AsyncServer` sharing the
+    /// services answers with an error.
+    pub fn register_stream_fn<F>(
+        &mut self,
+        service_path: String,
+        service_method: String,
+        meta: String,
+        f: F,
+    ) -> Result<()>
+    where
+        F: Fn(&Context, &[u8], SerializeType, &mut StreamSink) -> Result<()>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.registry()
+            .register_stream_fn(service_path, service_method, meta, f)
+    }
+}
+
+impl ServiceRegistry {
+    /// See `Server::register_stream_fn`.
+    pub fn register_stream_fn<F>(
+        &self,
+        service_path: String,
+        service_method: String,
+        meta: String,
+        f: F,
+    ) -> Result<()>
+    where
+        F: Fn(&Context, &[u8], SerializeType, &mut StreamSink) -> Result<()>
+            + Send
+            + Sync
+            + 'static,
+    {
+        // registered like any other service, so plugins and listings see
+        // it, but only called by dispatchers that can't stream
+        let unsupported: RpcxHandler =
+            Box::new(|_, _, _| Err(Error::new(ErrorKind::Server, "streaming is not supported")));
+        let handler: RpcxStreamHandler = Box::new(f);
+        self.register(
+            service_path,
+            service_method,
+            meta,
+            unsupported,
+            |registry, key| {
+                write_lock(&registry.streams).insert(key, Arc::new(handler));
+            },
+        )
+    }
+}
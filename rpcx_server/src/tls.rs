@@ -0,0 +1,206 @@
+use super::{lock, Server, Transport, WriteHalf};
+use rpcx_protocol::*;
+
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+};
+
+use rustls::{ServerConfig, ServerConnection};
+
+/// A server side TLS session over a `TcpStream`.
+///
+/// Both halves share the session, so the read half can block waiting for
+/// records while replies are written. The session lock is only held while records
+/// are processed or replies are encrypted, never while waiting on the socket:
+/// outgoing records are taken out of the session and written after the lock is
+/// released. A second lock keeps them in the order they were encrypted in.
+pub struct TlsStream {
+    sock: TcpStream,
+    conn: Arc<Mutex<ServerConnection>>,
+    send: Arc<Mutex<()>>,
+}
+
+impl TlsStream {
+    /// Starts a TLS session on an accepted stream. The handshake is driven
+    /// by the first reads.
+    pub fn new(sock: TcpStream, config: Arc<ServerConfig>) -> io::Result<Self> {
+        let conn = ServerConnection::new(config).map_err(io::Error::other)?;
+        Ok(TlsStream {
+            sock,
+            conn: Arc::new(Mutex::new(conn)),
+            send: Arc::new(Mutex::new(())),
+        })
+    }
+
+    /// Runs `f` on the session and writes the records it produced once the
+    /// session is unlocked again.
+    fn send<T>(&self, f: impl FnOnce(&mut ServerConnection) -> T) -> io::Result<T> {
+        let _send = lock(&self.send);
+        let (res, records) = {
+            let mut conn = lock(&self.conn);
+            let res = f(&mut conn);
+            (res, take_records(&mut conn)?)
+        };
+        (&self.sock).write_all(&records)?;
+        Ok(res)
+    }
+
+    /// Feeds records received from the client into the session and sends
+    /// whatever the session wants to answer, e.g. handshake messages.
+    fn process_records(&self, mut records: &[u8]) -> io::Result<()> {
+        while !records.is_empty() {
+            let state = self.send(|conn| {
+                conn.read_tls(&mut records)?;
+                conn.process_new_packets()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            })?;
+            state?;
+        }
+        Ok(())
+    }
+}
+
+/// Takes the records the session wants to send out of it.
+fn take_records(conn: &mut ServerConnection) -> io::Result<Vec<u8>> {
+    let mut records = Vec::new();
+    while conn.wants_write() {
+        conn.write_tls(&mut records)?;
+    }
+    Ok(records)
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut records = [0u8; 16 * 1024];
+        loop {
+            match lock(&self.conn).reader().read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                res => return res,
+            }
+
+            let n = (&self.sock).read(&mut records)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            self.process_records(&records[..n])?;
+        }
+    }
+}
+
+impl Transport for TlsStream {
+    type Reader = TlsStream;
+    type Writer = TlsStream;
+
+    fn split(self) -> io::Result<(TlsStream, TlsStream)> {
+        let reader = TlsStream {
+            sock: self.sock.try_clone()?,
+            conn: self.conn.clone(),
+            send: self.send.clone(),
+        };
+        Ok((reader, self))
+    }
+}
+
+impl WriteHalf for TlsStream {
+    fn write_reply(&self, buf: &[u8]) -> io::Result<()> {
+        self.send(|conn| conn.writer().write_all(buf))?
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if how != Shutdown::Read {
+            let _ = self.send(|conn| conn.send_close_notify());
+        }
+        self.sock.shutdown(how)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.sock.peer_addr()
+    }
+}
+
+impl Server {
+    /// Like `start`, but serves every connection over TLS.
+    pub fn start_tls(&mut self, config: Arc<ServerConfig>) -> Result<()> {
+        let listener = self.bind()?;
+        self.start_tls_with_listener(listener, config)
+    }
+
+    pub fn start_tls_with_listener(
+        &self,
+        listener: TcpListener,
+        config: Arc<ServerConfig>,
+    ) -> Result<()> {
+        self.init_register_plugins(&listener)?;
+        self.serve(listener.incoming(), move |stream| {
+            TlsStream::new(stream, config.clone())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RpcxFn;
+    use rustls::{Certificate, ClientConfig, ClientConnection, PrivateKey, RootCertStore};
+    use std::convert::TryFrom;
+
+    #[test]
+    fn calls_are_served_over_tls() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let der = Certificate(cert.serialize_der().unwrap());
+        let server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![der.clone()],
+                PrivateKey(cert.serialize_private_key_der()),
+            )
+            .unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(&der).unwrap();
+        let client_config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let mut server = Server::new("127.0.0.1:0".to_owned(), 1);
+        let echo: RpcxFn = |x, _| Ok(x.to_vec());
+        server
+            .register_fn("Echo".to_owned(), "Echo".to_owned(), String::new(), echo)
+            .unwrap();
+        let listener = server.bind().unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Arc::new(server);
+        let s = server.clone();
+        let handle = std::thread::spawn(move || {
+            s.start_tls_with_listener(listener, Arc::new(server_config))
+        });
+
+        let conn = ClientConnection::new(
+            Arc::new(client_config),
+            rustls::ServerName::try_from("localhost").unwrap(),
+        )
+        .unwrap();
+        let mut tls = rustls::StreamOwned::new(conn, TcpStream::connect(addr).unwrap());
+        for seq in 1..=3 {
+            let mut msg = Message::new();
+            msg.set_serialize_type(SerializeType::JSON);
+            msg.set_seq(seq);
+            msg.service_path = "Echo".to_owned();
+            msg.service_method = "Echo".to_owned();
+            msg.payload = format!("\"hello {}\"", seq).into_bytes();
+            tls.write_all(&msg.encode()).unwrap();
+
+            let mut reply = Message::new();
+            reply.decode(&mut tls).unwrap();
+            assert_eq!(reply.get_error(), None);
+            assert_eq!(reply.get_seq(), seq);
+            assert_eq!(reply.payload, msg.payload);
+        }
+
+        server.shutdown();
+        handle.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,94 @@
+use socket2::{SockRef, TcpKeepalive};
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
+    time::Duration,
+};
+
+/// A connected stream the server can serve requests on.
+///
+/// A connection is split once: the read loop owns the read half, and the
+/// write half is shared by all handlers replying on that connection.
+pub trait Transport: Send + 'static {
+    type Reader: Read + Send;
+    type Writer: WriteHalf + 'static;
+
+    fn split(self) -> io::Result<(Self::Reader, Self::Writer)>;
+}
+
+/// The half of a connection replies are written to.
+pub trait WriteHalf: Send + Sync {
+    /// Writes a complete reply. The server writes the replies of one
+    /// connection one at a time.
+    ///
+    /// An `io::ErrorKind::Interrupted` error must mean nothing was written,
+    /// the server writes `buf` again then.
+    fn write_reply(&self, buf: &[u8]) -> io::Result<()>;
+
+    /// Shuts down the read, write, or both halves of the connection.
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+
+    /// Returns the address of the client, if the transport has one.
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl Transport for TcpStream {
+    type Reader = TcpStream;
+    type Writer = TcpStream;
+
+    fn split(self) -> io::Result<(TcpStream, TcpStream)> {
+        Ok((self.try_clone()?, self))
+    }
+}
+
+impl WriteHalf for TcpStream {
+    fn write_reply(&self, buf: &[u8]) -> io::Result<()> {
+        let mut stream = self;
+        Write::write_all(&mut stream, buf)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+}
+
+/// A stream as accepted by one of the server's listeners, before it is
+/// wrapped into the transport requests are read from.
+pub(crate) trait Socket: Sized {
+    /// Returns the address of the client, if the transport has one.
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()>;
+
+    /// Only TCP sockets have Nagle's algorithm to switch off.
+    fn set_nodelay(&self, _nodelay: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Only TCP sockets have keepalive probes to send.
+    fn set_keepalive(&self, _keepalive: &TcpKeepalive) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Socket for TcpStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, dur)
+    }
+
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        TcpStream::set_nodelay(self, nodelay)
+    }
+
+    fn set_keepalive(&self, keepalive: &TcpKeepalive) -> io::Result<()> {
+        SockRef::from(self).set_tcp_keepalive(keepalive)
+    }
+}
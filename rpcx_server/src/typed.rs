@@ -0,0 +1,108 @@
+use super::{RpcxHandler, Server};
+use rpcx_protocol::*;
+
+use std::fmt::Display;
+
+use rmp_serde as rmps;
+use serde::{de::DeserializeOwned, Serialize};
+
+impl Server {
+    /// Registers `handler` as `service_path.service_method`, decoding its
+    /// argument from and encoding its reply to the serialize type of each
+    /// request, JSON or MessagePack.
+    ///
+    /// Unlike `register_func!`, the argument and reply types are inferred
+    /// and `handler` may be a closure with captured state.
+    pub fn register_typed<A, R, F>(
+        &mut self,
+        service_path: String,
+        service_method: String,
+        meta: String,
+        handler: F,
+    ) -> Result<()>
+    where
+        A: DeserializeOwned,
+        R: Serialize,
+        F: Fn(A) -> R + Send + Sync + 'static,
+    {
+        let handler: RpcxHandler = Box::new(move |_, data, st| {
+            let args = decode_args(st, data)?;
+            encode_reply(st, &handler(args))
+        });
+        self.register_handler(service_path, service_method, meta, handler)
+    }
+
+    /// Like `register_typed`, for handlers that can fail. An `Err` is sent
+    /// back as `SERVICE_ERROR`, showing the error with `Display`, and the
+    /// reply has no payload.
+    pub fn register_fallible<A, R, E, F>(
+        &mut self,
+        service_path: String,
+        service_method: String,
+        meta: String,
+        handler: F,
+    ) -> Result<()>
+    where
+        A: DeserializeOwned,
+        R: Serialize,
+        E: Display,
+        F: Fn(A) -> std::result::Result<R, E> + Send + Sync + 'static,
+    {
+        let handler: RpcxHandler = Box::new(move |_, data, st| {
+            let args = decode_args(st, data)?;
+            match handler(args) {
+                Ok(reply) => encode_reply(st, &reply),
+                Err(err) => Err(Error::new(ErrorKind::Server, err.to_string())),
+            }
+        });
+        self.register_handler(service_path, service_method, meta, handler)
+    }
+
+    /// Like `register_typed`, for prost messages. Only requests with
+    /// `SerializeType::Protobuf` are served; the others get an error.
+    #[cfg(feature = "protobuf")]
+    pub fn register_proto<A, R, F>(
+        &mut self,
+        service_path: String,
+        service_method: String,
+        meta: String,
+        handler: F,
+    ) -> Result<()>
+    where
+        A: prost::Message + Default,
+        R: prost::Message,
+        F: Fn(A) -> R + Send + Sync + 'static,
+    {
+        let handler: RpcxHandler = Box::new(move |_, data, st| match st {
+            SerializeType::Protobuf => {
+                let args =
+                    A::decode(data).map_err(|err| Error::new(ErrorKind::Other, err.to_string()))?;
+                Ok(handler(args).encode_to_vec())
+            }
+            _ => Err(Error::new(ErrorKind::Other, "unknown format")),
+        });
+        self.register_handler(service_path, service_method, meta, handler)
+    }
+}
+
+/// Decodes `data` like `RpcxParam::from_slice` does.
+fn decode_args<A: DeserializeOwned>(st: SerializeType, data: &[u8]) -> Result<A> {
+    match st {
+        SerializeType::JSON => Ok(serde_json::from_slice(data)?),
+        SerializeType::MsgPack => {
+            rmps::from_slice(data).map_err(|err| Error::new(ErrorKind::Other, err.to_string()))
+        }
+        _ => Err(Error::new(ErrorKind::Other, "unknown format")),
+    }
+}
+
+/// Encodes `reply` like `RpcxParam::into_bytes` does.
+pub(crate) fn encode_reply<R: Serialize>(st: SerializeType, reply: &R) -> Result<Vec<u8>> {
+    match st {
+        SerializeType::JSON => Ok(serde_json::to_vec(reply)?),
+        SerializeType::MsgPack => {
+            rmps::to_vec_named(reply).map_err(|err| Error::new(ErrorKind::Other, err.to_string()))
+        }
+        _ => Err(Error::new(ErrorKind::Other, "unknown format")),
+    }
+}
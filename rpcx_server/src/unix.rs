@@ -0,0 +1,99 @@
+use super::{lock, Server, Socket, Transport, WriteHalf};
+use rpcx_protocol::*;
+
+use std::{
+    fs, io,
+    net::{Shutdown, SocketAddr, TcpListener},
+    os::unix::{
+        io::{FromRawFd, RawFd},
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    time::Duration,
+};
+
+use log::info;
+
+impl Transport for UnixStream {
+    type Reader = UnixStream;
+    type Writer = UnixStream;
+
+    fn split(self) -> io::Result<(UnixStream, UnixStream)> {
+        Ok((self.try_clone()?, self))
+    }
+}
+
+impl WriteHalf for UnixStream {
+    fn write_reply(&self, buf: &[u8]) -> io::Result<()> {
+        let mut stream = self;
+        io::Write::write_all(&mut stream, buf)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        UnixStream::shutdown(self, how)
+    }
+
+    /// Unix sockets have no `SocketAddr`, so this always fails.
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "unix sockets have no socket address",
+        ))
+    }
+}
+
+impl Socket for UnixStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        WriteHalf::peer_addr(self)
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, dur)
+    }
+}
+
+impl Server {
+    /// Like `start`, but listens on the unix socket at `path`. The socket
+    /// file is removed once the server stops, and one left behind by a
+    /// server that is no longer running is replaced.
+    pub fn start_unix(&self, path: &str) -> Result<()> {
+        let listener = match UnixListener::bind(path) {
+            Err(err)
+                if err.kind() == io::ErrorKind::AddrInUse && UnixStream::connect(path).is_err() =>
+            {
+                fs::remove_file(path)?;
+                UnixListener::bind(path)?
+            }
+            res => res?,
+        };
+        info!("Listening on: {}", path);
+
+        let res = self.start_unix_with_listener(listener);
+        let _ = fs::remove_file(path);
+        res
+    }
+
+    /// Like `start_with_listener`, for a listening TCP socket inherited as
+    /// `fd`, e.g. through systemd socket activation. The server owns the
+    /// socket from then on, closes it once it stops and `close` stops it
+    /// like any other listener.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open listening TCP socket nothing else uses or
+    /// closes.
+    pub unsafe fn start_with_raw_fd(&self, fd: RawFd) -> Result<()> {
+        let listener = TcpListener::from_raw_fd(fd);
+        // inherited sockets may have been left nonblocking
+        listener.set_nonblocking(false)?;
+        self.start_with_listener(listener)
+    }
+
+    pub fn start_unix_with_listener(&self, listener: UnixListener) -> Result<()> {
+        *lock(&self.unix_path) = listener
+            .local_addr()
+            .ok()
+            .and_then(|addr| addr.as_pathname().map(Path::to_path_buf));
+        self.serve(listener.incoming(), Ok)
+    }
+}
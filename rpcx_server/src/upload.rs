@@ -0,0 +1,76 @@
+use super::{write_lock, Context, RpcxHandler, Server, ServiceRegistry};
+use rpcx_protocol::*;
+
+use std::{
+    collections::HashMap,
+    io::Read,
+    sync::{Arc, RwLock},
+};
+
+/// A handler reading the payload of its request from the connection as it
+/// arrives instead of from a buffer, see `Server::register_upload_fn`.
+pub type RpcxUploadHandler =
+    Box<dyn Fn(&Context, &mut dyn Read, SerializeType) -> Result<Vec<u8>> + Send + Sync>;
+
+/// Registered upload handlers keyed by `(service_path, service_method)`.
+pub(crate) type UploadServices = Arc<RwLock<HashMap<(String, String), Arc<RpcxUploadHandler>>>>;
+
+impl Server {
+    /// Registers `f` as `service_path.service_method`, passing it the
+    /// payload of each request as a reader of exactly the payload instead
+    /// of a buffer, so large requests, e.g. file uploads, are never held in
+    /// memory at once. A gzipped payload is decompressed as it is read, and
+    /// what `f` leaves unread is skipped once it returns.
+    ///
+    /// The reader is the connection itself, so `f` runs on the thread
+    /// reading the connection, whatever the `DispatchMode`, and the next
+    /// request of the connection is only read once it returns. Requests of
+    /// the connection that are being handled by the pool are not held up.
+    /// The payload is not limited by `set_max_message_size`; the rest of
+    /// the request is.
+    ///
+    /// Only the threaded server reads payloads as they arrive; an
+    /// `AsyncServer` sharing the services answers with an error.
+    pub fn register_upload_fn<F>(
+        &mut self,
+        service_path: String,
+        service_method: String,
+        meta: String,
+        f: F,
+    ) -> Result<()>
+    where
+        F: Fn(&Context, &mut dyn Read, SerializeType) -> Result<Vec<u8>> + Send + Sync + 'static,
+    {
+        self.registry()
+            .register_upload_fn(service_path, service_method, meta, f)
+    }
+}
+
+impl ServiceRegistry {
+    /// See `Server::register_upload_fn`.
+    pub fn register_upload_fn<F>(
+        &self,
+        service_path: String,
+        service_method: String,
+        meta: String,
+        f: F,
+    ) -> Result<()>
+    where
+        F: Fn(&Context, &mut dyn Read, SerializeType) -> Result<Vec<u8>> + Send + Sync + 'static,
+    {
+        // registered like any other service, so plugins and listings see
+        // it, but only called by dispatchers that buffer payloads
+        let unsupported: RpcxHandler =
+            Box::new(|_, _, _| Err(Error::new(ErrorKind::Server, "uploads are not supported")));
+        let handler: RpcxUploadHandler = Box::new(f);
+        self.register(
+            service_path,
+            service_method,
+            meta,
+            unsupported,
+            |registry, key| {
+                write_lock(&registry.uploads).insert(key, Arc::new(handler));
+            },
+        )
+    }
+}